@@ -0,0 +1,17 @@
+extern crate cbindgen;
+
+use std::env;
+
+/// Generates the C header into `OUT_DIR`, the copy in `include/` is
+/// checked against it by `tests/c_api.rs`.
+fn main() {
+    let crate_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir)).unwrap();
+    cbindgen::Builder::new()
+        .with_crate(&crate_dir)
+        .with_config(config)
+        .generate()
+        .expect("Unable to generate the C header")
+        .write_to_file(format!("{}/simple_vm.h", out_dir));
+}
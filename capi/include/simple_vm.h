@@ -0,0 +1,196 @@
+#ifndef SIMPLE_VM_H
+#define SIMPLE_VM_H
+
+/* Generated by cbindgen from capi/src/lib.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The result of a call that can fail.
+ */
+typedef enum SvmStatus {
+  SVM_STATUS_OK = 0,
+  /**
+   * The call failed, see `svm_last_error`.
+   */
+  SVM_STATUS_ERROR = 1,
+} SvmStatus;
+
+/**
+ * The type of a value on the stack.
+ */
+typedef enum SvmValueKind {
+  SVM_VALUE_KIND_BOOL,
+  SVM_VALUE_KIND_NUM,
+  SVM_VALUE_KIND_STRING,
+  /**
+   * A label, operation or native function.
+   */
+  SVM_VALUE_KIND_OTHER,
+} SvmValueKind;
+
+/**
+ * A machine, created by `svm_machine_new`.
+ */
+typedef struct SvmMachine SvmMachine;
+
+/**
+ * Limits for `svm_machine_run`, where `0` means unlimited.
+ */
+typedef struct SvmLimits {
+  /**
+   * Maximum number of steps, counted since the machine was created or reset.
+   */
+  size_t steps;
+  /**
+   * Maximum number of values on the stack.
+   */
+  size_t stack_depth;
+  /**
+   * Maximum depth of nested `call`s.
+   */
+  size_t return_stack_depth;
+  /**
+   * Maximum number of bytes held by strings on the stack.
+   */
+  size_t string_bytes;
+  /**
+   * Maximum number of milliseconds the run can take.
+   */
+  uint64_t timeout_ms;
+} SvmLimits;
+
+/**
+ * Called with every line the program prints.
+ */
+typedef void (*SvmPrintlnFn)(void *user_data, const char *line);
+
+/**
+ * Called when the program reads a line. The returned string is copied and
+ * only needs to live until the callback is called again, `NULL` reads an
+ * empty line.
+ */
+typedef const char *(*SvmReadLineFn)(void *user_data);
+
+/**
+ * Called when the program sleeps for `duration_ms` milliseconds.
+ */
+typedef void (*SvmSleepFn)(void *user_data, uint64_t duration_ms);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * The message of the last error on this thread, or `NULL` if there hasn't
+ * been one. It is valid until the next error on this thread.
+ */
+const char *svm_last_error(void);
+
+/**
+ * Creates a machine for `source`, returning `NULL` if it can't be parsed.
+ */
+struct SvmMachine *svm_machine_new(const char *source);
+
+/**
+ * Destroys a machine created by `svm_machine_new`, `NULL` is ignored.
+ */
+void svm_machine_free(struct SvmMachine *machine);
+
+/**
+ * Resets the machine to run its program again from the start, with an empty stack.
+ */
+enum SvmStatus svm_machine_reset(struct SvmMachine *machine);
+
+/**
+ * Pushes a number, as an argument for the program.
+ */
+enum SvmStatus svm_machine_push_num(struct SvmMachine *machine, ptrdiff_t value);
+
+/**
+ * Pushes a boolean, as an argument for the program.
+ */
+enum SvmStatus svm_machine_push_bool(struct SvmMachine *machine, bool value);
+
+/**
+ * Pushes a copy of a string, as an argument for the program.
+ */
+enum SvmStatus svm_machine_push_string(struct SvmMachine *machine, const char *value);
+
+/**
+ * Runs the machine until its program stops, storing the exit code in
+ * `exit_code` unless it is `NULL`. `limits` may be `NULL` for no limits.
+ */
+enum SvmStatus svm_machine_run(struct SvmMachine *machine,
+                               const struct SvmLimits *limits,
+                               int32_t *exit_code);
+
+/**
+ * The number of values on the stack.
+ */
+size_t svm_machine_stack_len(const struct SvmMachine *machine);
+
+/**
+ * Stores the type of the value at `index` in `kind`, where `0` is the
+ * bottom of the stack.
+ */
+enum SvmStatus svm_machine_stack_kind(const struct SvmMachine *machine,
+                                      size_t index,
+                                      enum SvmValueKind *kind);
+
+/**
+ * Stores the number at `index` in `value`, failing if it isn't a number.
+ */
+enum SvmStatus svm_machine_stack_num(const struct SvmMachine *machine,
+                                     size_t index,
+                                     ptrdiff_t *value);
+
+/**
+ * Stores the boolean at `index` in `value`, failing if it isn't a boolean.
+ */
+enum SvmStatus svm_machine_stack_bool(const struct SvmMachine *machine, size_t index, bool *value);
+
+/**
+ * The value at `index` as a string, the way `println` would print it, or
+ * `NULL` if there is no such value. It must be freed with `svm_string_free`.
+ */
+char *svm_machine_stack_string(const struct SvmMachine *machine, size_t index);
+
+/**
+ * Frees a string returned by this library, `NULL` is ignored.
+ */
+void svm_string_free(char *s);
+
+/**
+ * Calls `callback` with `user_data` for every line the program prints,
+ * or prints to stdout again if `callback` is `NULL`.
+ */
+enum SvmStatus svm_machine_set_println(struct SvmMachine *machine,
+                                       SvmPrintlnFn callback,
+                                       void *user_data);
+
+/**
+ * Calls `callback` with `user_data` for every line the program reads,
+ * or reads from stdin again if `callback` is `NULL`.
+ */
+enum SvmStatus svm_machine_set_read_line(struct SvmMachine *machine,
+                                         SvmReadLineFn callback,
+                                         void *user_data);
+
+/**
+ * Calls `callback` with `user_data` whenever the program sleeps,
+ * or sleeps the current thread again if `callback` is `NULL`.
+ */
+enum SvmStatus svm_machine_set_sleep_ms(struct SvmMachine *machine,
+                                        SvmSleepFn callback,
+                                        void *user_data);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* SIMPLE_VM_H */
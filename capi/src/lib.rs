@@ -0,0 +1,489 @@
+//! A C API for embedding `simple_vm`.
+//!
+//! Machines are handed out as opaque `SvmMachine` pointers, created from
+//! source with `svm_machine_new` and destroyed with `svm_machine_free`.
+//! Functions that can fail return an `SvmStatus` (or `NULL`), and the
+//! message of the most recent failure on the calling thread is returned by
+//! `svm_last_error`.
+//!
+//! Every pointer passed in must either be `NULL` or valid for the duration
+//! of the call, and strings must be nul terminated UTF-8. The header for
+//! this API, `include/simple_vm.h`, is generated by `cbindgen` when the
+//! crate is built.
+
+#![allow(clippy::missing_safety_doc)]
+
+extern crate simple_vm;
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use simple_vm::*;
+
+/// A machine, created by `svm_machine_new`.
+pub struct SvmMachine {
+    machine: Machine<CEffect>,
+    /// The machine's stack, kept around so it can be read by index.
+    stack: Vec<StackValue>,
+}
+
+impl SvmMachine {
+    fn sync_stack(&mut self) {
+        self.stack = self.machine.stack();
+    }
+}
+
+/// The result of a call that can fail.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SvmStatus {
+    Ok = 0,
+    /// The call failed, see `svm_last_error`.
+    Error = 1,
+}
+
+/// The type of a value on the stack.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SvmValueKind {
+    Bool,
+    Num,
+    String,
+    /// A label, operation or native function.
+    Other,
+}
+
+/// Limits for `svm_machine_run`, where `0` means unlimited.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SvmLimits {
+    /// Maximum number of steps, counted since the machine was created or reset.
+    pub steps: usize,
+    /// Maximum number of values on the stack.
+    pub stack_depth: usize,
+    /// Maximum depth of nested `call`s.
+    pub return_stack_depth: usize,
+    /// Maximum number of bytes held by strings on the stack.
+    pub string_bytes: usize,
+    /// Maximum number of milliseconds the run can take.
+    pub timeout_ms: u64,
+}
+
+/// Called with every line the program prints.
+pub type SvmPrintlnFn = Option<extern "C" fn(user_data: *mut c_void, line: *const c_char)>;
+
+/// Called when the program reads a line. The returned string is copied and
+/// only needs to live until the callback is called again, `NULL` reads an
+/// empty line.
+pub type SvmReadLineFn = Option<extern "C" fn(user_data: *mut c_void) -> *const c_char>;
+
+/// Called when the program sleeps for `duration_ms` milliseconds.
+pub type SvmSleepFn = Option<extern "C" fn(user_data: *mut c_void, duration_ms: u64)>;
+
+/// A `SideEffect` calling back into C, falling back to the
+/// `DefaultSideEffect` for callbacks that have not been set, and for stderr,
+/// `print`, `flush`, files, the environment and the clock.
+#[derive(Default)]
+struct CEffect {
+    println: Option<(extern "C" fn(*mut c_void, *const c_char), *mut c_void)>,
+    read_line: Option<(extern "C" fn(*mut c_void) -> *const c_char, *mut c_void)>,
+    sleep_ms: Option<(extern "C" fn(*mut c_void, u64), *mut c_void)>,
+    default: DefaultSideEffect,
+}
+
+impl SideEffect for CEffect {
+    fn println<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        match self.println {
+            Some((println, user_data)) => {
+                println(user_data, to_c_string(value.to_string()).as_ptr());
+                Ok(())
+            }
+            None => self.default.println(value),
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        match self.read_line {
+            Some((read_line, user_data)) => {
+                let line = read_line(user_data);
+                if line.is_null() {
+                    Ok(String::new())
+                } else {
+                    Ok(unsafe { CStr::from_ptr(line) }.to_string_lossy().trim().to_owned())
+                }
+            }
+            None => self.default.read_line(),
+        }
+    }
+
+    fn sleep_ms(&mut self, duration: u64) -> io::Result<()> {
+        match self.sleep_ms {
+            Some((sleep_ms, user_data)) => {
+                sleep_ms(user_data, duration);
+                Ok(())
+            }
+            None => self.default.sleep_ms(duration),
+        }
+    }
+
+    fn eprintln<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        self.default.eprintln(value)
+    }
+
+    fn print<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        self.default.print(value)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.default.flush()
+    }
+
+    fn open(&mut self, path: &str) -> io::Result<()> {
+        self.default.open(path)
+    }
+
+    fn read_file(&mut self, path: &str) -> io::Result<String> {
+        self.default.read_file(path)
+    }
+
+    fn write_file(&mut self, path: &str, contents: &str) -> io::Result<()> {
+        self.default.write_file(path, contents)
+    }
+
+    fn append_file(&mut self, path: &str, contents: &str) -> io::Result<()> {
+        self.default.append_file(path, contents)
+    }
+
+    fn exists(&mut self, path: &str) -> io::Result<bool> {
+        self.default.exists(path)
+    }
+
+    fn list_dir(&mut self, path: &str) -> io::Result<Vec<String>> {
+        self.default.list_dir(path)
+    }
+
+    fn env_var(&mut self, name: &str) -> io::Result<Option<String>> {
+        self.default.env_var(name)
+    }
+
+    fn now_ms(&mut self) -> io::Result<u64> {
+        self.default.now_ms()
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(to_c_string(message)));
+}
+
+/// Nul bytes can't be represented in a C string, so they are dropped.
+fn to_c_string(s: String) -> CString {
+    CString::new(s.replace('\0', "")).unwrap()
+}
+
+fn status(result: Result<(), String>) -> SvmStatus {
+    match result {
+        Ok(()) => SvmStatus::Ok,
+        Err(message) => {
+            set_last_error(message);
+            SvmStatus::Error
+        }
+    }
+}
+
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err(format!("{} is NULL", name));
+    }
+    CStr::from_ptr(s).to_str().map_err(|_| format!("{} is not valid UTF-8", name))
+}
+
+unsafe fn machine_arg<'a>(machine: *mut SvmMachine) -> Result<&'a mut SvmMachine, String> {
+    machine.as_mut().ok_or_else(|| "machine is NULL".to_owned())
+}
+
+unsafe fn stack_arg<'a>(machine: *const SvmMachine, index: usize) -> Result<&'a StackValue, String> {
+    let machine = machine.as_ref().ok_or_else(|| "machine is NULL".to_owned())?;
+    machine.stack.get(index).ok_or_else(|| {
+        format!("index {} is out of bounds for a stack of {}", index, machine.stack.len())
+    })
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown error".to_owned());
+    format!("the machine panicked: {}", message)
+}
+
+/// The message of the last error on this thread, or `NULL` if there hasn't
+/// been one. It is valid until the next error on this thread.
+#[no_mangle]
+pub extern "C" fn svm_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+/// Creates a machine for `source`, returning `NULL` if it can't be parsed.
+#[no_mangle]
+pub unsafe extern "C" fn svm_machine_new(source: *const c_char) -> *mut SvmMachine {
+    let result = str_arg(source, "source")
+        .and_then(|source| tokenize(source).map_err(|e| e.to_string()))
+        .and_then(|code| Machine::new(code).map_err(|e| e.to_string()));
+    match result {
+        Ok(machine) => Box::into_raw(Box::new(SvmMachine { machine, stack: vec![] })),
+        Err(message) => {
+            set_last_error(message);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Destroys a machine created by `svm_machine_new`, `NULL` is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn svm_machine_free(machine: *mut SvmMachine) {
+    if !machine.is_null() {
+        drop(Box::from_raw(machine));
+    }
+}
+
+/// Resets the machine to run its program again from the start, with an empty stack.
+#[no_mangle]
+pub unsafe extern "C" fn svm_machine_reset(machine: *mut SvmMachine) -> SvmStatus {
+    status(machine_arg(machine).map(|machine| {
+        machine.machine.reset();
+        machine.sync_stack();
+    }))
+}
+
+unsafe fn push(machine: *mut SvmMachine, value: Result<StackValue, String>) -> SvmStatus {
+    status(machine_arg(machine).and_then(|machine| {
+        machine.machine.stack_push(vec![value?]).map_err(|e| e.to_string())?;
+        machine.sync_stack();
+        Ok(())
+    }))
+}
+
+/// Pushes a number, as an argument for the program.
+#[no_mangle]
+pub unsafe extern "C" fn svm_machine_push_num(machine: *mut SvmMachine, value: isize) -> SvmStatus {
+    push(machine, Ok(StackValue::Num(value)))
+}
+
+/// Pushes a boolean, as an argument for the program.
+#[no_mangle]
+pub unsafe extern "C" fn svm_machine_push_bool(machine: *mut SvmMachine, value: bool) -> SvmStatus {
+    push(machine, Ok(StackValue::Bool(value)))
+}
+
+/// Pushes a copy of a string, as an argument for the program.
+#[no_mangle]
+pub unsafe extern "C" fn svm_machine_push_string(machine: *mut SvmMachine, value: *const c_char) -> SvmStatus {
+    push(machine, str_arg(value, "value").map(|value| StackValue::String(value.to_owned())))
+}
+
+/// Runs the machine until its program stops, storing the exit code in
+/// `exit_code` unless it is `NULL`. `limits` may be `NULL` for no limits.
+#[no_mangle]
+pub unsafe extern "C" fn svm_machine_run(
+    machine: *mut SvmMachine,
+    limits: *const SvmLimits,
+    exit_code: *mut i32,
+) -> SvmStatus {
+    let machine = match machine_arg(machine) {
+        Ok(machine) => machine,
+        Err(message) => return status(Err(message)),
+    };
+    let limits = limits.as_ref().cloned().unwrap_or_default();
+    let unlimited_if_zero = |limit: usize| if limit == 0 { None } else { Some(limit) };
+    machine.machine.set_step_limit(unlimited_if_zero(limits.steps));
+    machine.machine.set_limits(Limits {
+        stack_depth: unlimited_if_zero(limits.stack_depth),
+        return_stack_depth: unlimited_if_zero(limits.return_stack_depth),
+        string_bytes: unlimited_if_zero(limits.string_bytes),
+    });
+    machine.machine.set_deadline(match limits.timeout_ms {
+        0 => None,
+        ms => Some(Instant::now() + Duration::from_millis(ms)),
+    });
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| machine.machine.run(vec![])));
+    machine.sync_stack();
+    status(match result {
+        Ok(Ok(RunStatus::Stopped(code))) => {
+            if let Some(exit_code) = exit_code.as_mut() {
+                *exit_code = code;
+            }
+            Ok(())
+        }
+        Ok(Ok(RunStatus::Paused(reason))) => Err(format!("the machine paused: {:?}", reason)),
+        Ok(Err(error)) => Err(error.to_string()),
+        Err(payload) => Err(panic_message(payload)),
+    })
+}
+
+/// The number of values on the stack.
+#[no_mangle]
+pub unsafe extern "C" fn svm_machine_stack_len(machine: *const SvmMachine) -> usize {
+    machine.as_ref().map_or(0, |machine| machine.stack.len())
+}
+
+/// Stores the type of the value at `index` in `kind`, where `0` is the
+/// bottom of the stack.
+#[no_mangle]
+pub unsafe extern "C" fn svm_machine_stack_kind(
+    machine: *const SvmMachine,
+    index: usize,
+    kind: *mut SvmValueKind,
+) -> SvmStatus {
+    status(stack_arg(machine, index).map(|value| {
+        let value_kind = match *value {
+            StackValue::Bool(_) => SvmValueKind::Bool,
+            StackValue::Num(_) => SvmValueKind::Num,
+            StackValue::String(_) => SvmValueKind::String,
+            _ => SvmValueKind::Other,
+        };
+        if let Some(kind) = kind.as_mut() {
+            *kind = value_kind;
+        }
+    }))
+}
+
+/// Stores the number at `index` in `value`, failing if it isn't a number.
+#[no_mangle]
+pub unsafe extern "C" fn svm_machine_stack_num(machine: *const SvmMachine, index: usize, value: *mut isize) -> SvmStatus {
+    status(stack_arg(machine, index).and_then(|stack_value| match *stack_value {
+        StackValue::Num(n) => {
+            if let Some(value) = value.as_mut() {
+                *value = n;
+            }
+            Ok(())
+        }
+        ref other => Err(format!("{} is not a number", other)),
+    }))
+}
+
+/// Stores the boolean at `index` in `value`, failing if it isn't a boolean.
+#[no_mangle]
+pub unsafe extern "C" fn svm_machine_stack_bool(machine: *const SvmMachine, index: usize, value: *mut bool) -> SvmStatus {
+    status(stack_arg(machine, index).and_then(|stack_value| match *stack_value {
+        StackValue::Bool(b) => {
+            if let Some(value) = value.as_mut() {
+                *value = b;
+            }
+            Ok(())
+        }
+        ref other => Err(format!("{} is not a boolean", other)),
+    }))
+}
+
+/// The value at `index` as a string, the way `println` would print it, or
+/// `NULL` if there is no such value. It must be freed with `svm_string_free`.
+#[no_mangle]
+pub unsafe extern "C" fn svm_machine_stack_string(machine: *const SvmMachine, index: usize) -> *mut c_char {
+    match stack_arg(machine, index) {
+        Ok(value) => to_c_string(value.to_string()).into_raw(),
+        Err(message) => {
+            set_last_error(message);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Frees a string returned by this library, `NULL` is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn svm_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Calls `callback` with `user_data` for every line the program prints,
+/// or prints to stdout again if `callback` is `NULL`.
+#[no_mangle]
+pub unsafe extern "C" fn svm_machine_set_println(
+    machine: *mut SvmMachine,
+    callback: SvmPrintlnFn,
+    user_data: *mut c_void,
+) -> SvmStatus {
+    status(machine_arg(machine).map(|machine| {
+        machine.machine.effect_mut().println = callback.map(|callback| (callback, user_data));
+    }))
+}
+
+/// Calls `callback` with `user_data` for every line the program reads,
+/// or reads from stdin again if `callback` is `NULL`.
+#[no_mangle]
+pub unsafe extern "C" fn svm_machine_set_read_line(
+    machine: *mut SvmMachine,
+    callback: SvmReadLineFn,
+    user_data: *mut c_void,
+) -> SvmStatus {
+    status(machine_arg(machine).map(|machine| {
+        machine.machine.effect_mut().read_line = callback.map(|callback| (callback, user_data));
+    }))
+}
+
+/// Calls `callback` with `user_data` whenever the program sleeps,
+/// or sleeps the current thread again if `callback` is `NULL`.
+#[no_mangle]
+pub unsafe extern "C" fn svm_machine_set_sleep_ms(
+    machine: *mut SvmMachine,
+    callback: SvmSleepFn,
+    user_data: *mut c_void,
+) -> SvmStatus {
+    status(machine_arg(machine).map(|machine| {
+        machine.machine.effect_mut().sleep_ms = callback.map(|callback| (callback, user_data));
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(svm_last_error()) }.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_run() {
+        unsafe {
+            let source = CString::new("1 + stop").unwrap();
+            let machine = svm_machine_new(source.as_ptr());
+            assert_eq!(SvmStatus::Ok, svm_machine_push_num(machine, 41));
+            let mut exit_code = -1;
+            assert_eq!(SvmStatus::Ok, svm_machine_run(machine, ptr::null(), &mut exit_code));
+            assert_eq!(0, exit_code);
+            let mut value = 0;
+            assert_eq!(SvmStatus::Ok, svm_machine_stack_num(machine, 0, &mut value));
+            assert_eq!(42, value);
+            svm_machine_free(machine);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        unsafe {
+            let source = CString::new("nowhere jmp").unwrap();
+            assert!(svm_machine_new(source.as_ptr()).is_null());
+            assert!(last_error().contains("nowhere"));
+
+            assert_eq!(SvmStatus::Error, svm_machine_push_num(ptr::null_mut(), 1));
+            assert_eq!("machine is NULL", last_error());
+
+            let source = CString::new("1 0 /").unwrap();
+            let machine = svm_machine_new(source.as_ptr());
+            assert_eq!(SvmStatus::Error, svm_machine_run(machine, ptr::null(), ptr::null_mut()));
+            assert!(last_error().starts_with("the machine panicked"));
+            svm_machine_free(machine);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+/* Exercises the C API, exiting with a failure if any check fails. */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "simple_vm.h"
+
+#define CHECK(cond)                                                           \
+    do {                                                                      \
+        if (!(cond)) {                                                        \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, \
+                    #cond);                                                   \
+            const char *error = svm_last_error();                             \
+            if (error) {                                                      \
+                fprintf(stderr, "last error: %s\n", error);                   \
+            }                                                                 \
+            exit(1);                                                          \
+        }                                                                     \
+    } while (0)
+
+struct io {
+    char output[256];
+    const char *input;
+    uint64_t slept;
+};
+
+static void on_println(void *user_data, const char *line) {
+    struct io *io = user_data;
+    strcat(io->output, line);
+    strcat(io->output, "\n");
+}
+
+static const char *on_read_line(void *user_data) {
+    return ((struct io *)user_data)->input;
+}
+
+static void on_sleep_ms(void *user_data, uint64_t duration_ms) {
+    ((struct io *)user_data)->slept += duration_ms;
+}
+
+static void test_run_with_arguments(void) {
+    SvmMachine *machine = svm_machine_new("+ swap not 3 exit");
+    CHECK(machine != NULL);
+    CHECK(svm_machine_push_bool(machine, false) == SVM_STATUS_OK);
+    CHECK(svm_machine_push_num(machine, 40) == SVM_STATUS_OK);
+    CHECK(svm_machine_push_num(machine, 2) == SVM_STATUS_OK);
+
+    int32_t exit_code = -1;
+    CHECK(svm_machine_run(machine, NULL, &exit_code) == SVM_STATUS_OK);
+    CHECK(exit_code == 3);
+
+    CHECK(svm_machine_stack_len(machine) == 2);
+    SvmValueKind kind;
+    CHECK(svm_machine_stack_kind(machine, 0, &kind) == SVM_STATUS_OK);
+    CHECK(kind == SVM_VALUE_KIND_NUM);
+    ptrdiff_t num = 0;
+    CHECK(svm_machine_stack_num(machine, 0, &num) == SVM_STATUS_OK);
+    CHECK(num == 42);
+    bool boolean = false;
+    CHECK(svm_machine_stack_bool(machine, 1, &boolean) == SVM_STATUS_OK);
+    CHECK(boolean);
+    CHECK(svm_machine_stack_num(machine, 1, &num) == SVM_STATUS_ERROR);
+    CHECK(svm_machine_stack_kind(machine, 2, &kind) == SVM_STATUS_ERROR);
+
+    svm_machine_free(machine);
+}
+
+static void test_strings(void) {
+    SvmMachine *machine = svm_machine_new("\" world\" swap 1 cast_str");
+    CHECK(machine != NULL);
+    CHECK(svm_machine_push_string(machine, "hello") == SVM_STATUS_OK);
+    CHECK(svm_machine_run(machine, NULL, NULL) == SVM_STATUS_OK);
+
+    char *value = svm_machine_stack_string(machine, 1);
+    CHECK(value != NULL);
+    CHECK(strcmp(value, "hello") == 0);
+    svm_string_free(value);
+    SvmValueKind kind;
+    CHECK(svm_machine_stack_kind(machine, 2, &kind) == SVM_STATUS_OK);
+    CHECK(kind == SVM_VALUE_KIND_STRING);
+    CHECK(svm_machine_stack_string(machine, 3) == NULL);
+
+    svm_machine_free(machine);
+}
+
+static void test_callbacks(void) {
+    struct io io = {{0}, "7", 0};
+    SvmMachine *machine = svm_machine_new("read cast_int dup println 1 + println 5 sleep_ms 6 sleep_ms");
+    CHECK(machine != NULL);
+    CHECK(svm_machine_set_println(machine, on_println, &io) == SVM_STATUS_OK);
+    CHECK(svm_machine_set_read_line(machine, on_read_line, &io) == SVM_STATUS_OK);
+    CHECK(svm_machine_set_sleep_ms(machine, on_sleep_ms, &io) == SVM_STATUS_OK);
+    CHECK(svm_machine_run(machine, NULL, NULL) == SVM_STATUS_OK);
+
+    CHECK(strcmp(io.output, "7\n8\n") == 0);
+    CHECK(io.slept == 11);
+    CHECK(svm_machine_stack_len(machine) == 0);
+
+    svm_machine_free(machine);
+}
+
+static void test_errors(void) {
+    CHECK(svm_machine_new("nowhere jmp") == NULL);
+    CHECK(strstr(svm_last_error(), "nowhere") != NULL);
+
+    SvmMachine *machine = svm_machine_new("drop");
+    CHECK(machine != NULL);
+    CHECK(svm_machine_run(machine, NULL, NULL) == SVM_STATUS_ERROR);
+    CHECK(strstr(svm_last_error(), "empty stack") != NULL);
+    svm_machine_free(machine);
+}
+
+static void test_limits(void) {
+    SvmMachine *machine = svm_machine_new("loop: 1 loop jmp");
+    CHECK(machine != NULL);
+
+    SvmLimits limits = {0};
+    limits.steps = 100;
+    CHECK(svm_machine_run(machine, &limits, NULL) == SVM_STATUS_ERROR);
+    CHECK(strstr(svm_last_error(), "100") != NULL);
+
+    CHECK(svm_machine_reset(machine) == SVM_STATUS_OK);
+    limits.steps = 0;
+    limits.stack_depth = 10;
+    CHECK(svm_machine_run(machine, &limits, NULL) == SVM_STATUS_ERROR);
+    CHECK(svm_machine_stack_len(machine) == 10);
+
+    CHECK(svm_machine_reset(machine) == SVM_STATUS_OK);
+    CHECK(svm_machine_stack_len(machine) == 0);
+    SvmMachine *looping = svm_machine_new("loop: loop jmp");
+    SvmLimits timeout = {0};
+    timeout.timeout_ms = 10;
+    CHECK(svm_machine_run(looping, &timeout, NULL) == SVM_STATUS_ERROR);
+
+    svm_machine_free(looping);
+    svm_machine_free(machine);
+}
+
+int main(void) {
+    test_run_with_arguments();
+    test_strings();
+    test_callbacks();
+    test_errors();
+    test_limits();
+    printf("ok\n");
+    return 0;
+}
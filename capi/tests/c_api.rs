@@ -0,0 +1,56 @@
+//! Checks that the committed header is current, and compiles
+//! `tests/c/machine.c` against it and the `cdylib`, and runs it.
+
+#![cfg(unix)]
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+#[test]
+fn test_header_is_current() {
+    let generated = concat!(env!("OUT_DIR"), "/simple_vm.h");
+    let committed = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("include/simple_vm.h");
+    assert!(
+        fs::read_to_string(generated).unwrap() == fs::read_to_string(&committed).unwrap(),
+        "include/simple_vm.h is out of date, update it with:\n    cp {} {}",
+        generated,
+        committed.display()
+    );
+}
+
+#[test]
+fn test_c_program() {
+    let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    // Tests live in `target/<profile>/deps`, next to which the library is built.
+    let lib_dir = env::current_exe().unwrap().parent().unwrap().parent().unwrap().to_path_buf();
+    let out_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let binary = out_dir.join("machine");
+
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_owned());
+    let status = Command::new(compiler)
+        .arg(manifest_dir.join("tests/c/machine.c"))
+        .arg("-std=c99")
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-I")
+        .arg(manifest_dir.join("include"))
+        .arg("-L")
+        .arg(&lib_dir)
+        .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+        .arg("-lsimple_vm_capi")
+        .arg("-o")
+        .arg(&binary)
+        .status()
+        .expect("failed to run the C compiler");
+    assert!(status.success(), "failed to compile the C test program");
+
+    let output = Command::new(&binary).output().unwrap();
+    assert!(
+        output.status.success(),
+        "the C test program failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!("ok\n", String::from_utf8_lossy(&output.stdout));
+}
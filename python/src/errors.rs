@@ -0,0 +1,64 @@
+//! Python exceptions for `simple_vm::StackError`.
+//!
+//! Every variant gets its own exception, all of them subclasses of
+//! `simple_vm.StackError`, so they can be caught together or one at a time.
+
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+
+use simple_vm::error::StackError as VmError;
+
+create_exception!(simple_vm, StackError, PyException, "Base class of the errors raised by the VM.");
+
+macro_rules! exceptions {
+    ($($variant:ident => $name:ident,)+) => {
+        $(
+            create_exception!(
+                simple_vm,
+                $name,
+                StackError,
+                concat!("Raised for `StackError::", stringify!($variant), "`.")
+            );
+        )+
+
+        /// Converts an error from the VM to the matching Python exception.
+        pub fn to_py_err(error: VmError) -> PyErr {
+            let message = error.to_string();
+            match error {
+                $(VmError::$variant { .. } => $name::new_err(message),)+
+            }
+        }
+
+        /// Adds the exceptions to the module.
+        pub fn register(module: &Bound<PyModule>) -> PyResult<()> {
+            let py = module.py();
+            module.add("StackError", py.get_type::<StackError>())?;
+            $(module.add(stringify!($name), py.get_type::<$name>())?;)+
+            Ok(())
+        }
+    };
+}
+
+exceptions! {
+    BudgetExhausted => BudgetExhaustedError,
+    Cancelled => CancelledError,
+    EmptyStack => EmptyStackError,
+    InvalidNative => InvalidNativeError,
+    InvalidOperation => InvalidOperationError,
+    InvalidString => InvalidStringError,
+    InvalidRecording => InvalidRecordingError,
+    InvalidSnapshot => InvalidSnapshotError,
+    Io => IoError,
+    MultipleLabelDefinitions => MultipleLabelDefinitionsError,
+    NativeFailed => NativeFailedError,
+    OutOfBounds => OutOfBoundsError,
+    PatternMismatch => PatternMismatchError,
+    PermissionDenied => PermissionDeniedError,
+    ReturnStackOverflow => ReturnStackOverflowError,
+    StackIndexOutOfBounds => StackIndexOutOfBoundsError,
+    StackOverflow => StackOverflowError,
+    StringMemoryExceeded => StringMemoryExceededError,
+    TimedOut => TimedOutError,
+    UndefinedLabel => UndefinedLabelError,
+}
@@ -0,0 +1,479 @@
+//! Python bindings for `simple_vm`, built with maturin as the `simple_vm` module.
+//!
+//! ```python
+//! import simple_vm
+//!
+//! machine = simple_vm.Machine("dup * stop")
+//! machine.run([7])  # the exit code, 0
+//! machine.stack()   # [49]
+//! ```
+//!
+//! Booleans, numbers and strings are converted to and from `bool`, `int`
+//! and `str`. Labels, operations and native functions, which have no Python
+//! equivalent, are `Symbol`s. Errors from the VM are raised as subclasses of
+//! `simple_vm.StackError`, see the `errors` module.
+
+extern crate pyo3;
+extern crate simple_vm;
+
+mod errors;
+
+use std::io;
+use std::str::FromStr;
+
+use pyo3::exceptions::{PyRuntimeError, PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyDict, PyInt, PyString};
+
+use simple_vm::error::StackError;
+use simple_vm::{DefaultSideEffect, Instruction, PauseReason, RunStatus, SideEffect, StackOperation, StackValue};
+
+/// How many steps `Machine.run` takes between checking for exceptions
+/// raised by the effect and for signals like `KeyboardInterrupt`.
+const STEPS_BETWEEN_CHECKS: usize = 1024;
+
+/// A label, operation or native function on the stack.
+#[pyclass(frozen, eq, module = "simple_vm")]
+#[derive(Clone, Debug, PartialEq)]
+struct Symbol {
+    /// One of `"label"`, `"possible_label"`, `"operation"` or `"native"`.
+    #[pyo3(get)]
+    kind: String,
+    /// The label or operation as it is written, or the index of the native.
+    #[pyo3(get)]
+    name: String,
+}
+
+#[pymethods]
+impl Symbol {
+    #[new]
+    fn new(kind: String, name: String) -> PyResult<Symbol> {
+        let symbol = Symbol { kind, name };
+        symbol.to_value()?;
+        Ok(symbol)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Symbol({:?}, {:?})", self.kind, self.name)
+    }
+}
+
+impl Symbol {
+    fn to_value(&self) -> PyResult<StackValue> {
+        match self.kind.as_str() {
+            "label" => Ok(StackValue::Label(self.name.clone())),
+            "possible_label" => Ok(StackValue::PossibleLabel(self.name.clone())),
+            "operation" => StackOperation::from_str(&self.name)
+                .map(StackValue::Operation)
+                .map_err(errors::to_py_err),
+            "native" => self.name.parse()
+                .map(StackValue::Native)
+                .map_err(|_| PyValueError::new_err(format!("{:?} is not the index of a native", self.name))),
+            kind => Err(PyValueError::new_err(format!("{:?} is not a kind of symbol", kind))),
+        }
+    }
+}
+
+fn to_py(py: Python, value: &StackValue) -> PyResult<Py<PyAny>> {
+    let symbol = |kind: &str, name: String| {
+        Py::new(py, Symbol { kind: kind.to_owned(), name }).map(Py::into_any)
+    };
+    match *value {
+        StackValue::Bool(b) => Ok(PyBool::new(py, b).to_owned().into_any().unbind()),
+        StackValue::Num(n) => Ok(n.into_pyobject(py)?.into_any().unbind()),
+        StackValue::String(ref s) => Ok(PyString::new(py, s).into_any().unbind()),
+        StackValue::Label(ref name) => symbol("label", name.clone()),
+        StackValue::PossibleLabel(ref name) => symbol("possible_label", name.clone()),
+        StackValue::Operation(ref op) => symbol("operation", op.name().to_owned()),
+        StackValue::Native(index) => symbol("native", index.to_string()),
+    }
+}
+
+fn from_py(value: &Bound<PyAny>) -> PyResult<StackValue> {
+    if value.is_instance_of::<PyBool>() {
+        Ok(StackValue::Bool(value.extract()?))
+    } else if value.is_instance_of::<PyInt>() {
+        Ok(StackValue::Num(value.extract()?))
+    } else if value.is_instance_of::<PyString>() {
+        Ok(StackValue::String(value.extract()?))
+    } else if let Ok(symbol) = value.cast::<Symbol>() {
+        symbol.get().to_value()
+    } else {
+        Err(PyTypeError::new_err(format!(
+            "a {} can't be put on the stack",
+            value.get_type().name()?
+        )))
+    }
+}
+
+fn values_from_py(values: &Bound<PyAny>) -> PyResult<Vec<StackValue>> {
+    values.try_iter()?.map(|value| from_py(&value?)).collect()
+}
+
+/// A `SideEffect` calling the `println`, `eprintln`, `print`, `flush`,
+/// `read_line` and `sleep_ms` methods of a Python object, falling back to
+/// `print`, `sys.stdout.flush`, `input` and `time.sleep` for the methods
+/// it doesn't have. Files, the environment and the clock are the real ones.
+struct PyEffect {
+    effect: Option<Py<PyAny>>,
+    /// Used for the file, environment and clock operations.
+    system: DefaultSideEffect,
+    /// The exception raised while calling back into Python, raised again
+    /// once it has stopped the machine.
+    error: Option<PyErr>,
+}
+
+impl PyEffect {
+    fn method<'py>(&self, py: Python<'py>, name: &str) -> PyResult<Option<Bound<'py, PyAny>>> {
+        match self.effect {
+            Some(ref effect) if effect.bind(py).hasattr(name)? => effect.bind(py).getattr(name).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Keeps an exception for `Machine::run` to raise, failing the
+    /// operation with its message.
+    fn record<T>(&mut self, result: PyResult<T>) -> io::Result<T> {
+        result.map_err(|error| {
+            let message = error.to_string();
+            self.error = Some(error);
+            io::Error::other(message)
+        })
+    }
+}
+
+impl SideEffect for PyEffect {
+    fn println<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        let line = value.to_string();
+        let result = Python::attach(|py| match self.method(py, "println")? {
+            Some(println) => println.call1((line,)).map(|_| ()),
+            None => py.import("builtins")?.getattr("print")?.call1((line,)).map(|_| ()),
+        });
+        self.record(result)
+    }
+
+    fn eprintln<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        let line = value.to_string();
+        let result = Python::attach(|py| match self.method(py, "eprintln")? {
+            Some(eprintln) => eprintln.call1((line,)).map(|_| ()),
+            None => {
+                let kwargs = PyDict::new(py);
+                kwargs.set_item("file", py.import("sys")?.getattr("stderr")?)?;
+                py.import("builtins")?.getattr("print")?.call((line,), Some(&kwargs)).map(|_| ())
+            }
+        });
+        self.record(result)
+    }
+
+    fn print<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        let text = value.to_string();
+        let result = Python::attach(|py| match self.method(py, "print")? {
+            Some(print) => print.call1((text,)).map(|_| ()),
+            None => {
+                let kwargs = PyDict::new(py);
+                kwargs.set_item("end", "")?;
+                py.import("builtins")?.getattr("print")?.call((text,), Some(&kwargs)).map(|_| ())
+            }
+        });
+        self.record(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = Python::attach(|py| match self.method(py, "flush")? {
+            Some(flush) => flush.call0().map(|_| ()),
+            None => py.import("sys")?.getattr("stdout")?.getattr("flush")?.call0().map(|_| ()),
+        });
+        self.record(result)
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let result = Python::attach(|py| {
+            let line = match self.method(py, "read_line")? {
+                Some(read_line) => read_line.call0()?,
+                None => py.import("builtins")?.getattr("input")?.call0()?,
+            };
+            line.extract::<String>()
+        });
+        self.record(result).map(|line| line.trim().to_owned())
+    }
+
+    fn sleep_ms(&mut self, duration: u64) -> io::Result<()> {
+        let result = Python::attach(|py| match self.method(py, "sleep_ms")? {
+            Some(sleep_ms) => sleep_ms.call1((duration,)).map(|_| ()),
+            None => py.import("time")?.getattr("sleep")?.call1((duration as f64 / 1000.0,)).map(|_| ()),
+        });
+        self.record(result)
+    }
+
+    fn open(&mut self, path: &str) -> io::Result<()> {
+        self.system.open(path)
+    }
+
+    fn read_file(&mut self, path: &str) -> io::Result<String> {
+        self.system.read_file(path)
+    }
+
+    fn write_file(&mut self, path: &str, contents: &str) -> io::Result<()> {
+        self.system.write_file(path, contents)
+    }
+
+    fn append_file(&mut self, path: &str, contents: &str) -> io::Result<()> {
+        self.system.append_file(path, contents)
+    }
+
+    fn exists(&mut self, path: &str) -> io::Result<bool> {
+        self.system.exists(path)
+    }
+
+    fn list_dir(&mut self, path: &str) -> io::Result<Vec<String>> {
+        self.system.list_dir(path)
+    }
+
+    fn env_var(&mut self, name: &str) -> io::Result<Option<String>> {
+        self.system.env_var(name)
+    }
+
+    fn now_ms(&mut self) -> io::Result<u64> {
+        self.system.now_ms()
+    }
+}
+
+/// A machine running a program.
+///
+/// `code` is either source or a list of values, like the ones returned by
+/// `tokenize`. `effect` is an optional object with `println(line)`,
+/// `eprintln(line)`, `print(text)`, `flush()`, `read_line()` and
+/// `sleep_ms(duration)` methods used for I/O.
+#[pyclass(unsendable, module = "simple_vm")]
+struct Machine {
+    machine: simple_vm::Machine<PyEffect>,
+}
+
+#[pymethods]
+impl Machine {
+    #[new]
+    #[pyo3(signature = (code, effect = None))]
+    fn new(code: &Bound<PyAny>, effect: Option<Py<PyAny>>) -> PyResult<Machine> {
+        let code = if code.is_instance_of::<PyString>() {
+            simple_vm::tokenize(&code.extract::<String>()?).map_err(errors::to_py_err)?
+        } else {
+            values_from_py(code)?
+        };
+        let effect = PyEffect {
+            effect,
+            system: DefaultSideEffect::default(),
+            error: None,
+        };
+        let machine = simple_vm::Machine::<_>::with_effect(code, effect).map_err(errors::to_py_err)?;
+        Ok(Machine { machine })
+    }
+
+    /// Pushes `args` and runs the program until it stops, returning its exit code.
+    ///
+    /// An exception raised by the effect stops the program and is raised again.
+    #[pyo3(signature = (args = None))]
+    fn run(&mut self, py: Python, args: Option<&Bound<PyAny>>) -> PyResult<i32> {
+        let args = match args {
+            Some(args) => values_from_py(args)?,
+            None => vec![],
+        };
+        self.machine.stack_push(args).map_err(errors::to_py_err)?;
+        loop {
+            let status = self.machine.run_for(STEPS_BETWEEN_CHECKS);
+            let status = match (status, self.machine.effect_mut().error.take()) {
+                (Err(StackError::Io { .. }), Some(error)) => return Err(error),
+                (status, _) => status.map_err(errors::to_py_err)?,
+            };
+            match status {
+                RunStatus::Stopped(exit_code) => return Ok(exit_code),
+                RunStatus::Paused(PauseReason::StepBudget) => py.check_signals()?,
+                RunStatus::Paused(reason) => {
+                    return Err(PyRuntimeError::new_err(format!("the machine paused: {:?}", reason)))
+                }
+            }
+        }
+    }
+
+    /// The values on the stack, from the bottom up.
+    fn stack(&self, py: Python) -> PyResult<Vec<Py<PyAny>>> {
+        self.machine.stack().iter().map(|value| to_py(py, value)).collect()
+    }
+
+    /// Goes back to the start of the program, with an empty stack.
+    fn reset(&mut self) {
+        self.machine.reset();
+    }
+
+    /// The number of steps taken since the machine was created or reset.
+    #[getter]
+    fn steps(&self) -> usize {
+        self.machine.steps()
+    }
+}
+
+/// Splits source into a list of values, without resolving labels.
+#[pyfunction]
+fn tokenize(py: Python, source: &str) -> PyResult<Vec<Py<PyAny>>> {
+    let code = simple_vm::tokenize(source).map_err(errors::to_py_err)?;
+    code.iter().map(|value| to_py(py, value)).collect()
+}
+
+#[pymodule]
+#[pyo3(name = "simple_vm")]
+fn simple_vm_python(module: &Bound<PyModule>) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(tokenize, module)?)?;
+    module.add_class::<Machine>()?;
+    module.add_class::<Symbol>()?;
+    errors::register(module)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::ffi::CString;
+
+    /// Runs the Python `code` with the module imported as `simple_vm`.
+    fn run_python(code: &str) {
+        Python::initialize();
+        Python::attach(|py| {
+            let module = PyModule::new(py, "simple_vm").unwrap();
+            simple_vm_python(&module).unwrap();
+            let globals = PyDict::new(py);
+            globals.set_item("simple_vm", module).unwrap();
+            if let Err(error) = py.run(&CString::new(code).unwrap(), Some(&globals), None) {
+                error.display(py);
+                panic!("the Python code raised an exception");
+            }
+        });
+    }
+
+    #[test]
+    fn test_run() {
+        run_python(
+            r#"
+machine = simple_vm.Machine("dup * stop")
+assert machine.run([7]) == 0
+assert machine.stack() == [49]
+assert machine.steps == 3
+machine.reset()
+assert machine.stack() == []
+assert simple_vm.Machine("2 exit").run() == 2
+"#,
+        );
+    }
+
+    #[test]
+    fn test_conversions() {
+        run_python(
+            r#"
+Symbol = simple_vm.Symbol
+code = simple_vm.tokenize('f: "hi" true -3 5 + f')
+assert code == [
+    Symbol("label", "f"), "hi", True, -3, 5, Symbol("operation", "+"), Symbol("possible_label", "f"),
+], code
+assert repr(code[5]) == 'Symbol("operation", "+")'
+
+machine = simple_vm.Machine(code[1:6])
+machine.run([False, 5, "s"])
+assert machine.stack() == [False, 5, "s", "hi", True, 2]
+
+try:
+    simple_vm.Machine("").run([1.5])
+    assert False
+except TypeError:
+    pass
+try:
+    simple_vm.Machine("").run([2 ** 100])
+    assert False
+except OverflowError:
+    pass
+try:
+    Symbol("operation", "nope")
+    assert False
+except simple_vm.InvalidOperationError:
+    pass
+"#,
+        );
+    }
+
+    #[test]
+    fn test_effect() {
+        run_python(
+            r#"
+class Effect:
+    def __init__(self):
+        self.lines = []
+        self.slept = 0
+
+    def println(self, line):
+        self.lines.append(line)
+
+    def eprintln(self, line):
+        self.lines.append("error: " + line)
+
+    def print(self, text):
+        self.lines.append("print: " + text)
+
+    def read_line(self):
+        return "41\n"
+
+    def sleep_ms(self, duration):
+        self.slept += duration
+
+effect = Effect()
+machine = simple_vm.Machine("read cast_int 1 + println 5 sleep_ms 6 sleep_ms \"a\" print flush \"b\" eprintln", effect)
+machine.run()
+assert effect.lines == ["42", "print: a", "error: b"]
+assert effect.slept == 11
+
+class Failing:
+    def println(self, line):
+        raise ValueError(line)
+
+try:
+    simple_vm.Machine("1 println loop: loop jmp", Failing()).run()
+    assert False
+except ValueError as error:
+    assert str(error) == "1"
+
+class FailingRead(Effect):
+    def read_line(self):
+        raise KeyError("no input")
+
+effect = FailingRead()
+try:
+    simple_vm.Machine("read println \"after\" println", effect).run()
+    assert False
+except KeyError:
+    pass
+assert effect.lines == []
+"#,
+        );
+    }
+
+    #[test]
+    fn test_exceptions() {
+        run_python(
+            r#"
+try:
+    simple_vm.Machine("nowhere jmp")
+    assert False
+except simple_vm.UndefinedLabelError as error:
+    assert "nowhere" in str(error)
+
+try:
+    simple_vm.Machine("drop").run()
+    assert False
+except simple_vm.StackError as error:
+    assert isinstance(error, simple_vm.EmptyStackError)
+
+try:
+    simple_vm.Machine('"/nonexistent/file" read_file').run()
+    assert False
+except simple_vm.IoError as error:
+    assert "/nonexistent/file" in str(error)
+"#,
+        );
+    }
+}
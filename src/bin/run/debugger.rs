@@ -0,0 +1,354 @@
+//! An interactive debugger for stepping through programs.
+
+use simple_vm::*;
+use simple_vm::error::StackError;
+use std::io::{self, BufRead, Write};
+
+/// How many steps the debugger can undo with `back`.
+const HISTORY_LIMIT: usize = 100_000;
+
+const HELP: &str = "\
+Commands (an empty line repeats the last one):
+  s, step              execute one instruction
+  n, next              like step, but runs over a `call` until it returns
+  f, finish            run until the current `call` returns
+  c, continue          run until a breakpoint or the end of the program
+  back [<n>]           undo the last <n> steps (1 by default)
+  b, break <loc>       set a breakpoint on a label name or a line number
+  d, delete [<loc>]    delete a breakpoint, or all of them
+  breakpoints          list the breakpoints
+  watch depth <n>      pause when the stack grows deeper than <n>
+  watch push <value>   pause when <value> is pushed onto the stack
+  unwatch <id>         delete a watchpoint
+  watchpoints          list the watchpoints
+  l, list              show the next instruction
+  st, stack            show the data stack
+  rs, rstack           show the return stack
+  set <index> <value>  replace the stack value at <index>
+  push <value>         push a value onto the stack
+  pop                  pop the top value off of the stack
+                       (set, push and pop can't be undone with `back`)
+  q, quit              stop debugging
+  h, help              show this message";
+
+/// The debugger wraps the machine it is running along with the
+/// source line of each instruction, for breakpoints and display.
+pub struct Debugger<'a, E: SideEffect + 'a, O: Observer + 'a> {
+    machine: &'a mut Machine<E, O>,
+    lines: Vec<usize>,
+}
+
+impl<'a, E: SideEffect, O: Observer> Debugger<'a, E, O> {
+    pub fn new(machine: &'a mut Machine<E, O>, lines: Vec<usize>) -> Debugger<'a, E, O> {
+        machine.set_history_limit(Some(HISTORY_LIMIT));
+        Debugger { machine, lines }
+    }
+
+    /// Pushes `args` and reads commands from stdin until the program
+    /// stops or the user quits, returning the exit code.
+    pub fn run(&mut self, args: Vec<StackValue>) -> Result<i32, String> {
+        self.machine.stack_push(args).map_err(|e| e.to_string())?;
+        println!("Type `help` for a list of commands.");
+        self.print_location();
+
+        let mut last_command = String::new();
+        loop {
+            print!("(debug) ");
+            io::stdout().flush().map_err(|e| e.to_string())?;
+
+            let mut line = String::new();
+            let stdin = io::stdin();
+            if stdin.lock().read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+                return Ok(0);
+            }
+
+            let command = match line.trim() {
+                "" => last_command.clone(),
+                command => command.to_owned(),
+            };
+            match self.execute(&command) {
+                Ok(Some(exit_code)) => return Ok(exit_code),
+                Ok(None) => {}
+                Err(e) => println!("{}", e),
+            }
+            last_command = command;
+        }
+    }
+
+    /// Executes a single command, returning the exit code if debugging is over.
+    fn execute(&mut self, command: &str) -> Result<Option<i32>, String> {
+        let (name, rest) = split_word(command);
+        match name {
+            "s" | "step" => {
+                let status = self.machine.step_watched();
+                self.report(status)
+            }
+            "n" | "next" => {
+                let depth = self.machine.return_stack().len();
+                self.run_to(|m| m.return_stack().len() <= depth)
+            }
+            "f" | "finish" => {
+                let depth = self.machine.return_stack().len();
+                if depth == 0 {
+                    return Err("Not inside of a `call`".to_owned());
+                }
+                self.run_to(|m| m.return_stack().len() < depth)
+            }
+            "c" | "continue" => self.run_to(|_| false),
+            "back" => {
+                let count = match rest {
+                    "" => 1,
+                    count => count.parse::<usize>()
+                        .map_err(|_| format!("Invalid number of steps \"{}\"", count))?,
+                };
+                for _ in 0..count {
+                    if !self.machine.step_back() {
+                        println!("Reached the start of the history");
+                        break;
+                    }
+                }
+                self.print_location();
+                Ok(None)
+            }
+            "b" | "break" => {
+                let address = self.resolve_location(rest)?;
+                self.machine.set_breakpoint(address);
+                println!("Breakpoint at {}", self.describe(address));
+                Ok(None)
+            }
+            "d" | "delete" => {
+                if rest.is_empty() {
+                    self.machine.clear_breakpoints();
+                } else {
+                    let address = self.resolve_location(rest)?;
+                    if !self.machine.clear_breakpoint(address) {
+                        return Err(format!("No breakpoint at {}", self.describe(address)));
+                    }
+                }
+                Ok(None)
+            }
+            "breakpoints" => {
+                for address in self.machine.breakpoints() {
+                    println!("  {}", self.describe(address));
+                }
+                Ok(None)
+            }
+            "watch" => {
+                let watchpoint = match split_word(rest) {
+                    ("depth", depth) => Watchpoint::StackDepthExceeds(depth.parse::<usize>()
+                        .map_err(|_| format!("Invalid stack depth \"{}\"", depth))?),
+                    ("push", value) => Watchpoint::ValuePushed(self.parse_value(value)?),
+                    _ => return Err("Expected `watch depth <n>` or `watch push <value>`".to_owned()),
+                };
+                let id = self.machine.add_watchpoint(watchpoint);
+                println!("Watchpoint #{}", id);
+                Ok(None)
+            }
+            "unwatch" => {
+                let id = rest.parse::<usize>()
+                    .map_err(|_| format!("Invalid watchpoint id \"{}\"", rest))?;
+                match self.machine.remove_watchpoint(id) {
+                    Some(_) => Ok(None),
+                    None => Err(format!("No watchpoint #{}", id)),
+                }
+            }
+            "watchpoints" => {
+                for &(id, ref watchpoint) in self.machine.watchpoints() {
+                    println!("  #{}: {}", id, describe_watchpoint(watchpoint));
+                }
+                Ok(None)
+            }
+            "l" | "list" => {
+                self.print_location();
+                Ok(None)
+            }
+            "st" | "stack" => {
+                self.print_stack();
+                Ok(None)
+            }
+            "rs" | "rstack" => {
+                self.print_return_stack();
+                Ok(None)
+            }
+            "set" => {
+                let (index, value) = split_word(rest);
+                let index = index.parse::<usize>()
+                    .map_err(|_| format!("Invalid stack index \"{}\"", index))?;
+                if index >= self.machine.stack().len() {
+                    return Err(format!("No value at stack index {}", index));
+                }
+                let value = self.parse_value(value)?;
+                self.machine.stack_set(index, value).map_err(|e| e.to_string())?;
+                self.print_stack();
+                Ok(None)
+            }
+            "push" => {
+                let value = self.parse_value(rest)?;
+                self.machine.stack_push(vec![value]).map_err(|e| e.to_string())?;
+                self.print_stack();
+                Ok(None)
+            }
+            "pop" => {
+                match self.machine.stack_pop() {
+                    Some(value) => println!("{}", show(&value)),
+                    None => return Err("The stack is empty".to_owned()),
+                }
+                Ok(None)
+            }
+            "q" | "quit" => Ok(Some(0)),
+            "h" | "help" => {
+                println!("{}", HELP);
+                Ok(None)
+            }
+            "" => Ok(None),
+            _ => Err(format!("Unknown command \"{}\", type `help` for a list of commands", name)),
+        }
+    }
+
+    /// Steps once, so that we can move off of a breakpoint, and then keeps
+    /// running until `done` holds or we reach a breakpoint.
+    fn run_to<F>(&mut self, done: F) -> Result<Option<i32>, String>
+    where
+        F: Fn(&Machine<E, O>) -> bool,
+    {
+        match self.machine.step_watched() {
+            Ok(RunStatus::Paused(PauseReason::StepBudget)) => {}
+            status => return self.report(status),
+        }
+        let status = self.machine.run_until(done);
+        self.report(status)
+    }
+
+    fn report(&self, status: Result<RunStatus, StackError>) -> Result<Option<i32>, String> {
+        match status {
+            Ok(RunStatus::Stopped(exit_code)) => {
+                self.print_stack();
+                println!("Program stopped with exit code {}", exit_code);
+                Ok(Some(exit_code))
+            }
+            Ok(RunStatus::Paused(reason)) => {
+                match reason {
+                    PauseReason::Breakpoint(_) => println!("Breakpoint hit"),
+                    PauseReason::Watchpoint(id) => {
+                        if let Some((_, watchpoint)) = self.machine.watchpoints().iter().find(|w| w.0 == id) {
+                            println!("Watchpoint #{} hit: {}", id, describe_watchpoint(watchpoint));
+                        }
+                    }
+                    _ => {}
+                }
+                self.print_location();
+                Ok(None)
+            }
+            Err(e) => {
+                self.print_location();
+                Err(format!("Error: {}", e))
+            }
+        }
+    }
+
+    /// A location is either a label name or a line number, the latter
+    /// resolving to the first instruction on or after that line.
+    fn resolve_location(&self, location: &str) -> Result<usize, String> {
+        if location.is_empty() {
+            return Err("Expected a label or line number".to_owned());
+        }
+        match location.parse::<usize>() {
+            Ok(line) => self.lines.iter().enumerate()
+                .position(|(idx, &l)| l >= line && !is_label(&self.machine.code[idx]))
+                .ok_or_else(|| format!("No instructions on or after line {}", line)),
+            Err(_) => self.machine.label_address(location)
+                .ok_or_else(|| format!("Unknown label \"{}\"", location)),
+        }
+    }
+
+    /// Parses a single value, resolving label names to their addresses.
+    fn parse_value(&self, input: &str) -> Result<StackValue, String> {
+        let mut values = tokenize(input).map_err(|e| e.to_string())?;
+        if values.len() != 1 {
+            return Err(format!("Expected a single value but found {}", values.len()));
+        }
+        match values.remove(0) {
+            StackValue::PossibleLabel(name) => self.machine.label_address(&name)
+                .map(|address| StackValue::Num(address as isize))
+                .ok_or_else(|| format!("Unknown label \"{}\"", name)),
+            value => Ok(value),
+        }
+    }
+
+    /// Describes an address as `address (label+offset, line n)`.
+    fn describe(&self, address: usize) -> String {
+        let mut description = address.to_string();
+        let label = self.machine.label_for(address);
+        let line = self.lines.get(address);
+        if label.is_some() || line.is_some() {
+            let mut parts = vec![];
+            match label {
+                Some((label, 0)) => parts.push(label.to_owned()),
+                Some((label, offset)) => parts.push(format!("{}+{}", label, offset)),
+                None => {}
+            }
+            if let Some(line) = line {
+                parts.push(format!("line {}", line));
+            }
+            description.push_str(&format!(" ({})", parts.join(", ")));
+        }
+        description
+    }
+
+    fn print_location(&self) {
+        let ip = self.machine.instruction_ptr();
+        match self.machine.code.get(ip) {
+            Some(value) => println!("{}: {}", self.describe(ip), show(value)),
+            None => println!("{}: <end of program>", ip),
+        }
+    }
+
+    fn print_stack(&self) {
+        let stack = self.machine.stack();
+        if stack.is_empty() {
+            println!("  <empty stack>");
+        }
+        for (idx, value) in stack.iter().enumerate() {
+            println!("  {}: {}", idx, show(value));
+        }
+    }
+
+    fn print_return_stack(&self) {
+        let return_stack = self.machine.return_stack();
+        if return_stack.is_empty() {
+            println!("  <empty return stack>");
+        }
+        for (depth, &address) in return_stack.iter().enumerate().rev() {
+            println!("  #{} returns to {}", depth, self.describe(address));
+        }
+    }
+}
+
+fn describe_watchpoint(watchpoint: &Watchpoint) -> String {
+    match *watchpoint {
+        Watchpoint::StackDepthExceeds(depth) => format!("stack deeper than {}", depth),
+        Watchpoint::ValuePushed(ref value) => format!("{} pushed", show(value)),
+    }
+}
+
+fn is_label(value: &StackValue) -> bool {
+    matches!(*value, StackValue::Label(_))
+}
+
+/// Shows a value the way it would be written in source.
+fn show(value: &StackValue) -> String {
+    match *value {
+        StackValue::Operation(ref op) => op.name().to_owned(),
+        StackValue::String(ref s) => format!("{:?}", s),
+        ref value => value.to_string(),
+    }
+}
+
+/// Splits off the first whitespace separated word of `input`.
+fn split_word(input: &str) -> (&str, &str) {
+    let input = input.trim();
+    match input.find(char::is_whitespace) {
+        Some(idx) => (&input[..idx], input[idx..].trim()),
+        None => (input, ""),
+    }
+}
@@ -0,0 +1,260 @@
+#[macro_use] extern crate clap;
+extern crate simple_vm;
+
+mod debugger;
+
+use simple_vm::*;
+use std::fs::File;
+use std::path::PathBuf;
+use std::io::{BufWriter, Read, Write};
+use std::time::{Duration, Instant};
+
+fn main() {
+
+    let matches = clap_app!(simple_vm_bin =>
+        (version: crate_version!())
+        (author: crate_authors!())
+        (about: "A simple stack based vm.")
+        (@arg file: +required "Input file of the program to run")
+        (@arg dump_ast: --ast "Print the Machine's code before running")
+        (@arg no_run: --no_run "Don't execute the program")
+        (@arg debug: --debug "Run the program in the interactive debugger")
+        (@arg trace: --trace +takes_value "Write a JSON lines trace of every step to this file")
+        (@arg profile: --profile "Print a profile of the program to stderr after it stops")
+        (@arg profile_folded: --profile_folded +takes_value "Write folded call stacks for flamegraph tools to this file")
+        (@arg coverage: --coverage +takes_value "Write an lcov coverage report to this file and print a summary to stderr")
+        (@arg max_steps: --max_steps +takes_value "Stop the program after this many steps")
+        (@arg max_stack: --max_stack +takes_value "Maximum depth of the data stack")
+        (@arg max_return_stack: --max_return_stack +takes_value "Maximum depth of the return stack")
+        (@arg max_string_bytes: --max_string_bytes +takes_value "Maximum bytes held by strings on the stack")
+        (@arg timeout_ms: --timeout_ms +takes_value "Stop the program if it runs longer than this many ms")
+        (@arg sandbox: --sandbox "Only allow the side effects granted by the --allow flags")
+        (@arg allow_stdout: --allow_stdout requires[sandbox] "Allow writing to stdout")
+        (@arg allow_stderr: --allow_stderr requires[sandbox] "Allow writing to stderr")
+        (@arg allow_stdin: --allow_stdin requires[sandbox] "Allow reading from stdin")
+        (@arg allow_sleep: --allow_sleep requires[sandbox] "Allow sleeping")
+        (@arg allow_clock: --allow_clock requires[sandbox] "Allow reading the time")
+        (@arg allow_env: --allow_env +takes_value +multiple number_of_values(1) requires[sandbox]
+            "Allow reading this environment variable")
+        (@arg allow_read: --allow_read +takes_value +multiple number_of_values(1) requires[sandbox]
+            "Allow reading files under this directory")
+        (@arg allow_write: --allow_write +takes_value +multiple number_of_values(1) requires[sandbox]
+            "Allow reading and writing files under this directory")
+        (@arg record: --record +takes_value conflicts_with[replay]
+            "Record the program's side effects to this file, to be replayed with --replay")
+        (@arg replay: --replay +takes_value
+            "Replay the side effects recorded by --record in this file, failing if the program diverges")
+        (@arg args: +multiple "args to pass to the program")
+    ).get_matches();
+
+    match run(&matches) {
+        Ok(exit_code) => std::process::exit(exit_code),
+        Err(e) => {
+            eprintln!("{:?}", e);
+            std::process::exit(1);
+        }
+    }
+
+}
+
+/// Like `try!` but will transform all `Err(e)` into `Err(String)`
+macro_rules! attempt {
+    ($msg:expr => $e:expr) => {
+        match $e {
+            Err(e) => return Err(format!("Error {}... {}", $msg, e)),
+            Ok(v) => v
+        }
+    }
+}
+
+/// Attempts to actually run the program
+fn run(matches: &clap::ArgMatches) -> Result<i32, String> {
+
+    let file_name = matches.value_of("file").unwrap();
+    let program = {
+        let mut file = attempt!("opening file" => File::open(&file_name));
+        let mut contents = String::new();
+        attempt!("reading file" => file.read_to_string(&mut contents));
+        attempt!("tokenizing file" => tokenize_with_lines(&contents))
+    };
+    let (program, lines) = program;
+
+    let args = {
+        let args: Vec<_> = matches.values_of("args")
+            .unwrap_or_default()
+            .collect();
+        attempt!("tokenizng args" => tokenize(&args.join(" ")))
+    };
+
+    let effect = DefaultSideEffect::default();
+    if let Some(record_file) = matches.value_of("record") {
+        let file = attempt!("creating recording file" => File::create(record_file));
+        let recorder = attempt!("writing recording" => Recorder::new(effect, BufWriter::new(file)));
+        sandboxed(matches, program, lines, args, recorder)
+    } else if let Some(replay_file) = matches.value_of("replay") {
+        let mut recording = String::new();
+        let mut file = attempt!("opening recording" => File::open(replay_file));
+        attempt!("reading recording" => file.read_to_string(&mut recording));
+        let replayer = attempt!("parsing recording" => Replayer::new(&recording));
+        sandboxed(matches, program, lines, args, replayer)
+    } else {
+        sandboxed(matches, program, lines, args, effect)
+    }
+}
+
+/// Wraps `effect` in a `Sandbox` if the flags ask for one, and runs the program.
+fn sandboxed<E: SideEffect + Finish>(
+    matches: &clap::ArgMatches,
+    program: Code,
+    lines: Vec<usize>,
+    args: Vec<StackValue>,
+    effect: E,
+) -> Result<i32, String> {
+    if matches.is_present("sandbox") {
+        let values = |name| matches.values_of(name).unwrap_or_default();
+        let capabilities = Capabilities {
+            stdout: matches.is_present("allow_stdout"),
+            stderr: matches.is_present("allow_stderr"),
+            stdin: matches.is_present("allow_stdin"),
+            sleep: matches.is_present("allow_sleep"),
+            clock: matches.is_present("allow_clock"),
+            env_vars: values("allow_env").map(str::to_owned).collect(),
+            read_roots: values("allow_read").map(PathBuf::from).collect(),
+            write_roots: values("allow_write").map(PathBuf::from).collect(),
+        };
+        execute(matches, program, lines, args, Sandbox::new(effect, capabilities))
+    } else {
+        execute(matches, program, lines, args, effect)
+    }
+}
+
+/// Checks on the side effect once the program has stopped.
+trait Finish {
+    fn finish(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl Finish for DefaultSideEffect {}
+
+impl<E: SideEffect + Finish, W: Write> Finish for Recorder<E, W> {
+    fn finish(&self) -> Result<(), String> {
+        self.inner().finish()
+    }
+}
+
+impl Finish for Replayer {
+    fn finish(&self) -> Result<(), String> {
+        match self.remaining().front() {
+            Some(next) => Err(format!(
+                "Error replaying... the program stopped with {} recorded interactions left, starting with: {}",
+                self.remaining().len(),
+                next
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<E: SideEffect + Finish> Finish for Sandbox<E> {
+    fn finish(&self) -> Result<(), String> {
+        self.inner().finish()
+    }
+}
+
+/// Runs the program with `effect`, along with everything the flags ask for.
+fn execute<E: SideEffect + Finish>(
+    matches: &clap::ArgMatches,
+    program: Code,
+    lines: Vec<usize>,
+    args: Vec<StackValue>,
+    effect: E,
+) -> Result<i32, String> {
+
+    let file_name = matches.value_of("file").unwrap();
+
+    let coverage = if matches.is_present("coverage") {
+        Some(Coverage::new(&program))
+    } else {
+        None
+    };
+    let profiler = if matches.is_present("profile") || matches.is_present("profile_folded") {
+        Some(Profiler::new(&program))
+    } else {
+        None
+    };
+    let observers = (coverage, profiler);
+    let mut machine = attempt!("creating machine" => Machine::with_effect_and_observer(program, effect, observers));
+
+    if let Some(max_steps) = matches.value_of("max_steps") {
+        let max_steps = attempt!("parsing max_steps" => max_steps.parse::<usize>());
+        machine.set_step_limit(Some(max_steps));
+    }
+
+    if let Some(timeout_ms) = matches.value_of("timeout_ms") {
+        let timeout_ms = attempt!("parsing timeout_ms" => timeout_ms.parse::<u64>());
+        machine.set_deadline(Some(Instant::now() + Duration::from_millis(timeout_ms)));
+    }
+
+    machine.set_limits(Limits {
+        stack_depth: attempt!("parsing max_stack" => parse_limit(matches, "max_stack")),
+        return_stack_depth: attempt!("parsing max_return_stack" => parse_limit(matches, "max_return_stack")),
+        string_bytes: attempt!("parsing max_string_bytes" => parse_limit(matches, "max_string_bytes")),
+    });
+
+    if let Some(trace_file) = matches.value_of("trace") {
+        let file = attempt!("creating trace file" => File::create(trace_file));
+        machine.set_tracer(Box::new(JsonLinesTracer::new(BufWriter::new(file))));
+    }
+
+    if matches.is_present("dump_ast") {
+        println!("{:?}", machine.code);
+    }
+
+    let mut exit_code = 0;
+    if matches.is_present("no_run") {
+        // nothing to do
+    } else if matches.is_present("debug") {
+        exit_code = debugger::Debugger::new(&mut machine, lines.clone()).run(args)?;
+        machine.effect().finish()?;
+    } else {
+        exit_code = match attempt!("running machine" => machine.run(args)) {
+            RunStatus::Stopped(exit_code) => exit_code,
+            RunStatus::Paused(reason) => return Err(format!("Machine paused: {:?}", reason)),
+        };
+        machine.effect().finish()?;
+    }
+
+    if let Some(mut tracer) = machine.take_tracer() {
+        attempt!("writing trace" => tracer.flush());
+    }
+
+    let (coverage, profiler) = machine.into_observer();
+
+    if let Some(coverage) = coverage {
+        let lcov_file = matches.value_of("coverage").unwrap();
+        let mut file = attempt!("creating coverage file" => File::create(lcov_file));
+        attempt!("writing coverage" => file.write_all(coverage.lcov(file_name, &lines).as_bytes()));
+        eprint!("{}", coverage.summary());
+    }
+
+    if let Some(profiler) = profiler {
+        if matches.is_present("profile") {
+            eprint!("{}", profiler.report());
+        }
+        if let Some(folded_file) = matches.value_of("profile_folded") {
+            let mut file = attempt!("creating folded stacks file" => File::create(folded_file));
+            attempt!("writing folded stacks" => file.write_all(profiler.folded().as_bytes()));
+        }
+    }
+
+    Ok(exit_code)
+}
+
+/// Parses an optional numeric limit from the command line.
+fn parse_limit(matches: &clap::ArgMatches, name: &str) -> Result<Option<usize>, std::num::ParseIntError> {
+    match matches.value_of(name) {
+        Some(value) => value.parse::<usize>().map(Some),
+        None => Ok(None),
+    }
+}
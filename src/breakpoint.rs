@@ -0,0 +1,180 @@
+//! Breakpoints and watchpoints, which pause a running `Machine`
+//! with `RunStatus::Paused` so that it can be inspected.
+
+use std::collections::BTreeSet;
+
+use super::*;
+
+/// A condition that pauses a running machine after the step that makes it true.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Watchpoint<I = StackOperation> {
+    /// The data stack grew deeper than this many values.
+    StackDepthExceeds(usize),
+    /// This value was pushed onto the data stack.
+    ValuePushed(StackValue<I>),
+}
+
+/// The breakpoint and watchpoint state of a `Machine`.
+#[derive(Debug)]
+pub(crate) struct Breakpoints<I> {
+    addresses: BTreeSet<usize>,
+    watchpoints: Vec<(usize, Watchpoint<I>)>,
+    next_id: usize,
+    /// Whether any watchpoint cares about pushed values, so that
+    /// pushing stays cheap when none do.
+    pub(crate) watch_pushes: bool,
+    /// The watchpoint triggered by a push during the current step.
+    triggered: Option<usize>,
+    /// The `steps` at which we last paused on a breakpoint, so that
+    /// resuming doesn't immediately pause on the same breakpoint again.
+    paused_at_step: Option<usize>,
+}
+
+impl<I> Default for Breakpoints<I> {
+    fn default() -> Self {
+        Breakpoints {
+            addresses: BTreeSet::new(),
+            watchpoints: vec![],
+            next_id: 0,
+            watch_pushes: false,
+            triggered: None,
+            paused_at_step: None,
+        }
+    }
+}
+
+impl<I: Instruction> Breakpoints<I> {
+    /// Called for every pushed value while `watch_pushes` is set.
+    pub(crate) fn value_pushed(&mut self, value: &StackValue<I>) {
+        if self.triggered.is_some() {
+            return;
+        }
+        self.triggered = self.watchpoints.iter()
+            .find(|&(_, watchpoint)| match *watchpoint {
+                Watchpoint::ValuePushed(ref v) => v == value,
+                _ => false,
+            })
+            .map(|&(id, _)| id);
+    }
+
+    /// Whether there are no breakpoints or watchpoints to check.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.addresses.is_empty() && self.watchpoints.is_empty()
+    }
+
+    /// Called when a run starts, so that values pushed before it,
+    /// like its arguments, don't trigger a watchpoint.
+    pub(crate) fn run_started(&mut self) {
+        self.triggered = None;
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.triggered = None;
+        self.paused_at_step = None;
+    }
+}
+
+impl<E: SideEffect, O: Observer, I: Instruction> Machine<E, O, I> {
+    /// Pauses the machine with `PauseReason::Breakpoint` before it
+    /// executes the instruction at `address`.
+    pub fn set_breakpoint(&mut self, address: usize) {
+        self.breakpoints.addresses.insert(address);
+    }
+
+    /// Sets a breakpoint on the first instruction after the label `name`,
+    /// returning its address, or `None` if there is no such label.
+    pub fn set_breakpoint_at_label(&mut self, name: &str) -> Option<usize> {
+        let address = self.label_address(name)?;
+        self.set_breakpoint(address);
+        Some(address)
+    }
+
+    /// Removes the breakpoint at `address`, returning whether there was one.
+    pub fn clear_breakpoint(&mut self, address: usize) -> bool {
+        self.breakpoints.addresses.remove(&address)
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.addresses.clear();
+    }
+
+    /// The addresses with breakpoints, in ascending order.
+    pub fn breakpoints(&self) -> Vec<usize> {
+        self.breakpoints.addresses.iter().cloned().collect()
+    }
+
+    /// Adds a watchpoint, returning the id it will be reported with
+    /// in `PauseReason::Watchpoint`.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint<I>) -> usize {
+        let id = self.breakpoints.next_id;
+        self.breakpoints.next_id += 1;
+        self.breakpoints.watchpoints.push((id, watchpoint));
+        self.update_watch_pushes();
+        id
+    }
+
+    /// Removes the watchpoint with `id`, returning it if it existed.
+    pub fn remove_watchpoint(&mut self, id: usize) -> Option<Watchpoint<I>> {
+        let idx = self.breakpoints.watchpoints.iter().position(|&(i, _)| i == id)?;
+        let (_, watchpoint) = self.breakpoints.watchpoints.remove(idx);
+        self.update_watch_pushes();
+        Some(watchpoint)
+    }
+
+    /// The watchpoints along with their ids.
+    pub fn watchpoints(&self) -> &[(usize, Watchpoint<I>)] {
+        &self.breakpoints.watchpoints
+    }
+
+    /// Takes a single step like `step`, but pauses on the watchpoints it
+    /// triggers the way running does, and otherwise with `PauseReason::StepBudget`.
+    pub fn step_watched(&mut self) -> Result<RunStatus, StackError> {
+        self.breakpoints.run_started();
+        let depth = self.stack.len();
+        match self.step()? {
+            StepResult::Continue => {}
+            StepResult::Stop(exit_code) => return Ok(RunStatus::Stopped(exit_code)),
+            StepResult::AwaitingInput => return Ok(RunStatus::Paused(PauseReason::AwaitingInput)),
+        }
+        let reason = self.check_watchpoints(depth).unwrap_or(PauseReason::StepBudget);
+        Ok(RunStatus::Paused(reason))
+    }
+
+    fn update_watch_pushes(&mut self) {
+        self.breakpoints.watch_pushes = self.breakpoints.watchpoints.iter()
+            .any(|(_, w)| matches!(*w, Watchpoint::ValuePushed(_)));
+        self.update_checked_stack();
+    }
+
+    /// Checks whether we should pause before executing the next instruction.
+    #[inline]
+    pub(crate) fn check_breakpoint(&mut self) -> Option<PauseReason> {
+        if self.breakpoints.addresses.is_empty()
+            || self.breakpoints.paused_at_step == Some(self.steps)
+            || !self.breakpoints.addresses.contains(&self.instruction_ptr)
+        {
+            return None;
+        }
+        self.breakpoints.paused_at_step = Some(self.steps);
+        Some(PauseReason::Breakpoint(self.instruction_ptr))
+    }
+
+    /// Checks whether the step that was just taken, starting with a
+    /// stack of `depth_before` values, triggered a watchpoint.
+    #[inline]
+    pub(crate) fn check_watchpoints(&mut self, depth_before: usize) -> Option<PauseReason> {
+        if self.breakpoints.watchpoints.is_empty() {
+            return None;
+        }
+        if let Some(id) = self.breakpoints.triggered.take() {
+            return Some(PauseReason::Watchpoint(id));
+        }
+        let depth = self.stack.len();
+        self.breakpoints.watchpoints.iter()
+            .find(|&(_, watchpoint)| match *watchpoint {
+                Watchpoint::StackDepthExceeds(max) => depth_before <= max && depth > max,
+                Watchpoint::ValuePushed(_) => false,
+            })
+            .map(|&(id, _)| PauseReason::Watchpoint(id))
+    }
+}
@@ -0,0 +1,216 @@
+//! Code coverage for programs running on a `Machine`.
+//!
+//! `Coverage` is an `Observer` counting how many times each instruction in
+//! a machine's `code` is executed. Label definitions are not instructions of
+//! their own and are left out of the results. Coverage keeps adding up over
+//! `Machine::reset`s, so that several runs of a program can be combined.
+//!
+//! `Coverage::lcov` maps the counts back to source lines, using the lines
+//! returned by `tokenize_with_lines`, for tools like `genhtml`, while
+//! `Coverage::summary` shows how much of each label was covered.
+
+use std::collections::BTreeMap;
+
+use super::*;
+
+/// The name used for code before the first label.
+const TOP_LEVEL: &str = "main";
+
+/// A label, or the top level, and the addresses of the instructions under it.
+#[derive(Debug)]
+struct Section {
+    name: String,
+    /// The address of the label definition, `None` for the top level.
+    definition: Option<usize>,
+    instructions: Vec<usize>,
+}
+
+/// How many times each instruction was executed, see the `coverage` module.
+#[derive(Debug)]
+pub struct Coverage {
+    hits: Vec<u64>,
+    sections: Vec<Section>,
+}
+
+impl Coverage {
+    /// Creates an empty coverage for `code`, which is what the machine will be
+    /// created with. Either tokenized or preprocessed code works.
+    pub fn new<I>(code: &Code<I>) -> Coverage {
+        let mut sections = vec![Section {
+            name: TOP_LEVEL.to_owned(),
+            definition: None,
+            instructions: vec![],
+        }];
+        for (address, value) in code.iter().enumerate() {
+            match *value {
+                StackValue::Label(ref name) => sections.push(Section {
+                    name: name.clone(),
+                    definition: Some(address),
+                    instructions: vec![],
+                }),
+                _ => sections.last_mut().unwrap().instructions.push(address),
+            }
+        }
+        if sections[0].instructions.is_empty() {
+            sections.remove(0);
+        }
+        Coverage { hits: vec![0; code.len()], sections }
+    }
+
+    /// How many times the value at each address in `code` was executed.
+    pub fn hits(&self) -> &[u64] {
+        &self.hits
+    }
+
+    /// The addresses of instructions that were never executed.
+    pub fn uncovered(&self) -> Vec<usize> {
+        self.sections.iter()
+            .flat_map(|section| section.instructions.iter().cloned())
+            .filter(|&address| self.hits[address] == 0)
+            .collect()
+    }
+
+    /// An lcov tracefile for `source_file`, where `lines` holds the source
+    /// line of every value in `code`, as returned by `tokenize_with_lines`.
+    ///
+    /// Labels are reported as functions, which were called as many times as
+    /// their first instruction was executed. A line with several instructions
+    /// counts the executions of the least executed one, so that partially
+    /// covered lines show up as uncovered.
+    pub fn lcov(&self, source_file: &str, lines: &[usize]) -> String {
+        let mut lcov = format!("TN:\nSF:{}\n", source_file);
+
+        let labels: Vec<&Section> = self.sections.iter()
+            .filter(|section| section.definition.is_some())
+            .collect();
+        for section in &labels {
+            lcov.push_str(&format!("FN:{},{}\n", lines[section.definition.unwrap()], section.name));
+        }
+        let mut functions_hit = 0;
+        for section in &labels {
+            let calls = section.instructions.first().map_or(0, |&address| self.hits[address]);
+            if calls > 0 {
+                functions_hit += 1;
+            }
+            lcov.push_str(&format!("FNDA:{},{}\n", calls, section.name));
+        }
+        lcov.push_str(&format!("FNF:{}\nFNH:{}\n", labels.len(), functions_hit));
+
+        let mut line_hits: BTreeMap<usize, u64> = BTreeMap::new();
+        for section in &self.sections {
+            for &address in &section.instructions {
+                let hits = line_hits.entry(lines[address]).or_insert(u64::MAX);
+                *hits = (*hits).min(self.hits[address]);
+            }
+        }
+        for (line, hits) in &line_hits {
+            lcov.push_str(&format!("DA:{},{}\n", line, hits));
+        }
+        let lines_hit = line_hits.values().filter(|&&hits| hits > 0).count();
+        lcov.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", line_hits.len(), lines_hit));
+
+        lcov
+    }
+
+    /// A table of how many instructions under each label were covered.
+    pub fn summary(&self) -> String {
+        let mut summary = format!("{:>10} {:>10} {:>8}  label\n", "covered", "total", "percent");
+        let mut covered = 0;
+        let mut total = 0;
+        for section in &self.sections {
+            let section_covered = section.instructions.iter()
+                .filter(|&&address| self.hits[address] > 0)
+                .count();
+            let section_total = section.instructions.len();
+            summary.push_str(&format_summary_line(section_covered, section_total, &section.name));
+            covered += section_covered;
+            total += section_total;
+        }
+        summary.push_str(&format_summary_line(covered, total, "total"));
+        summary
+    }
+}
+
+fn format_summary_line(covered: usize, total: usize, name: &str) -> String {
+    let percent = if total == 0 { 100.0 } else { covered as f64 * 100.0 / total as f64 };
+    format!("{:>10} {:>10} {:>7.1}%  {}\n", covered, total, percent, name)
+}
+
+impl Observer for Coverage {
+    #[inline]
+    fn before_step<I: Instruction>(&mut self, address: usize, _instruction: &StackValue<I>, _stack: &[StackValue<I>]) {
+        // Instructions outside of the covered code are ignored.
+        if let Some(hits) = self.hits.get_mut(address) {
+            *hits += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const PROGRAM: &str = "\
+check call stop
+check: 0 == zero other if call return
+zero: 0 return
+other:
+  1 return";
+
+    fn covered(args: &[isize]) -> (Coverage, Vec<usize>) {
+        let (code, lines) = tokenize_with_lines(PROGRAM).unwrap();
+        let coverage = Coverage::new(&code);
+        let mut machine = Machine::<DefaultSideEffect, _>::with_observer(code, coverage).unwrap();
+        for &arg in args {
+            machine.reset();
+            machine.run(vec![StackValue::Num(arg)]).unwrap();
+        }
+        (machine.into_observer(), lines)
+    }
+
+    #[test]
+    fn test_hits() {
+        let (coverage, _) = covered(&[1]);
+        assert_eq!(&[1, 1, 1, 0, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 1, 1], coverage.hits());
+        assert_eq!(vec![12, 13], coverage.uncovered());
+    }
+
+    #[test]
+    fn test_runs_add_up() {
+        let (coverage, _) = covered(&[1, 0]);
+        assert!(coverage.uncovered().is_empty());
+        assert_eq!(2, coverage.hits()[0]);
+    }
+
+    #[test]
+    fn test_ignores_addresses_outside_the_code() {
+        let coverage = Coverage::new(&tokenize("1").unwrap());
+        let mut machine = Machine::<DefaultSideEffect, _>::with_observer(tokenize("1 2").unwrap(), coverage).unwrap();
+        machine.run(vec![]).unwrap();
+        assert_eq!(&[1], machine.into_observer().hits());
+    }
+
+    #[test]
+    fn test_lcov() {
+        let (coverage, lines) = covered(&[1]);
+        assert_eq!(
+            "TN:\nSF:prog\n\
+             FN:2,check\nFN:3,zero\nFN:4,other\n\
+             FNDA:1,check\nFNDA:0,zero\nFNDA:1,other\nFNF:3\nFNH:2\n\
+             DA:1,1\nDA:2,1\nDA:3,0\nDA:5,1\nLF:4\nLH:3\nend_of_record\n",
+            coverage.lcov("prog", &lines)
+        );
+    }
+
+    #[test]
+    fn test_summary() {
+        let (coverage, _) = covered(&[1]);
+        let summary = coverage.summary();
+        let lines: Vec<&str> = summary.lines().collect();
+        assert_eq!(6, lines.len());
+        assert_eq!("         3          3   100.0%  main", lines[1]);
+        assert_eq!("         0          2     0.0%  zero", lines[3]);
+        assert_eq!("        12         14    85.7%  total", lines[5]);
+    }
+}
@@ -1,10 +1,21 @@
 /// The possible error conditions.
 #[derive(Debug, Fail)]
 pub enum StackError {
+    /// Error condition for when the machine has taken as many
+    /// steps as its step limit allows.
+    #[fail(display = "Step budget exhausted after {} steps", steps)]
+    BudgetExhausted { steps: usize },
+    /// Error condition for when the machine was stopped
+    /// through its `CancelHandle`.
+    #[fail(display = "Execution was cancelled")]
+    Cancelled,
     /// Error condition for when we try to pop a value off
     /// the stack and it's empty for the given expression.
     #[fail(display = "Cannot pop an empty stack, looking for {} in {}", arg_pattern, expr)]
     EmptyStack { arg_pattern: String, expr: String },
+    /// Error condition for when a native function could not be registered.
+    #[fail(display = "Invalid native \"{}\": {}", name, reason)]
+    InvalidNative { name: String, reason: String },
     /// Error condition for when a given string does not correspond to
     /// any defined operation.
     #[fail(display = "Invalid operation: {}", name)]
@@ -12,6 +23,16 @@ pub enum StackError {
     /// Error condition when we could not parse the string.
     #[fail(display = "Could not parse \"{}\"", string)]
     InvalidString { string: String },
+    /// Error condition when a recording of side effects could not be parsed.
+    #[fail(display = "Invalid recording: {}", reason)]
+    InvalidRecording { reason: String },
+    /// Error condition when a snapshot could not be restored.
+    #[fail(display = "Invalid snapshot: {}", reason)]
+    InvalidSnapshot { reason: String },
+    /// Error condition for when an I/O operation of the machine's
+    /// `SideEffect` failed.
+    #[fail(display = "{} failed: {}", operation, message)]
+    Io { operation: String, message: String },
     /// Error condition when a label is defined in multiple locations
     /// in the source.
     #[fail(display = "Label {} defined in locations: {:?}", label, locations)]
@@ -19,6 +40,9 @@ pub enum StackError {
         label: String,
         locations: Vec<usize>,
     },
+    /// Error condition for when a native function returned an error.
+    #[fail(display = "Native {} failed: {}", name, message)]
+    NativeFailed { name: String, message: String },
     /// Error condition when the instruction pointer is out of bounds
     /// for the code provided to the machine.
     #[fail(display = "Out of bounds instruction pointer")]
@@ -28,6 +52,30 @@ pub enum StackError {
     /// argument pattern provided for the expression.
     #[fail(display = "Pattern mismatch, looking for {} in {}", arg_pattern, expr)]
     PatternMismatch { arg_pattern: String, expr: String },
+    /// Error condition for when the machine's `Sandbox` does not
+    /// allow an operation.
+    #[fail(display = "{} is not permitted: {}", operation, reason)]
+    PermissionDenied { operation: String, reason: String },
+    /// Error condition for when a `call` would nest deeper than
+    /// the machine's return stack limit.
+    #[fail(display = "Return stack overflow, depth limit is {}", limit)]
+    ReturnStackOverflow { limit: usize },
+    /// Error condition for when there is no value at an index
+    /// into the data stack.
+    #[fail(display = "Stack index {} is out of bounds for a stack of {} values", index, len)]
+    StackIndexOutOfBounds { index: usize, len: usize },
+    /// Error condition for when pushing a value would grow the
+    /// data stack past the machine's depth limit.
+    #[fail(display = "Stack overflow, depth limit is {}", limit)]
+    StackOverflow { limit: usize },
+    /// Error condition for when the strings on the stack would
+    /// take up more bytes than the machine's limit.
+    #[fail(display = "String memory limit of {} bytes exceeded", limit)]
+    StringMemoryExceeded { limit: usize },
+    /// Error condition for when the machine was still running
+    /// when its deadline passed.
+    #[fail(display = "Execution timed out")]
+    TimedOut,
 
     #[fail(display = "Program referes to undefined \"{}\" {} time(s)", label, times)]
     UndefinedLabel { label: String, times: usize },
@@ -0,0 +1,122 @@
+//! An undo history that lets a `Machine` step backwards.
+//!
+//! While enabled with `Machine::set_history_limit`, every step records
+//! what it popped, how much it pushed, how it changed the return stack
+//! and where it jumped from, which is enough to put the machine back
+//! the way it was. Side effects like `println` and `sleep_ms` cannot be
+//! undone, and are not repeated when stepping forward again. Lines that
+//! were read are put back, and read again in the same order.
+//!
+//! Changes made to the stack between steps, with `stack_push`,
+//! `stack_pop` or `stack_set`, discard the history.
+
+use std::collections::VecDeque;
+
+use super::*;
+use journal::ReturnChange;
+
+/// Enough information to undo a single step.
+#[derive(Debug)]
+pub(crate) struct UndoRecord<I> {
+    pub(crate) instruction_ptr: usize,
+    pub(crate) steps: usize,
+    /// The values popped, in the order they were popped.
+    pub(crate) popped: Vec<StackValue<I>>,
+    /// The number of values pushed.
+    pub(crate) pushed: usize,
+    pub(crate) return_change: ReturnChange,
+    pub(crate) input: Option<String>,
+}
+
+/// The most recent `limit` steps' undo records.
+#[derive(Debug)]
+pub(crate) struct History<I> {
+    limit: usize,
+    records: VecDeque<UndoRecord<I>>,
+}
+
+impl<I> History<I> {
+    pub(crate) fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    pub(crate) fn record(&mut self, record: UndoRecord<I>) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.records.len() == self.limit {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+}
+
+impl<E: SideEffect, O: Observer, I: Instruction> Machine<E, O, I> {
+    /// Keeps undo information for up to `limit` of the most recent steps,
+    /// so that they can be undone with `step_back`.
+    ///
+    /// `None` turns the history off and discards it.
+    pub fn set_history_limit(&mut self, limit: Option<usize>) {
+        self.history = limit.map(|limit| {
+            let mut records = self.history.take().map(|h| h.records).unwrap_or_default();
+            while records.len() > limit {
+                records.pop_front();
+            }
+            History { limit, records }
+        });
+        self.update_journal();
+    }
+
+    /// Discards the undo history, for changes that can't be undone.
+    pub(crate) fn clear_history(&mut self) {
+        if let Some(ref mut history) = self.history {
+            history.clear();
+        }
+    }
+
+    /// The number of steps that can currently be undone.
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, |h| h.records.len())
+    }
+
+    /// Undoes the most recent step, returning `false` if there is
+    /// no history left to undo.
+    pub fn step_back(&mut self) -> bool {
+        let record = match self.history.as_mut().and_then(|h| h.records.pop_back()) {
+            Some(record) => record,
+            None => return false,
+        };
+
+        for _ in 0..record.pushed {
+            self.stack.pop();
+        }
+        for value in record.popped.into_iter().rev() {
+            self.stack.push(value);
+        }
+        self.count_string_bytes();
+        match record.return_change {
+            ReturnChange::Unchanged => {}
+            ReturnChange::Pushed => {
+                self.return_stack.pop();
+            }
+            ReturnChange::Popped(address) => self.return_stack.push(address),
+        }
+        if let Some(line) = record.input {
+            self.input.push_front(line);
+        }
+        self.instruction_ptr = record.instruction_ptr;
+        self.steps = record.steps;
+        true
+    }
+
+    /// Steps back until the machine has taken `steps` steps, returning
+    /// `false` if the history doesn't go back that far.
+    pub fn rewind_to(&mut self, steps: usize) -> bool {
+        while self.steps > steps {
+            if !self.step_back() {
+                return false;
+            }
+        }
+        true
+    }
+}
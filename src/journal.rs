@@ -0,0 +1,119 @@
+//! Recording what a single step did to the machine, which is what
+//! both tracing and the undo history are built from.
+
+use std::mem;
+
+use super::*;
+use history::UndoRecord;
+
+/// How a step changed the return stack.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum ReturnChange {
+    #[default]
+    Unchanged,
+    /// A `call` pushed an address.
+    Pushed,
+    /// A `return` popped this address.
+    Popped(usize),
+}
+
+/// Everything recorded during the current step.
+#[derive(Debug)]
+pub(crate) struct Journal<I> {
+    pub(crate) operation: Option<MachineOperation<I>>,
+    pub(crate) popped: Vec<StackValue<I>>,
+    pub(crate) pushed: Vec<StackValue<I>>,
+    pub(crate) return_change: ReturnChange,
+    /// The line this step's `read` pushed, whether it was given with
+    /// `provide_input` or read from the `SideEffect`.
+    pub(crate) input: Option<String>,
+}
+
+impl<I> Default for Journal<I> {
+    fn default() -> Self {
+        Journal {
+            operation: None,
+            popped: vec![],
+            pushed: vec![],
+            return_change: ReturnChange::default(),
+            input: None,
+        }
+    }
+}
+
+impl<E: SideEffect, O: Observer, I: Instruction> Machine<E, O, I> {
+    /// The journal is only kept while something needs it, so that
+    /// stepping stays cheap otherwise.
+    pub(crate) fn update_journal(&mut self) {
+        let needed = self.trace.is_some() || self.history.is_some();
+        if needed && self.journal.is_none() {
+            self.journal = Some(Journal::default());
+        } else if !needed {
+            self.journal = None;
+        }
+        self.update_checked_stack();
+    }
+
+    /// Records the operation a step dispatched to and carries it out,
+    /// out of line so that dispatching stays cheap without a journal.
+    #[inline(never)]
+    pub(crate) fn journaled_dispatch(&mut self, operation: MachineOperation<I>) -> Result<StepResult, StackError> {
+        if let Some(ref mut journal) = self.journal {
+            journal.operation = Some(operation.clone());
+        }
+        self.perform(operation, true)
+    }
+
+    /// Takes a step while journaling it, and hands the journal
+    /// to the tracer and history.
+    #[inline(never)]
+    pub(crate) fn journaled_step(&mut self) -> Result<StepResult, StackError> {
+        // Pushes and pops that happen between steps, like `stack_push`,
+        // are not part of this step.
+        if let Some(ref mut journal) = self.journal {
+            *journal = Journal::default();
+        }
+
+        let address = self.instruction_ptr;
+        let steps = self.steps;
+        let result = self.execute_step();
+
+        // Nothing was executed if we ran out of budget, hit the end of
+        // the code, or were rewound to wait for input.
+        if self.steps == steps {
+            return result;
+        }
+
+        let journal = match self.journal {
+            Some(ref mut journal) => mem::take(journal),
+            None => return result,
+        };
+
+        if let Some(ref mut trace) = self.trace {
+            trace.tracer.trace(&TraceEvent {
+                step: self.steps,
+                address,
+                instruction: self.code[address].clone(),
+                operation: journal.operation,
+                popped: journal.popped.clone(),
+                pushed: journal.pushed.clone(),
+                stack_depth: self.stack.len(),
+                return_depth: self.return_stack.len(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+            });
+        }
+
+        if let Some(ref mut history) = self.history {
+            history.record(UndoRecord {
+                instruction_ptr: address,
+                steps,
+                popped: journal.popped,
+                pushed: journal.pushed.len(),
+                return_change: journal.return_change,
+                input: journal.input,
+            });
+        }
+
+        result
+    }
+}
@@ -1,24 +1,54 @@
 extern crate failure;
 #[macro_use] extern crate failure_derive;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::mem;
 use std::str::FromStr;
+use std::time::Instant;
 
+pub mod breakpoint;
+pub mod cancel;
+pub mod coverage;
 pub mod error;
+pub mod history;
+mod journal;
+pub mod native;
+pub mod observer;
+pub mod profile;
+pub mod record;
+pub mod sandbox;
 pub mod side_effect;
-
+pub mod snapshot;
+pub mod testing;
+pub mod trace;
+
+pub use breakpoint::Watchpoint;
+use breakpoint::Breakpoints;
+pub use cancel::CancelHandle;
+pub use coverage::Coverage;
 use error::StackError;
+pub use native::{NativeFn, Natives, ValueType};
+pub use observer::{NoObserver, Observer};
+pub use profile::{LabelStats, Profiler};
+pub use record::{Recorder, Replayer};
+pub use sandbox::{Capabilities, Sandbox};
 pub use side_effect::*;
+pub use trace::{JsonLinesTracer, TraceEvent, Tracer};
+use history::History;
+use journal::{Journal, ReturnChange};
+use trace::TraceState;
 
 #[macro_use]
 pub mod stack_operations;
 
+pub use stack_operations::Instruction;
+
 /// Primitive machine operations.
 ///
 /// These are the operations that `StackOperation` is built on
 /// and what they return to the machine.
-#[derive(Debug)]
-pub enum MachineOperation {
+#[derive(Clone, Debug, PartialEq)]
+pub enum MachineOperation<I = StackOperation> {
     /// Adds the current `instruction_ptr` to the return stack and
     /// jumps to `usize`.
     Call(usize),
@@ -27,67 +57,110 @@ pub enum MachineOperation {
     /// Does nothing
     NA,
     /// Appends one value to the stack.
-    Push(StackValue),
+    Push(StackValue<I>),
     /// Appends two values to the stack.
-    PushTwo(StackValue, StackValue),
+    PushTwo(StackValue<I>, StackValue<I>),
     /// Appends three values to the stack.
-    PushThree(StackValue, StackValue, StackValue),
+    PushThree(StackValue<I>, StackValue<I>, StackValue<I>),
+    /// Appends any number of values to the stack, like the results
+    /// of a native function.
+    PushAll(Vec<StackValue<I>>),
     /// Returns to the last thing added to the return stack.
     Return,
     /// Writes a value to stdout
-    Println(StackValue),
+    Println(StackValue<I>),
+    /// Writes a value to stderr
+    Eprintln(StackValue<I>),
+    /// Writes a value to stdout without a newline
+    Print(StackValue<I>),
+    /// Flushes stdout
+    Flush,
     /// Reads from stdin
     ReadLn,
     /// Sleeps :shrug:
     Sleep(u64),
+    /// Makes `ReadLn` read from the file at this path.
+    Open(String),
+    /// Pushes the contents of the file at this path.
+    ReadFile(String),
+    /// Writes the contents (the second string) to the file at the path (the first).
+    WriteFile(String, String),
+    /// Appends the contents (the second string) to the file at the path (the first).
+    AppendFile(String, String),
+    /// Pushes whether there is a file or directory at this path.
+    Exists(String),
+    /// Pushes the names in the directory at this path, sorted, followed by how many there are.
+    ListDir(String),
+    /// Pushes the value of this environment variable, or an empty string if it isn't set.
+    EnvVar(String),
+    /// Pushes the number of ms since the Unix epoch.
+    Now,
     /// Stops execution of the Machine with an exit code.
     Stop(i32),
 }
 
 ops! {
-    Plus + (Num(a), Num(b)) Push(Num(a + b)),
-    Minus - (Num(a), Num(b)) Push(Num(b - a)),
-    Multiply * (Num(a), Num(b)) Push(Num(a * b)),
-    Divide / (Num(a), Num(b)) Push(Num(b / a)),
-    ToInt cast_int (String(a)) Push(Num(a.parse::<isize>().unwrap_or(0))),
-    ToStr cast_str (a) Push(String(format!("{}", a))),
-    Println println (a) Println(a),
-    Equals == (a, b) Push(Bool(a == b)),
-    Or or (Bool(a), Bool(b)) Push(Bool(a || b)),
-    And and (Bool(a), Bool(b)) Push(Bool(a && b)),
-    Not not (Bool(a)) Push(Bool(!a)),
-    LessThan < (Num(a), Num(b)) Push(Bool(b < a)),
-    LessThanOrEqualTo <= (Num(a), Num(b)) Push(Bool(b <= a)),
-    GreaterHan > (Num(a), Num(b)) Push(Bool(b > a)),
-    GreaterHanOrEqualto >= (Num(a), Num(b)) Push(Bool(b >= a)),
-    Mod % (Num(a), Num(b)) Push(Num(b % a)),
-    If if (f, t, Bool(cond)) Push(if cond { t } else { f }),
-    Jump jmp (Num(a)) Jump(a as usize),
-    Duplicate dup (val) PushTwo(val.clone(), val),
-    Drop drop (_) NA,
-    Rotate rot (a, b, c) PushThree(b, a, c),
-    Swap swap (a, b) PushTwo(a, b),
-    SleepMS sleep_ms (Num(a)) Sleep(a as u64),
-    Exit exit (Num(exit_code)) Stop(exit_code as i32),
-    Stop stop () Stop(0),
-    Read read () ReadLn,
-    Over over (a, b) PushThree(b.clone(), a, b),
-    Call call (Num(a)) Call(a as usize),
-    Return return () Return,
+    /// Generated enum of all the user-accessible primitive stack operations.
+    pub enum StackOperation {
+        Plus + (Num(a), Num(b)) Push(Num(a + b)),
+        Minus - (Num(a), Num(b)) Push(Num(b - a)),
+        Multiply * (Num(a), Num(b)) Push(Num(a * b)),
+        Divide / (Num(a), Num(b)) Push(Num(b / a)),
+        ToInt cast_int (String(a)) Push(Num(a.parse::<isize>().unwrap_or(0))),
+        ToStr cast_str (a) Push(String(format!("{}", a))),
+        Println println (a) Println(a),
+        Eprintln eprintln (a) Eprintln(a),
+        Print print (a) Print(a),
+        Flush flush () Flush,
+        Equals == (a, b) Push(Bool(a == b)),
+        Or or (Bool(a), Bool(b)) Push(Bool(a || b)),
+        And and (Bool(a), Bool(b)) Push(Bool(a && b)),
+        Not not (Bool(a)) Push(Bool(!a)),
+        LessThan < (Num(a), Num(b)) Push(Bool(b < a)),
+        LessThanOrEqualTo <= (Num(a), Num(b)) Push(Bool(b <= a)),
+        GreaterHan > (Num(a), Num(b)) Push(Bool(b > a)),
+        GreaterHanOrEqualto >= (Num(a), Num(b)) Push(Bool(b >= a)),
+        Mod % (Num(a), Num(b)) Push(Num(b % a)),
+        If if (f, t, Bool(cond)) Push(if cond { t } else { f }),
+        Jump jmp (Num(a)) Jump(a as usize),
+        Duplicate dup (val) PushTwo(val.clone(), val),
+        Drop drop (_) NA,
+        Rotate rot (a, b, c) PushThree(b, a, c),
+        Swap swap (a, b) PushTwo(a, b),
+        SleepMS sleep_ms (Num(a)) Sleep(a as u64),
+        Exit exit (Num(exit_code)) Stop(exit_code as i32),
+        Stop stop () Stop(0),
+        Read read () ReadLn,
+        Over over (a, b) PushThree(b.clone(), a, b),
+        Call call (Num(a)) Call(a as usize),
+        Return return () Return,
+        Open open (String(path)) Open(path),
+        ReadFile read_file (String(path)) ReadFile(path),
+        WriteFile write_file (String(path), String(contents)) WriteFile(path, contents),
+        AppendFile append_file (String(path), String(contents)) AppendFile(path, contents),
+        Exists exists (String(path)) Exists(path),
+        ListDir list_dir (String(path)) ListDir(path),
+        Env env (String(name)) EnvVar(name),
+        NowMs now_ms () Now,
+    }
 }
 
 /// A value that can live on the stack.
+///
+/// It is generic to the `Instruction` set its operations are from.
 #[derive(Clone, PartialEq, Debug)]
-pub enum StackValue {
+pub enum StackValue<I = StackOperation> {
     Bool(bool),
     Num(isize),
     Label(String),
-    Operation(StackOperation),
+    Operation(I),
     String(String),
     PossibleLabel(String),
+    /// A native function, by its index in the machine's `Natives`.
+    Native(usize),
 }
 
-impl std::fmt::Display for StackValue {
+impl<I: std::fmt::Debug> std::fmt::Display for StackValue<I> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         use StackValue::*;
         match *self {
@@ -97,11 +170,12 @@ impl std::fmt::Display for StackValue {
             String(ref s) => write!(f, "{}", s),
             Operation(ref op) => write!(f, "<op:{:?}>", op),
             PossibleLabel(ref s) => s.fmt(f),
+            Native(index) => write!(f, "<native:{}>", index),
         }
     }
 }
 
-impl FromStr for StackValue {
+impl<I: Instruction> FromStr for StackValue<I> {
     type Err = StackError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         use StackValue::*;
@@ -112,7 +186,7 @@ impl FromStr for StackValue {
             Ok(StackValue::Bool(false))
         } else if let Ok(n) = s.parse::<isize>() {
             return Ok(Num(n));
-        } else if let Ok(op) = StackOperation::from_str(s) {
+        } else if let Ok(op) = I::from_str(s) {
             return Ok(Operation(op));
         } else if len > 1 && s.starts_with('"') && s.ends_with('"') {
             let substr = unsafe { s.get_unchecked(1..(len - 1)) };
@@ -126,17 +200,69 @@ impl FromStr for StackValue {
     }
 }
 
-pub type Code = Vec<StackValue>;
+pub type Code<I = StackOperation> = Vec<StackValue<I>>;
+
+/// Bounds on how much memory a `Machine` is allowed to use.
+///
+/// Every limit defaults to `None`, which means unbounded.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    /// Maximum number of values on the data stack.
+    pub stack_depth: Option<usize>,
+    /// Maximum number of return addresses, i.e. how deeply `call`s can nest.
+    pub return_stack_depth: Option<usize>,
+    /// Maximum number of bytes held by all `String` values on the data stack.
+    pub string_bytes: Option<usize>,
+}
+
+/// Why a running machine handed control back before its program stopped.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PauseReason {
+    /// The condition given to `run_until` was met.
+    Condition,
+    /// The program wants to `read` but no input has been provided,
+    /// see `Machine::pause_on_input`.
+    AwaitingInput,
+    /// The number of steps given to `run_for` have been taken.
+    StepBudget,
+    /// The next instruction to execute, at this address, has a breakpoint.
+    Breakpoint(usize),
+    /// The watchpoint with this id was triggered by the last step.
+    Watchpoint(usize),
+}
+
+/// The outcome of running a machine.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RunStatus {
+    /// The program stopped with this exit code.
+    Stopped(i32),
+    /// The machine paused and can be picked back up with `resume`.
+    Paused(PauseReason),
+}
 
-/// Contains the exit code of the vm program.
-pub struct RunResult {
-    pub exit_code: i32,
+impl RunStatus {
+    /// The exit code of the program, if it has stopped.
+    pub fn exit_code(&self) -> Option<i32> {
+        match *self {
+            RunStatus::Stopped(exit_code) => Some(exit_code),
+            RunStatus::Paused(_) => None,
+        }
+    }
 }
 
-#[derive(Copy, Clone)]
+/// How many steps a running machine takes between checking
+/// its `CancelHandle` and deadline.
+const INTERRUPT_POLL_INTERVAL: usize = 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum StepResult {
     Continue,
     Stop(i32),
+    /// The instruction needs input that hasn't been provided yet.
+    ///
+    /// The instruction isn't executed, so stepping again after
+    /// `provide_input` will run it.
+    AwaitingInput,
 }
 
 /// This is the Stack Machine.
@@ -145,57 +271,246 @@ pub enum StepResult {
 /// a zero-size memory dependency (at rust runtime),
 /// but can be injected to test reading to and writing
 /// from stdout.
+///
+/// It is also generic to an `Observer`, which is told about every step
+/// the machine takes and costs nothing when it is the default `NoObserver`,
+/// and to the `Instruction` set it runs, see the `stack_operations` module.
 #[derive(Debug)]
-pub struct Machine<E>
+pub struct Machine<E, O = NoObserver, I = StackOperation>
 where
     E: SideEffect,
+    O: Observer,
+    I: Instruction,
 {
     effect: E,
-    pub code: Code,
-    step: bool,
+    observer: O,
+    pub code: Code<I>,
     instruction_ptr: usize,
     return_stack: Vec<usize>,
-    stack: Vec<StackValue>,
+    stack: Vec<StackValue<I>>,
+    steps: usize,
+    step_limit: Option<usize>,
+    limits: Limits,
+    /// The bytes held by strings on the stack, only counted while
+    /// there is a `Limits::string_bytes`.
+    string_bytes: usize,
+    /// Whether pushes and pops go through `checked_push` and `checked_pop`,
+    /// because of limits, watchpoints or the journal.
+    checked_stack: bool,
+    cancel: Option<CancelHandle>,
+    deadline: Option<Instant>,
+    pause_on_input: bool,
+    /// Lines for `read` to push before asking the `SideEffect`, given with
+    /// `provide_input` or put back by `step_back`.
+    input: VecDeque<String>,
+    breakpoints: Breakpoints<I>,
+    natives: Natives<I>,
+    trace: Option<TraceState<I>>,
+    history: Option<History<I>>,
+    journal: Option<Journal<I>>,
 }
 
-impl<E: SideEffect> Machine<E> {
-    /// Create a new machine for the code.
+impl<E: SideEffect, O: Observer, I: Instruction> Machine<E, O, I> {
+    /// Create a new machine for the code, with the default `SideEffect`.
     ///
     /// This runs through a `preprocess` step.
-    pub fn new(code: Code) -> Result<Self, StackError> {
+    pub fn new(code: Code<I>) -> Result<Self, StackError>
+    where
+        E: Default,
+        O: Default,
+    {
+        Self::with_effect(code, E::default())
+    }
+
+    /// Create a new machine for the code that uses `effect` for I/O.
+    pub fn with_effect(code: Code<I>, effect: E) -> Result<Self, StackError>
+    where
+        O: Default,
+    {
+        Self::with_effect_and_observer(code, effect, O::default())
+    }
+
+    /// Create a new machine for the code that reports to `observer`.
+    pub fn with_observer(code: Code<I>, observer: O) -> Result<Self, StackError>
+    where
+        E: Default,
+    {
+        Self::with_effect_and_observer(code, E::default(), observer)
+    }
+
+    /// Create a new machine for the code that uses `effect` for I/O
+    /// and reports to `observer`.
+    pub fn with_effect_and_observer(code: Code<I>, effect: E, observer: O) -> Result<Self, StackError> {
         let code = Self::preprocess(code)?;
+        Ok(Self::from_preprocessed(code, effect, observer))
+    }
+
+    /// Creates a machine for code that has already been through `preprocess`.
+    fn from_preprocessed(code: Code<I>, effect: E, observer: O) -> Self {
         let len = code.len();
-        Ok(Machine {
-            effect: E::default(),
+        Machine {
+            effect,
+            observer,
             code,
-            step: false,
             instruction_ptr: 0,
             return_stack: Vec::new(),
             stack: Vec::with_capacity(len),
-        })
+            steps: 0,
+            step_limit: None,
+            limits: Limits::default(),
+            string_bytes: 0,
+            checked_stack: false,
+            cancel: None,
+            deadline: None,
+            pause_on_input: false,
+            input: VecDeque::new(),
+            breakpoints: Breakpoints::default(),
+            natives: Natives::default(),
+            trace: None,
+            history: None,
+            journal: None,
+        }
+    }
+
+    /// Limits the total number of steps this machine can take.
+    ///
+    /// Once the limit is reached `step` returns `StackError::BudgetExhausted`
+    /// *before* executing anything, so the limit can be raised and the
+    /// machine picked back up with `resume`.
+    pub fn set_step_limit(&mut self, limit: Option<usize>) {
+        self.step_limit = limit;
+    }
+
+    /// Bounds the memory the machine can use, see `Limits`.
+    ///
+    /// These are checked as values are pushed, so values already on the
+    /// stack are not affected by lowering a limit.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.count_string_bytes();
+        self.update_checked_stack();
+    }
+
+    /// Counts the bytes held by strings on the stack, if there is a limit on them.
+    pub(crate) fn count_string_bytes(&mut self) {
+        self.string_bytes = match self.limits.string_bytes {
+            Some(_) => self.stack.iter().map(string_bytes).sum(),
+            None => 0,
+        };
+    }
+
+    pub(crate) fn update_checked_stack(&mut self) {
+        self.checked_stack = self.limits != Limits::default()
+            || self.breakpoints.watch_pushes
+            || self.journal.is_some();
+    }
+
+    /// Returns a handle that can cancel this machine while it is running.
+    ///
+    /// Every call returns a handle to the same underlying flag.
+    pub fn cancel_handle(&mut self) -> CancelHandle {
+        self.cancel.get_or_insert_with(CancelHandle::new).clone()
+    }
+
+    /// Makes the machine stop with `StackError::TimedOut` if it is
+    /// still running at `deadline`. A `sleep_ms` is cut short at it.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// When enabled, a `read` with no input available pauses the machine
+    /// with `PauseReason::AwaitingInput` instead of blocking on the `SideEffect`.
+    ///
+    /// The input is then given to the machine with `provide_input`.
+    pub fn pause_on_input(&mut self, pause: bool) {
+        self.pause_on_input = pause;
+    }
+
+    /// Provides a line for a `read` to push onto the stack, after
+    /// any lines that were provided before it.
+    pub fn provide_input(&mut self, line: String) {
+        self.input.push_back(line);
+    }
+
+    /// The machine's `SideEffect`.
+    pub fn effect(&self) -> &E {
+        &self.effect
     }
 
-    pub fn enable_step(&mut self) {
-        self.step = true;
+    /// The machine's `SideEffect`, for configuring it after the machine is created.
+    pub fn effect_mut(&mut self) -> &mut E {
+        &mut self.effect
+    }
+
+    /// Consumes the machine, returning its `SideEffect`, for example to
+    /// inspect what a program wrote once it has run.
+    pub fn into_effect(self) -> E {
+        self.effect
+    }
+
+    /// The number of steps taken since the machine was created or `reset`.
+    pub fn steps(&self) -> usize {
+        self.steps
     }
 
     pub fn reset(&mut self) {
         self.instruction_ptr = 0;
+        self.steps = 0;
+        self.string_bytes = 0;
+        self.input.clear();
+        self.breakpoints.reset();
+        self.clear_history();
+        self.observer.on_reset();
         self.return_stack.drain(..);
         self.stack.drain(..);
     }
 
-    pub fn stack(&self) -> Vec<StackValue> {
+    pub fn stack(&self) -> Vec<StackValue<I>> {
         self.stack.clone()
     }
 
+    /// The index into `code` of the next instruction to execute.
+    pub fn instruction_ptr(&self) -> usize {
+        self.instruction_ptr
+    }
+
+    pub fn return_stack(&self) -> &[usize] {
+        &self.return_stack
+    }
+
+    /// The address that jumping to the label `name` would go to.
+    pub fn label_address(&self, name: &str) -> Option<usize> {
+        self.code.iter().position(|value| match *value {
+            StackValue::Label(ref label) => label == name,
+            _ => false,
+        }).map(|idx| idx + 1)
+    }
+
+    /// Finds the closest label defined at or before `address`,
+    /// returning its name and how far past the label `address` is.
+    pub fn label_for(&self, address: usize) -> Option<(&str, usize)> {
+        let end = address.min(self.code.len());
+        self.code[..end].iter().enumerate().rev()
+            .filter_map(|(idx, value)| match *value {
+                StackValue::Label(ref label) => Some((label.as_str(), address - idx - 1)),
+                _ => None,
+            })
+            .next()
+    }
+
     /// Takes `Code` as input and finds and replaces the
     /// labels with their actual positions.
     ///
     /// This will return `StackError` if there are labels used
     /// that have never been defined, or if there are labels
     /// that have been defined multiple times.
-    pub fn preprocess(code: Code) -> Result<Code, StackError> {
+    pub fn preprocess(code: Code<I>) -> Result<Code<I>, StackError> {
+        Self::preprocess_with(code, &Natives::default())
+    }
+
+    /// Like `preprocess`, but also resolves the names of native functions
+    /// that aren't labels to `StackValue::Native`s, see the `native` module.
+    pub fn preprocess_with(code: Code<I>, natives: &Natives<I>) -> Result<Code<I>, StackError> {
         // The stack machine itself would know the labels
         // so we should know _before_ we run the code
         // whether or not there are malformed instructions.
@@ -207,9 +522,10 @@ impl<E: SideEffect> Machine<E> {
         // The hashmap is keyed on the label name, and the value is tuple of:
         // 1. Do we have a location in code to point this to? How many?
         // 2. How many times is this label referenced?
-        let replacements = {
+        let (replacements, native_replacements) = {
             let mut labels_meta: HashMap<&str, (Vec<usize>, Vec<usize>)> = HashMap::new();
             let mut replacements = vec![];
+            let mut native_replacements = vec![];
 
             for (idx, value) in code.iter().enumerate() {
                 if let StackValue::Label(ref s) = *value {
@@ -228,16 +544,19 @@ impl<E: SideEffect> Machine<E> {
                         locations: val.0.clone(),
                     });
                 } else if val.0.is_empty() && !val.1.is_empty() {
-                    return Err(StackError::UndefinedLabel {
-                        label: (*key).into(),
-                        times: val.1.len(),
-                    });
+                    match natives.index_of(key) {
+                        Some(index) => native_replacements.push((index, val.1)),
+                        None => return Err(StackError::UndefinedLabel {
+                            label: (*key).into(),
+                            times: val.1.len(),
+                        }),
+                    }
                 } else {
                     replacements.push((val.0[0], val.1));
                 }
             }
 
-            replacements
+            (replacements, native_replacements)
         };
 
         let mut code = code;
@@ -247,6 +566,11 @@ impl<E: SideEffect> Machine<E> {
                 code[location] = StackValue::Num(place);
             }
         }
+        for (index, locations) in native_replacements {
+            for location in locations {
+                code[location] = StackValue::Native(index);
+            }
+        }
 
         Ok(code)
     }
@@ -257,72 +581,351 @@ impl<E: SideEffect> Machine<E> {
         self.instruction_ptr = address;
     }
 
+    /// Pushes a single value onto the stack, enforcing the machine's `Limits`.
+    #[inline(always)]
+    fn push(&mut self, value: StackValue<I>) -> Result<(), StackError> {
+        let checked = self.checked_stack;
+        self.push_with(value, checked)
+    }
+
+    /// Like `push`, for when `checked` already says whether the stack is checked.
+    #[inline(always)]
+    fn push_with(&mut self, value: StackValue<I>, checked: bool) -> Result<(), StackError> {
+        if checked {
+            return self.checked_push(value);
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    /// Pops a single value off of the stack.
+    #[inline(always)]
+    fn pop(&mut self) -> Option<StackValue<I>> {
+        let checked = self.checked_stack;
+        self.pop_with(checked)
+    }
+
+    /// Like `pop`, for when `checked` already says whether the stack is checked.
+    #[inline(always)]
+    fn pop_with(&mut self, checked: bool) -> Option<StackValue<I>> {
+        if checked {
+            return self.checked_pop();
+        }
+        self.stack.pop()
+    }
+
+    /// Whether pushes and pops need `checked_push` and `checked_pop`,
+    /// which `ops!` looks up once per operation.
+    #[doc(hidden)]
+    #[inline(always)]
+    pub fn stack_is_checked(&self) -> bool {
+        self.checked_stack
+    }
+
+    /// The part of `push` for when there are limits, watchpoints or a journal,
+    /// kept out of line so that pushing stays cheap without them.
+    #[cold]
+    #[inline(never)]
+    fn checked_push(&mut self, value: StackValue<I>) -> Result<(), StackError> {
+        if let Some(limit) = self.limits.stack_depth {
+            if self.stack.len() >= limit {
+                return Err(StackError::StackOverflow { limit });
+            }
+        }
+        if let Some(limit) = self.limits.string_bytes {
+            let bytes = string_bytes(&value);
+            if self.string_bytes + bytes > limit {
+                return Err(StackError::StringMemoryExceeded { limit });
+            }
+            self.string_bytes += bytes;
+        }
+        if self.breakpoints.watch_pushes {
+            self.breakpoints.value_pushed(&value);
+        }
+        if let Some(ref mut journal) = self.journal {
+            journal.pushed.push(value.clone());
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    /// The part of `pop` for when there are limits or a journal, releasing
+    /// any string memory the value held and journaling it.
+    #[cold]
+    #[inline(never)]
+    fn checked_pop(&mut self) -> Option<StackValue<I>> {
+        let value = self.stack.pop();
+        if let Some(ref value) = value {
+            if self.limits.string_bytes.is_some() {
+                self.string_bytes -= string_bytes(value);
+            }
+            if let Some(ref mut journal) = self.journal {
+                journal.popped.push(value.clone());
+            }
+        }
+        value
+    }
+
     /// Dispatch given the result from the stack operation, which gets consumed here.
     ///
     /// Returns an Error or a StepResult indicating how this loop should continue.
-    pub fn dispatch(&mut self, op: MachineOperation) -> Result<StepResult, StackError> {
+    #[inline(always)]
+    pub fn dispatch(&mut self, op: MachineOperation<I>) -> Result<StepResult, StackError> {
+        let checked = self.checked_stack;
+        self.dispatch_with(op, checked)
+    }
+
+    /// `dispatch` for `ops!`, given what `stack_is_checked` returned.
+    /// Only a checked stack can have a journal.
+    #[doc(hidden)]
+    #[inline(always)]
+    pub fn dispatch_with(&mut self, op: MachineOperation<I>, checked: bool) -> Result<StepResult, StackError> {
+        if checked && self.journal.is_some() {
+            return self.journaled_dispatch(op);
+        }
+        self.perform(op, checked)
+    }
+
+    /// Carries out an operation for `dispatch`, inlined so that each
+    /// operation only keeps the arm it dispatches to. Limits and the
+    /// journal are only looked at when `checked`.
+    #[inline(always)]
+    fn perform(&mut self, op: MachineOperation<I>, checked: bool) -> Result<StepResult, StackError> {
 
         use MachineOperation::*;
 
-        if self.step {
-            macro_rules! debug_step {
-                ($($e:expr),*) => {
-                    $(self.effect.println(StackValue::String(format!("{}:\t{:?}", stringify!($e), $e)));)*
-                };
-            }
-            debug_step![op, self.code[self.instruction_ptr - 1], self.stack, self.return_stack];
-            self.effect.println(StackValue::String(String::from("...")));
-            self.effect.read_line();
-        }
+        let address = self.instruction_ptr.wrapping_sub(1);
+        self.observer.after_dispatch(address, &op, &self.stack);
 
         match op {
             Call(to) => {
+                if checked {
+                    self.check_call()?;
+                }
                 self.return_stack.push(self.instruction_ptr);
                 self.jump(to);
+                self.observer.on_call(address, to, &self.return_stack);
             }
             Jump(to) => {
                 self.jump(to);
             }
-            Push(val) => self.stack.push(val),
+            Push(val) => self.push_with(val, checked)?,
             PushTwo(v1, v2) => {
-                self.stack.push(v1);
-                self.stack.push(v2);
+                self.push_with(v1, checked)?;
+                self.push_with(v2, checked)?;
             }
             PushThree(v1, v2, v3) => {
-                self.stack.push(v1);
-                self.stack.push(v2);
-                self.stack.push(v3);
+                self.push_with(v1, checked)?;
+                self.push_with(v2, checked)?;
+                self.push_with(v3, checked)?;
+            }
+            PushAll(values) => {
+                for value in values {
+                    self.push_with(value, checked)?;
+                }
             }
             Return => match self.return_stack.pop() {
                 Some(jump_to) => {
+                    if checked {
+                        if let Some(ref mut journal) = self.journal {
+                            journal.return_change = ReturnChange::Popped(jump_to);
+                        }
+                    }
                     self.jump(jump_to);
+                    self.observer.on_return(address, jump_to, &self.return_stack);
                 }
                 _ => return ops!(ERR EmptyStack Return, return),
             },
-            Sleep(ms) => self.effect.sleep_ms(ms),
-            Println(val) => self.effect.println(val),
-            ReadLn => self.stack.push(StackValue::String(self.effect.read_line())),
             NA => (),
             Stop(code) => return Ok(StepResult::Stop(code)),
+            op => return self.perform_effect(op),
+        }
+        Ok(StepResult::Continue)
+    }
+
+    /// Enforces `Limits::return_stack_depth` for a `call`, and journals it.
+    #[cold]
+    #[inline(never)]
+    fn check_call(&mut self) -> Result<(), StackError> {
+        if let Some(limit) = self.limits.return_stack_depth {
+            if self.return_stack.len() >= limit {
+                return Err(StackError::ReturnStackOverflow { limit });
+            }
+        }
+        if let Some(ref mut journal) = self.journal {
+            journal.return_change = ReturnChange::Pushed;
         }
+        Ok(())
+    }
+
+    /// Carries out the operations that go through the `SideEffect`, kept
+    /// apart from `perform` so that the stack operations stay small.
+    #[inline(never)]
+    fn perform_effect(&mut self, op: MachineOperation<I>) -> Result<StepResult, StackError> {
+
+        use MachineOperation::*;
+
+        match op {
+            Sleep(ms) => {
+                // Sleeping past the deadline would only delay timing out.
+                let ms = match self.deadline {
+                    Some(deadline) => {
+                        let left = deadline.saturating_duration_since(Instant::now());
+                        ms.min(left.as_millis() as u64)
+                    }
+                    None => ms,
+                };
+                self.effect.sleep_ms(ms).map_err(|error| io_error("sleep_ms", None, error))?
+            }
+            Println(val) => self.effect.println(val).map_err(|error| io_error("println", None, error))?,
+            Eprintln(val) => self.effect.eprintln(val).map_err(|error| io_error("eprintln", None, error))?,
+            Print(val) => self.effect.print(val).map_err(|error| io_error("print", None, error))?,
+            Flush => self.effect.flush().map_err(|error| io_error("flush", None, error))?,
+            ReadLn => {
+                let line = match self.input.pop_front() {
+                    Some(line) => line,
+                    None if self.pause_on_input => return Ok(StepResult::AwaitingInput),
+                    None => self.effect.read_line().map_err(|error| io_error("read", None, error))?,
+                };
+                if let Some(ref mut journal) = self.journal {
+                    journal.input = Some(line.clone());
+                }
+                self.push(StackValue::String(line))?;
+            }
+            Open(path) => self.effect.open(&path).map_err(|error| io_error("open", Some(&path), error))?,
+            ReadFile(path) => {
+                let contents = self.effect.read_file(&path).map_err(|error| io_error("read_file", Some(&path), error))?;
+                self.push(StackValue::String(contents))?;
+            }
+            WriteFile(path, contents) => {
+                self.effect.write_file(&path, &contents).map_err(|error| io_error("write_file", Some(&path), error))?;
+            }
+            AppendFile(path, contents) => {
+                self.effect.append_file(&path, &contents).map_err(|error| io_error("append_file", Some(&path), error))?;
+            }
+            Exists(path) => {
+                let exists = self.effect.exists(&path).map_err(|error| io_error("exists", Some(&path), error))?;
+                self.push(StackValue::Bool(exists))?;
+            }
+            ListDir(path) => {
+                let mut names = self.effect.list_dir(&path).map_err(|error| io_error("list_dir", Some(&path), error))?;
+                names.sort();
+                let count = names.len();
+                for name in names {
+                    self.push(StackValue::String(name))?;
+                }
+                self.push(StackValue::Num(count as isize))?;
+            }
+            EnvVar(name) => {
+                let value = self.effect.env_var(&name).map_err(|error| io_error("env", None, error))?;
+                self.push(StackValue::String(value.unwrap_or_default()))?;
+            }
+            Now => {
+                let now = self.effect.now_ms().map_err(|error| io_error("now_ms", None, error))?;
+                self.push(StackValue::Num(now as isize))?;
+            }
+            _ => unreachable!("{:?} is not a side effect", op),
+        }
+        // Side effects can block for a while, so they don't wait for
+        // the next poll to notice an interrupt.
+        self.check_interrupts()?;
         Ok(StepResult::Continue)
     }
 
-    pub fn stack_push(&mut self, mut values: Vec<StackValue>) {
-        self.stack.append(&mut values);
+    /// Pops the top value off of the stack.
+    ///
+    /// Like the other changes made to the stack between steps, this
+    /// discards the undo history.
+    pub fn stack_pop(&mut self) -> Option<StackValue<I>> {
+        self.clear_history();
+        self.pop()
+    }
+
+    /// Pops an operand for the operation being executed, used by `ops!`.
+    #[doc(hidden)]
+    #[inline(always)]
+    pub fn pop_operand(&mut self, checked: bool) -> Option<StackValue<I>> {
+        self.pop_with(checked)
+    }
+
+    /// Replaces the value at `index` (counting from the bottom of the stack),
+    /// returning the value that was there.
+    ///
+    /// Returns `StackError::StackIndexOutOfBounds` if there is no value at `index`.
+    pub fn stack_set(&mut self, index: usize, value: StackValue<I>) -> Result<StackValue<I>, StackError> {
+        let len = self.stack.len();
+        if index >= len {
+            return Err(StackError::StackIndexOutOfBounds { index, len });
+        }
+        if let Some(limit) = self.limits.string_bytes {
+            let old_bytes = string_bytes(&self.stack[index]);
+            let new_bytes = string_bytes(&value);
+            if new_bytes > old_bytes && self.string_bytes - old_bytes + new_bytes > limit {
+                return Err(StackError::StringMemoryExceeded { limit });
+            }
+            self.string_bytes = self.string_bytes - old_bytes + new_bytes;
+        }
+        self.clear_history();
+        Ok(mem::replace(&mut self.stack[index], value))
+    }
+
+    /// Pushes `values` onto the stack, discarding the undo history
+    /// if there are any.
+    pub fn stack_push(&mut self, values: Vec<StackValue<I>>) -> Result<(), StackError> {
+        if !values.is_empty() {
+            self.clear_history();
+        }
+        for value in values {
+            self.push(value)?;
+        }
+        Ok(())
     }
 
     /// Steps forward once in the stack machine.
     ///
     /// If there is no further to go given the `instruction_ptr`,
-    /// this will return `Ok(false)`, if there are further
-    /// instructions to proceed with, `Ok(true)`, otherwise
+    /// this will return `Ok(StepResult::Stop(0))`, if there are further
+    /// instructions to proceed with, `Ok(StepResult::Continue)`, otherwise
     /// it will return an `Err(StackError)`.
     pub fn step(&mut self) -> Result<StepResult, StackError> {
+        let address = self.instruction_ptr;
+        let result = if self.journal.is_some() {
+            self.journaled_step()
+        } else {
+            self.execute_step()
+        };
+        if let Err(ref e) = result {
+            self.observer.on_error(address, e);
+        }
+        result
+    }
+
+    #[inline(always)]
+    fn execute_step(&mut self) -> Result<StepResult, StackError> {
+        self.execute(true)
+    }
+
+    /// Executes the next instruction, leaving out the step budget and
+    /// checking the stack for values unless `instrumented`.
+    #[inline(always)]
+    fn execute(&mut self, instrumented: bool) -> Result<StepResult, StackError> {
         if self.instruction_ptr == self.code.len() {
             return Ok(StepResult::Stop(0));
         }
 
+        if instrumented {
+            if let Some(limit) = self.step_limit {
+                if self.steps >= limit {
+                    return Err(StackError::BudgetExhausted { steps: self.steps });
+                }
+            }
+        }
+        if O::ENABLED && self.pause_on_input && self.input.is_empty() && self.awaits_input() {
+            return Ok(StepResult::AwaitingInput);
+        }
+        self.steps += 1;
+        self.observer.before_step(self.instruction_ptr, &self.code[self.instruction_ptr], &self.stack);
+
         // We *first* borrow the value from the `code` we're running because
         // we might not actually need it (in case it's a label), otherwise we
         // clone it so that we can use it in the stack operations.
@@ -330,8 +933,8 @@ impl<E: SideEffect> Machine<E> {
         // If we use references here, the operation would end up having a mutable
         // reference to *this Machine* struct, which is a problem, since the `Machine`
         // owns the code that it operates on.
-        let value: StackValue = {
-            let value: &StackValue = &self.code[self.instruction_ptr];
+        let value: StackValue<I> = {
+            let value: &StackValue<I> = &self.code[self.instruction_ptr];
             self.instruction_ptr += 1;
             if let StackValue::Label(_) = *value {
                 return Ok(StepResult::Continue);
@@ -344,46 +947,206 @@ impl<E: SideEffect> Machine<E> {
         // otherwise it's a regular value, push it
         // onto the stack.
         if let StackValue::Operation(op) = value {
-            return op.dispatch(self);
+            let result = op.dispatch(self);
+            if let Ok(StepResult::AwaitingInput) = result {
+                self.instruction_ptr -= 1;
+                self.steps -= 1;
+            }
+            return result;
+        } else if let StackValue::Native(index) = value {
+            self.call_native(index)
         } else {
-            self.stack.push(value);
+            let checked = instrumented && self.checked_stack;
+            self.push_with(value, checked)?;
             return Ok(StepResult::Continue);
         }
     }
 
+    /// Whether the next instruction reads input, which `execute_step` checks
+    /// before it commits to the step, so observers don't see a step that
+    /// only pauses.
+    fn awaits_input(&self) -> bool {
+        match self.code[self.instruction_ptr] {
+            StackValue::Operation(ref op) => op.reads_input(),
+            _ => false,
+        }
+    }
+
+    /// Checks for cancellation and the deadline, but only when one is set,
+    /// and only every `INTERRUPT_POLL_INTERVAL` steps so that running stays cheap.
+    #[inline]
+    fn poll_interrupts(&self) -> Result<(), StackError> {
+        if self.cancel.is_none() && self.deadline.is_none() {
+            return Ok(());
+        }
+        if !self.steps.is_multiple_of(INTERRUPT_POLL_INTERVAL) {
+            return Ok(());
+        }
+        self.check_interrupts()
+    }
+
+    /// Checks for cancellation and the deadline.
+    fn check_interrupts(&self) -> Result<(), StackError> {
+        if let Some(ref cancel) = self.cancel {
+            if cancel.is_cancelled() {
+                return Err(StackError::Cancelled);
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return Err(StackError::TimedOut);
+            }
+        }
+        Ok(())
+    }
+
     /// Runs the machine with given arguments,
-    pub fn run(&mut self, args: Vec<StackValue>) -> Result<RunResult, StackError> {
+    pub fn run(&mut self, args: Vec<StackValue<I>>) -> Result<RunStatus, StackError> {
+        self.stack_push(args)?;
+        self.resume()
+    }
 
-        self.stack_push(args);
+    /// Runs at most `steps` steps from wherever the machine currently is.
+    ///
+    /// Pauses with `PauseReason::StepBudget` if the program has not stopped yet.
+    pub fn run_for(&mut self, steps: usize) -> Result<RunStatus, StackError> {
+        let until = self.steps.saturating_add(steps);
+        self.run_loop(|machine| machine.steps >= until, PauseReason::StepBudget)
+    }
 
+    /// Runs the machine until `condition` holds, pausing with `PauseReason::Condition`.
+    ///
+    /// The condition is checked before every step, *including* the first one,
+    /// so resuming with a condition that already holds will not make progress.
+    pub fn run_until<F>(&mut self, condition: F) -> Result<RunStatus, StackError>
+    where
+        F: FnMut(&Self) -> bool,
+    {
+        self.run_loop(condition, PauseReason::Condition)
+    }
+
+    /// Continues running the machine from its current state until it stops.
+    pub fn resume(&mut self) -> Result<RunStatus, StackError> {
+        self.run_loop(|_| false, PauseReason::Condition)
+    }
+
+    /// The main run loop, which pauses with `reason` when `pause` holds,
+    /// as well as on breakpoints and watchpoints.
+    #[inline]
+    fn run_loop<F>(&mut self, mut pause: F, reason: PauseReason) -> Result<RunStatus, StackError>
+    where
+        F: FnMut(&Self) -> bool,
+    {
+        self.breakpoints.run_started();
+        if !O::ENABLED && self.is_uninstrumented() {
+            return self.run_uninstrumented(pause, reason);
+        }
         loop {
-            match self.step() {
-                Err(e) => return Err(e),
-                Ok(StepResult::Stop(exit_code)) => {
-                    return Ok(RunResult {
-                        exit_code,
-                    })
-                }
-                Ok(StepResult::Continue) => {},
+            if let Some(reason) = self.check_breakpoint() {
+                return Ok(RunStatus::Paused(reason));
+            }
+            if pause(self) {
+                return Ok(RunStatus::Paused(reason));
+            }
+            self.poll_interrupts()?;
+            let depth = self.stack.len();
+            match self.step()? {
+                StepResult::Continue => {}
+                StepResult::Stop(exit_code) => return Ok(RunStatus::Stopped(exit_code)),
+                StepResult::AwaitingInput => return Ok(RunStatus::Paused(PauseReason::AwaitingInput)),
+            }
+            if let Some(reason) = self.check_watchpoints(depth) {
+                return Ok(RunStatus::Paused(reason));
+            }
+        }
+    }
+
+    /// Whether nothing needs to watch individual steps: no limits, journal,
+    /// step budget, interrupts, breakpoints or watchpoints.
+    fn is_uninstrumented(&self) -> bool {
+        !self.checked_stack
+            && self.step_limit.is_none()
+            && self.cancel.is_none()
+            && self.deadline.is_none()
+            && self.breakpoints.is_empty()
+    }
+
+    /// `run_loop` for when `is_uninstrumented` holds and there is no
+    /// observer, which only has to step and check `pause`.
+    fn run_uninstrumented<F>(&mut self, mut pause: F, reason: PauseReason) -> Result<RunStatus, StackError>
+    where
+        F: FnMut(&Self) -> bool,
+    {
+        loop {
+            if pause(self) {
+                return Ok(RunStatus::Paused(reason));
+            }
+            match self.execute(false)? {
+                StepResult::Continue => {}
+                StepResult::Stop(exit_code) => return Ok(RunStatus::Stopped(exit_code)),
+                StepResult::AwaitingInput => return Ok(RunStatus::Paused(PauseReason::AwaitingInput)),
             }
         }
     }
 }
 
+/// The bytes a value counts towards `Limits::string_bytes`.
+fn string_bytes<I>(value: &StackValue<I>) -> usize {
+    match *value {
+        StackValue::String(ref s) => s.len(),
+        _ => 0,
+    }
+}
+
+/// Converts an error from the machine's `SideEffect` during `operation`
+/// on the file at `path`, if any.
+fn io_error(operation: &str, path: Option<&str>, error: std::io::Error) -> StackError {
+    if let Some(sandbox::Denied(reason)) = error.get_ref().and_then(|error| error.downcast_ref()) {
+        return StackError::PermissionDenied {
+            operation: operation.to_owned(),
+            reason: reason.clone(),
+        };
+    }
+    StackError::Io {
+        operation: operation.to_owned(),
+        message: match path {
+            Some(path) => format!("{}: {}", path, error),
+            None => error.to_string(),
+        },
+    }
+}
+
 /// Given a `String` it should break this up into
 /// a list of tokens that can be parsed into `StackValue`.
 pub fn tokenize(input: &str) -> Result<Code, StackError> {
+    tokenize_with_lines(input).map(|(code, _)| code)
+}
+
+/// Like `tokenize`, but also returns the (1-based) source line
+/// that each of the tokens started on.
+pub fn tokenize_with_lines(input: &str) -> Result<(Code, Vec<usize>), StackError> {
+    tokenize_instructions(input)
+}
 
-    struct ParserState {
+/// The tokenizer for any `Instruction` set, see `Instruction::tokenize`.
+fn tokenize_instructions<I: Instruction>(input: &str) -> Result<(Code<I>, Vec<usize>), StackError> {
+
+    struct ParserState<I> {
         prev_is_escape: bool,
         ignore_til_eol: bool,
+        line: usize,
+        token_line: usize,
         token: String,
-        tokens: Vec<StackValue>,
+        tokens: Vec<StackValue<I>>,
+        lines: Vec<usize>,
     }
 
-    impl ParserState {
+    impl<I: Instruction> ParserState<I> {
         fn push_char(&mut self, c: char) {
             if !self.ignore_til_eol {
+                if self.token.is_empty() {
+                    self.token_line = self.line;
+                }
                 self.token.push(c);
             }
         }
@@ -391,6 +1154,7 @@ pub fn tokenize(input: &str) -> Result<Code, StackError> {
             if !self.token.is_empty() {
                 let value = StackValue::from_str(&self.token)?;
                 self.tokens.push(value);
+                self.lines.push(self.token_line);
                 self.token.clear();
             }
             Ok(())
@@ -403,11 +1167,18 @@ pub fn tokenize(input: &str) -> Result<Code, StackError> {
     let mut state = ParserState {
         prev_is_escape: false,
         ignore_til_eol: false,
+        line: 1,
+        token_line: 1,
         tokens: Vec::new(),
         token: String::new(),
+        lines: Vec::new(),
     };
 
     for c in input.chars() {
+        if c == '\n' {
+            state.line += 1;
+        }
+
         if state.ignore_til_eol {
             if c == '\n' {
                 state.ignore_til_eol = false;
@@ -452,13 +1223,14 @@ pub fn tokenize(input: &str) -> Result<Code, StackError> {
         }
     }
     state.push_token()?;
-    Ok(state.tokens)
+    Ok((state.tokens, state.lines))
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use testing::ScriptedEffect;
     use StackValue::*;
 
     macro_rules! assert_tokens {
@@ -468,6 +1240,24 @@ mod tests {
         }}
     }
 
+    #[test]
+    fn test_tokenize_with_lines() {
+        let (code, lines) = tokenize_with_lines("1 2\n# comment\n\n\"a\nb\" +\n").unwrap();
+        assert_eq!(vec![Num(1), Num(2), String("a\nb".to_owned()), Operation(StackOperation::Plus)], code);
+        assert_eq!(vec![1, 1, 4, 5], lines);
+    }
+
+    #[test]
+    fn test_labels() {
+        let code = tokenize("1 f call stop f: 2 + return").unwrap();
+        let machine = Machine::<ScriptedEffect>::new(code).unwrap();
+        assert_eq!(Some(5), machine.label_address("f"));
+        assert_eq!(None, machine.label_address("g"));
+        assert_eq!(None, machine.label_for(3));
+        assert_eq!(Some(("f", 0)), machine.label_for(5));
+        assert_eq!(Some(("f", 2)), machine.label_for(7));
+    }
+
     #[test]
     fn test_tokenize() {
         assert_tokens!([], "# whatever man");
@@ -491,40 +1281,11 @@ mod tests {
         machine.effect
     }
 
-    #[derive(Debug, PartialEq, Eq)]
-    struct NoIOEffect {
-        line: std::string::String,
-        output: Vec<std::string::String>,
-        slept: Vec<u64>,
-    }
-
-    impl Default for NoIOEffect {
-        fn default() -> NoIOEffect {
-            NoIOEffect {
-                line: "10".to_owned(), // we use this for testing
-                output: vec![],
-                slept: vec![],
-            }
-        }
-    }
-
-    impl SideEffect for NoIOEffect {
-        fn read_line(&mut self) -> std::string::String {
-            self.line.clone()
-        }
-        fn sleep_ms(&mut self, duration: u64) {
-            self.slept.push(duration)
-        }
-        fn println(&mut self, value: StackValue) {
-            self.output.push(format!("{}", value))
-        }
-    }
-
     macro_rules! effect {
         ($( $field:ident: $type:expr, )+) => {
-            NoIOEffect {
+            ScriptedEffect {
                 $( $field: $type, )+
-                ..NoIOEffect::default()
+                ..ScriptedEffect::default()
             }
         }
     }
@@ -538,10 +1299,10 @@ mod tests {
                 fn $name() {
                     use Machine;
                     let code = super::tokenize($code).unwrap();
-                    let mut machine = Machine::<NoIOEffect>::new(code).unwrap();
+                    let mut machine = Machine::<ScriptedEffect>::new(code).unwrap();
                     let output = machine.run(vec![]).unwrap();
                     assert_eq!($v, $f(machine));
-                    assert_eq!($c, output.exit_code);
+                    assert_eq!(RunStatus::Stopped($c), output);
                 }
             )+
         };
@@ -566,7 +1327,6 @@ mod tests {
         test_label2 0, Num(1), [  "0 one jmp one: 1 + end: 0 +" ],
         test_swap 0, Num(2), [ "1 2 swap" ],
         test_drop 0, Num(2), [ "1 drop 2" ],
-        test_readline 0, Bool(true), [ "10 read cast_int =="],
         test_rot1 0, Num(2), [ "1 2 3 rot" ],
         test_rot2 0, Num(5), [ "1 2 3 rot drop +" ],
         test_rot3 0, Num(3), [ "1 2 3 rot rot" ],
@@ -596,14 +1356,479 @@ mod tests {
     }
 
     test_run! { [effect]
-        test_sleep 0, effect! { slept: vec![10], }, [ "10 sleep_ms" ],
-        test_subsequent_sleeps 0, effect! { slept: vec![1, 1, 1], }, [ "1 dup dup sleep_ms sleep_ms sleep_ms" ],
+        test_sleep 0, effect! { slept: vec![10], now_ms: 10, }, [ "10 sleep_ms" ],
+        test_subsequent_sleeps 0, effect! { slept: vec![1, 1, 1], now_ms: 3, }, [ "1 dup dup sleep_ms sleep_ms sleep_ms" ],
         test_writes 0, effect! { output: vec!["10".to_owned(), "10".to_owned()], }, [ "10 dup println cast_str println" ],
     }
 
+    fn run_with_files(code: &str, files: &[(&str, &str)]) -> (Result<RunStatus, StackError>, Machine<ScriptedEffect>) {
+        let mut machine = Machine::<ScriptedEffect>::new(tokenize(code).unwrap()).unwrap();
+        for &(path, contents) in files {
+            machine.effect_mut().files.insert(path.to_owned(), contents.to_owned());
+        }
+        (machine.run(vec![]), machine)
+    }
+
+    #[test]
+    fn test_readline() {
+        testing::run("10 read cast_int ==", ScriptedEffect::with_input(vec!["10"]))
+            .unwrap()
+            .assert_stack(&[Bool(true)]);
+    }
+
+    /// Writes into a buffer it borrows, so it has no `Default`.
+    struct BufferEffect<'a> {
+        lines: &'a mut Vec<std::string::String>,
+    }
+
+    impl<'a> SideEffect for BufferEffect<'a> {
+        fn println<I: Instruction>(&mut self, value: StackValue<I>) -> std::io::Result<()> {
+            self.lines.push(value.to_string());
+            Ok(())
+        }
+        fn read_line(&mut self) -> std::io::Result<std::string::String> {
+            Ok("line".to_owned())
+        }
+        fn sleep_ms(&mut self, _duration: u64) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_effect_instance() {
+        let mut lines = vec![];
+        {
+            let effect = BufferEffect { lines: &mut lines };
+            let mut machine = Machine::<_>::with_effect(tokenize("1 println read println").unwrap(), effect).unwrap();
+            machine.run(vec![]).unwrap();
+            assert_eq!(2, machine.effect().lines.len());
+            let effect = machine.into_effect();
+            effect.lines.push("after".to_owned());
+        }
+        assert_eq!(vec!["1", "line", "after"], lines);
+    }
+
+    #[test]
+    fn test_file_operations() {
+        let code = r#"
+            "in.txt" read_file
+            "a" "out.txt" write_file "b" "out.txt" append_file "c" "new.txt" append_file
+            "out.txt" exists "nope.txt" exists
+            "in.txt" open read read
+        "#;
+        let (status, machine) = run_with_files(code, &[("in.txt", "one\ntwo")]);
+        assert_eq!(RunStatus::Stopped(0), status.unwrap());
+        let expected = vec![
+            String("one\ntwo".to_owned()),
+            Bool(true),
+            Bool(false),
+            String("one".to_owned()),
+            String("two".to_owned()),
+        ];
+        assert_eq!(expected, machine.stack());
+        assert_eq!("ab", machine.effect.files["out.txt"]);
+        assert_eq!("c", machine.effect.files["new.txt"]);
+    }
+
+    #[test]
+    fn test_list_dir() {
+        let files = [("dir/b", ""), ("dir/a", ""), ("dir/sub/c", ""), ("dir/sub/d", ""), ("other", "")];
+        let (_, machine) = run_with_files("\"dir\" list_dir", &files);
+        let expected = vec![String("a".to_owned()), String("b".to_owned()), String("sub".to_owned()), Num(3)];
+        assert_eq!(expected, machine.stack());
+    }
+
+    #[test]
+    fn test_io_errors() {
+        match run_with_files("\"nope.txt\" read_file", &[]).0 {
+            Err(StackError::Io { operation, message }) => {
+                assert_eq!("read_file", operation);
+                assert_eq!("nope.txt: not found", message);
+            }
+            other => panic!("expected an Io error, got {:?}", other),
+        }
+        match run_with_files("\"nope\" list_dir", &[]).0 {
+            Err(StackError::Io { operation, .. }) => assert_eq!("list_dir", operation),
+            other => panic!("expected an Io error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_step_limit_stops_runaway_loop() {
+        let code = tokenize("loop: loop jmp").unwrap();
+        let mut machine = Machine::<ScriptedEffect>::new(code).unwrap();
+        machine.set_step_limit(Some(100));
+        match machine.run(vec![]) {
+            Err(StackError::BudgetExhausted { steps }) => assert_eq!(100, steps),
+            _ => panic!("expected BudgetExhausted"),
+        }
+    }
+
+    #[test]
+    fn test_run_for_and_resume() {
+        let code = tokenize("1 2 + 3 +").unwrap();
+        let mut machine = Machine::<ScriptedEffect>::new(code).unwrap();
+        assert_eq!(RunStatus::Paused(PauseReason::StepBudget), machine.run_for(3).unwrap());
+        assert_eq!(vec![Num(3)], machine.stack());
+        assert_eq!(3, machine.steps());
+        assert_eq!(RunStatus::Stopped(0), machine.resume().unwrap());
+        assert_eq!(vec![Num(6)], machine.stack());
+
+        machine.reset();
+        machine.run_for(1).unwrap();
+        assert_eq!(RunStatus::Stopped(0), machine.run_for(usize::MAX).unwrap());
+    }
+
+    #[test]
+    fn test_checked_stack_runs_the_same() {
+        let code = tokenize(include_str!("../examples/fib_no_print")).unwrap();
+        let run = |limits: Limits| {
+            let mut machine = Machine::<ScriptedEffect>::new(code.clone()).unwrap();
+            machine.set_limits(limits);
+            let status = machine.run(vec![Num(10)]).unwrap();
+            (status, machine.stack(), machine.steps())
+        };
+        let checked = run(Limits { stack_depth: Some(1000), ..Limits::default() });
+        assert_eq!(run(Limits::default()), checked);
+        assert_eq!(vec![Num(55)], checked.1);
+    }
+
+    #[test]
+    fn test_resume_after_raising_step_limit() {
+        let code = tokenize("1 2 + 3 +").unwrap();
+        let mut machine = Machine::<ScriptedEffect>::new(code).unwrap();
+        machine.set_step_limit(Some(2));
+        assert!(machine.run(vec![]).is_err());
+        machine.set_step_limit(None);
+        assert_eq!(RunStatus::Stopped(0), machine.resume().unwrap());
+        assert_eq!(vec![Num(6)], machine.stack());
+    }
+
+    fn run_with_limits(code: &str, limits: Limits) -> Result<RunStatus, StackError> {
+        let code = tokenize(code).unwrap();
+        let mut machine = Machine::<ScriptedEffect>::new(code).unwrap();
+        machine.set_limits(limits);
+        machine.run(vec![])
+    }
+
+    #[test]
+    fn test_stack_depth_limit() {
+        let limits = Limits { stack_depth: Some(100), ..Limits::default() };
+        match run_with_limits("1 loop: dup loop jmp", limits) {
+            Err(StackError::StackOverflow { limit }) => assert_eq!(100, limit),
+            _ => panic!("expected StackOverflow"),
+        }
+    }
+
+    #[test]
+    fn test_return_stack_depth_limit() {
+        let limits = Limits { return_stack_depth: Some(64), ..Limits::default() };
+        match run_with_limits("f: f call", limits) {
+            Err(StackError::ReturnStackOverflow { limit }) => assert_eq!(64, limit),
+            _ => panic!("expected ReturnStackOverflow"),
+        }
+    }
+
+    #[test]
+    fn test_string_bytes_limit() {
+        let limits = Limits { string_bytes: Some(10), ..Limits::default() };
+        match run_with_limits("\"hello\" dup dup", limits) {
+            Err(StackError::StringMemoryExceeded { limit }) => assert_eq!(10, limit),
+            _ => panic!("expected StringMemoryExceeded"),
+        }
+    }
+
+    #[test]
+    fn test_string_bytes_are_released_when_popped() {
+        let limits = Limits { string_bytes: Some(5), ..Limits::default() };
+        assert!(run_with_limits("\"hello\" drop \"hello\" drop \"hello\"", limits).is_ok());
+    }
+
+    #[test]
+    fn test_stack_set() {
+        let mut machine = Machine::<ScriptedEffect>::new(vec![]).unwrap();
+        machine.set_limits(Limits { string_bytes: Some(6), ..Limits::default() });
+        machine.stack_push(vec![Num(1), String("abc".to_owned()), Num(3)]).unwrap();
+        for _ in 0..10 {
+            machine.stack_set(0, Num(2)).unwrap();
+        }
+        assert_eq!(Num(2), machine.stack_set(0, String("def".to_owned())).unwrap());
+        match machine.stack_set(2, String("g".to_owned())) {
+            Err(StackError::StringMemoryExceeded { limit }) => assert_eq!(6, limit),
+            _ => panic!("expected StringMemoryExceeded"),
+        }
+        assert_eq!(String("abc".to_owned()), machine.stack_set(1, Num(0)).unwrap());
+        assert_eq!(vec![String("def".to_owned()), Num(0), Num(3)], machine.stack());
+        match machine.stack_set(3, Num(4)) {
+            Err(StackError::StackIndexOutOfBounds { index, len }) => assert_eq!((3, 3), (index, len)),
+            _ => panic!("expected StackIndexOutOfBounds"),
+        }
+    }
+
+    #[test]
+    fn test_run_until() {
+        let code = tokenize("1 2 + 3 + 4 +").unwrap();
+        let mut machine = Machine::<ScriptedEffect>::new(code).unwrap();
+        let status = machine.run_until(|m| m.instruction_ptr() == 5).unwrap();
+        assert_eq!(RunStatus::Paused(PauseReason::Condition), status);
+        assert_eq!(vec![Num(6)], machine.stack());
+        assert_eq!(RunStatus::Stopped(0), machine.resume().unwrap());
+        assert_eq!(vec![Num(10)], machine.stack());
+    }
+
+    #[test]
+    fn test_pause_on_input() {
+        let code = tokenize("1 read cast_int +").unwrap();
+        let mut machine = Machine::<ScriptedEffect>::new(code).unwrap();
+        machine.pause_on_input(true);
+        let status = machine.run(vec![]).unwrap();
+        assert_eq!(RunStatus::Paused(PauseReason::AwaitingInput), status);
+        assert_eq!(1, machine.instruction_ptr());
+        assert_eq!(RunStatus::Paused(PauseReason::AwaitingInput), machine.resume().unwrap());
+        machine.provide_input("41".to_owned());
+        assert_eq!(RunStatus::Stopped(0), machine.resume().unwrap());
+        assert_eq!(vec![Num(42)], machine.stack());
+        assert!(effect(machine).output.is_empty());
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let code = tokenize("0 loop: 1 + dup 3 < loop end if jmp end:").unwrap();
+        let mut machine = Machine::<ScriptedEffect>::new(code).unwrap();
+        machine.set_breakpoint(0);
+        assert_eq!(Some(2), machine.set_breakpoint_at_label("loop"));
+        assert_eq!(None, machine.set_breakpoint_at_label("nope"));
+        assert_eq!(vec![0, 2], machine.breakpoints());
+
+        assert_eq!(RunStatus::Paused(PauseReason::Breakpoint(0)), machine.run(vec![]).unwrap());
+        assert!(machine.stack().is_empty());
+        assert_eq!(RunStatus::Paused(PauseReason::Breakpoint(2)), machine.resume().unwrap());
+        assert_eq!(vec![Num(0)], machine.stack());
+        assert_eq!(RunStatus::Paused(PauseReason::Breakpoint(2)), machine.resume().unwrap());
+        assert_eq!(vec![Num(1)], machine.stack());
+
+        assert!(machine.clear_breakpoint(2));
+        assert_eq!(RunStatus::Stopped(0), machine.resume().unwrap());
+        assert_eq!(vec![Num(3)], machine.stack());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let code = tokenize("1 2 3 drop 4 5 6").unwrap();
+        let mut machine = Machine::<ScriptedEffect>::new(code).unwrap();
+        let depth = machine.add_watchpoint(Watchpoint::StackDepthExceeds(2));
+        let pushed = machine.add_watchpoint(Watchpoint::ValuePushed(Num(5)));
+
+        assert_eq!(RunStatus::Paused(PauseReason::Watchpoint(depth)), machine.run(vec![]).unwrap());
+        assert_eq!(vec![Num(1), Num(2), Num(3)], machine.stack());
+        assert_eq!(RunStatus::Paused(PauseReason::Watchpoint(depth)), machine.resume().unwrap());
+        assert_eq!(vec![Num(1), Num(2), Num(4)], machine.stack());
+        assert_eq!(RunStatus::Paused(PauseReason::Watchpoint(pushed)), machine.resume().unwrap());
+        assert_eq!(vec![Num(1), Num(2), Num(4), Num(5)], machine.stack());
+
+        assert_eq!(Some(Watchpoint::StackDepthExceeds(2)), machine.remove_watchpoint(depth));
+        assert_eq!(RunStatus::Stopped(0), machine.resume().unwrap());
+    }
+
+    #[test]
+    fn test_step_watched() {
+        let code = tokenize("1 2 3").unwrap();
+        let mut machine = Machine::<ScriptedEffect>::new(code).unwrap();
+        let pushed = machine.add_watchpoint(Watchpoint::ValuePushed(Num(2)));
+        assert_eq!(RunStatus::Paused(PauseReason::StepBudget), machine.step_watched().unwrap());
+        assert_eq!(RunStatus::Paused(PauseReason::Watchpoint(pushed)), machine.step_watched().unwrap());
+        assert_eq!(RunStatus::Paused(PauseReason::StepBudget), machine.step_watched().unwrap());
+        assert_eq!(RunStatus::Stopped(0), machine.step_watched().unwrap());
+    }
+
+    #[test]
+    fn test_watchpoints_ignore_pushes_outside_a_run() {
+        let code = tokenize("1 2").unwrap();
+        let mut machine = Machine::<ScriptedEffect>::new(code).unwrap();
+        let pushed = machine.add_watchpoint(Watchpoint::ValuePushed(Num(2)));
+        machine.stack_push(vec![Num(2)]).unwrap();
+        assert_eq!(RunStatus::Paused(PauseReason::Watchpoint(pushed)), machine.run(vec![Num(2)]).unwrap());
+        assert_eq!(vec![Num(2), Num(2), Num(1), Num(2)], machine.stack());
+    }
+
+    #[test]
+    fn test_step_back() {
+        let code = tokenize("\"a\" 1 2 f call + read stop f: dup return").unwrap();
+        let mut machine = Machine::<_>::with_effect(code, ScriptedEffect::with_input(vec!["10"])).unwrap();
+        machine.set_history_limit(Some(100));
+        assert_eq!(RunStatus::Stopped(0), machine.run(vec![]).unwrap());
+        let finished = machine.snapshot();
+        assert_eq!(vec![String("a".to_owned()), Num(1), Num(4), String("10".to_owned())], machine.stack());
+
+        assert!(machine.rewind_to(6));
+        assert_eq!(vec![String("a".to_owned()), Num(1), Num(2), Num(2)], machine.stack());
+        assert_eq!(&[5], machine.return_stack());
+
+        assert!(machine.rewind_to(0));
+        assert!(machine.stack().is_empty());
+        assert_eq!(0, machine.instruction_ptr());
+        assert!(!machine.step_back());
+
+        assert_eq!(RunStatus::Stopped(0), machine.resume().unwrap());
+        assert_eq!(finished, machine.snapshot());
+    }
+
+    #[test]
+    fn test_step_back_puts_back_every_read() {
+        let code = tokenize("read read read").unwrap();
+        let mut machine = Machine::<_>::with_effect(code, ScriptedEffect::with_input(vec!["a", "b"])).unwrap();
+        machine.set_history_limit(Some(100));
+        machine.provide_input("c".to_owned());
+        assert_eq!(RunStatus::Stopped(0), machine.run(vec![]).unwrap());
+        assert_eq!(vec![String("c".to_owned()), String("a".to_owned()), String("b".to_owned())], machine.stack());
+
+        machine.provide_input("d".to_owned());
+        assert!(machine.rewind_to(0));
+        assert_eq!(RunStatus::Paused(PauseReason::StepBudget), machine.run_for(3).unwrap());
+        assert_eq!(vec![String("c".to_owned()), String("a".to_owned()), String("b".to_owned())], machine.stack());
+        assert_eq!(machine.input, ["d"]);
+    }
+
+    #[test]
+    fn test_stack_edits_discard_the_history() {
+        let code = tokenize("1 2 3").unwrap();
+        let mut machine = Machine::<ScriptedEffect>::new(code).unwrap();
+        machine.set_history_limit(Some(100));
+        machine.run_for(2).unwrap();
+        machine.stack_push(vec![]).unwrap();
+        assert_eq!(2, machine.history_len());
+        machine.stack_push(vec![Num(9)]).unwrap();
+        assert_eq!(0, machine.history_len());
+        assert!(!machine.step_back());
+
+        machine.resume().unwrap();
+        machine.stack_set(0, Num(0)).unwrap();
+        assert_eq!(0, machine.history_len());
+        machine.step_back();
+        assert_eq!(Some(Num(3)), machine.stack_pop());
+        assert_eq!(0, machine.history_len());
+        assert_eq!(vec![Num(0), Num(2), Num(9)], machine.stack());
+    }
+
+    #[test]
+    fn test_step_back_is_limited() {
+        let code = tokenize("1 2 3 4 5").unwrap();
+        let mut machine = Machine::<ScriptedEffect>::new(code).unwrap();
+        machine.set_history_limit(Some(2));
+        machine.run(vec![]).unwrap();
+        assert_eq!(2, machine.history_len());
+        assert!(machine.step_back() && machine.step_back());
+        assert!(!machine.step_back());
+        assert_eq!(vec![Num(1), Num(2), Num(3)], machine.stack());
+    }
+
+    #[test]
+    fn test_cancel_from_another_thread() {
+        let code = tokenize("loop: loop jmp").unwrap();
+        let mut machine = Machine::<ScriptedEffect>::new(code).unwrap();
+        let handle = machine.cancel_handle();
+        let canceller = ::std::thread::spawn(move || {
+            ::std::thread::sleep(::std::time::Duration::from_millis(10));
+            handle.cancel();
+        });
+        match machine.run(vec![]) {
+            Err(StackError::Cancelled) => {}
+            _ => panic!("expected Cancelled"),
+        }
+        canceller.join().unwrap();
+        assert!(machine.steps() > 0);
+    }
+
+    #[test]
+    fn test_deadline() {
+        let code = tokenize("loop: loop jmp").unwrap();
+        let mut machine = Machine::<ScriptedEffect>::new(code).unwrap();
+        machine.set_deadline(Some(Instant::now()));
+        match machine.run(vec![]) {
+            Err(StackError::TimedOut) => {}
+            _ => panic!("expected TimedOut"),
+        }
+    }
+
+    #[test]
+    fn test_deadline_cuts_sleeps_short() {
+        let code = tokenize("100 sleep_ms 1 println").unwrap();
+        let mut machine = Machine::<ScriptedEffect>::new(code).unwrap();
+        machine.run_for(1).unwrap();
+        machine.set_deadline(Some(Instant::now()));
+        match machine.resume() {
+            Err(StackError::TimedOut) => {}
+            _ => panic!("expected TimedOut"),
+        }
+        assert_eq!(2, machine.steps());
+        let effect = effect(machine);
+        assert_eq!(vec![0], effect.slept);
+        assert!(effect.output.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_handle_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<CancelHandle>();
+    }
+
     #[test]
     fn test_stack_operations_are_tiny() {
         assert_eq!(1, ::std::mem::size_of::<StackOperation>());
     }
 
+    mod tiny_ops {
+        ops! {
+            pub enum TinyOps {
+                One one () Push(Num(1)),
+                Add add (Num(a), Num(b)) Push(Num(a + b)),
+            }
+        }
+    }
+
+    mod saturating_ops {
+        ops! {
+            pub enum SaturatingOps extends ::StackOperation {
+                Plus + (Num(a), Num(b)) Push(Num(a.saturating_add(b))),
+                Double double (Num(a)) Push(Num(a * 2)),
+            }
+        }
+    }
+
+    use self::saturating_ops::SaturatingOps;
+    use self::tiny_ops::TinyOps;
+
+    #[test]
+    fn test_custom_instruction_set() {
+        let code = TinyOps::tokenize("one one add").unwrap();
+        assert_eq!(Operation(TinyOps::Add), code[2]);
+        assert_eq!("add", TinyOps::Add.name());
+        let mut machine = Machine::<ScriptedEffect, NoObserver, TinyOps>::new(code).unwrap();
+        machine.run(vec![]).unwrap();
+        assert_eq!(vec![Num(2)], machine.stack());
+
+        match Machine::<ScriptedEffect, NoObserver, TinyOps>::new(TinyOps::tokenize("1 dup").unwrap()) {
+            Err(StackError::UndefinedLabel { label, .. }) => assert_eq!("dup", label),
+            other => panic!("expected UndefinedLabel, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_extended_instruction_set() {
+        let code = SaturatingOps::tokenize("5 double 1 - dup +").unwrap();
+        assert_eq!(Operation(SaturatingOps::Double), code[1]);
+        assert_eq!(Operation(SaturatingOps::Base(StackOperation::Minus)), code[3]);
+        assert_eq!(Operation(SaturatingOps::Plus), code[5]);
+        assert_eq!("-", SaturatingOps::Base(StackOperation::Minus).name());
+
+        let mut machine = Machine::<ScriptedEffect, NoObserver, SaturatingOps>::new(code).unwrap();
+        machine.run(vec![]).unwrap();
+        assert_eq!(vec![Num(18)], machine.stack());
+
+        let code = SaturatingOps::tokenize("9223372036854775807 1 +").unwrap();
+        let mut machine = Machine::<ScriptedEffect, NoObserver, SaturatingOps>::new(code).unwrap();
+        machine.run(vec![]).unwrap();
+        assert_eq!(vec![Num(isize::MAX)], machine.stack());
+    }
+
 }
@@ -0,0 +1,348 @@
+//! Operations defined by the program embedding the machine.
+//!
+//! The operations generated by `ops!` are fixed at compile time. Embedders
+//! can add their own by registering named native functions in `Natives`,
+//! declaring the type of each argument. When the code is preprocessed, any
+//! word that isn't a label or operation is looked up in the registry and
+//! replaced by a `StackValue::Native`. A label with the same name as a
+//! native takes precedence over it.
+//!
+//! ```
+//! use simple_vm::*;
+//!
+//! let mut natives = Natives::new();
+//! natives.register("price", &[ValueType::String, ValueType::Num], |args| {
+//!     match (&args[0], &args[1]) {
+//!         (&StackValue::String(ref item), &StackValue::Num(count)) if item == "apple" => {
+//!             Ok(vec![StackValue::Num(3 * count)])
+//!         }
+//!         _ => Err("unknown item".to_owned()),
+//!     }
+//! }).unwrap();
+//!
+//! let code = tokenize("\"apple\" 2 price").unwrap();
+//! let mut machine = Machine::<DefaultSideEffect>::with_natives(code, natives).unwrap();
+//! machine.run(vec![]).unwrap();
+//! assert_eq!(vec![StackValue::Num(6)], machine.stack());
+//! ```
+//!
+//! Arguments are popped off of the stack and checked the same way generated
+//! operations do, so a missing argument is a `StackError::EmptyStack` and
+//! one of the wrong type a `StackError::PatternMismatch`. Errors returned by
+//! the function itself become `StackError::NativeFailed`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use super::*;
+
+/// The type of an argument to a native function.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueType {
+    /// Any value at all.
+    Any,
+    Bool,
+    Num,
+    String,
+}
+
+impl ValueType {
+    pub fn matches<I>(&self, value: &StackValue<I>) -> bool {
+        matches!(
+            (*self, value),
+            (ValueType::Any, _)
+                | (ValueType::Bool, &StackValue::Bool(_))
+                | (ValueType::Num, &StackValue::Num(_))
+                | (ValueType::String, &StackValue::String(_))
+        )
+    }
+
+    /// The pattern reported in errors, written like the ones in `ops!`.
+    fn pattern(&self) -> &'static str {
+        match *self {
+            ValueType::Any => "_",
+            ValueType::Bool => "Bool(_)",
+            ValueType::Num => "Num(_)",
+            ValueType::String => "String(_)",
+        }
+    }
+}
+
+/// A native function, given its arguments in the order they were pushed
+/// and returning the values to push.
+pub type NativeFn<I = StackOperation> = Box<dyn FnMut(&[StackValue<I>]) -> Result<Vec<StackValue<I>>, String>>;
+
+struct Native<I> {
+    name: String,
+    args: Vec<ValueType>,
+    function: NativeFn<I>,
+}
+
+/// A registry of native functions, see the `native` module.
+pub struct Natives<I = StackOperation> {
+    natives: Vec<Native<I>>,
+}
+
+impl<I> Default for Natives<I> {
+    fn default() -> Self {
+        Natives { natives: vec![] }
+    }
+}
+
+impl<I> fmt::Debug for Natives<I> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.natives.iter().map(|native| &native.name)).finish()
+    }
+}
+
+impl<I: Instruction> Natives<I> {
+    pub fn new() -> Natives<I> {
+        Natives::default()
+    }
+
+    /// Registers `function` under `name`, returning its index for `StackValue::Native`.
+    ///
+    /// Returns `StackError::InvalidNative` if `name` is already registered or
+    /// would not be read as a word, like a number or a built in operation.
+    pub fn register<F>(&mut self, name: &str, args: &[ValueType], function: F) -> Result<usize, StackError>
+    where
+        F: FnMut(&[StackValue<I>]) -> Result<Vec<StackValue<I>>, String> + 'static,
+    {
+        let invalid = |reason: &str| StackError::InvalidNative {
+            name: name.to_owned(),
+            reason: reason.to_owned(),
+        };
+        if name.is_empty() || name.contains(char::is_whitespace) || name.contains('#') {
+            return Err(invalid("names must be a single word"));
+        }
+        match StackValue::<I>::from_str(name) {
+            Ok(StackValue::PossibleLabel(_)) => {}
+            _ => return Err(invalid("the name is already a value or operation")),
+        }
+        if self.index_of(name).is_some() {
+            return Err(invalid("the name is already registered"));
+        }
+        self.natives.push(Native {
+            name: name.to_owned(),
+            args: args.to_vec(),
+            function: Box::new(function),
+        });
+        Ok(self.natives.len() - 1)
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.natives.iter().position(|native| native.name == name)
+    }
+
+    pub fn name(&self, index: usize) -> Option<&str> {
+        self.natives.get(index).map(|native| native.name.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.natives.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.natives.is_empty()
+    }
+}
+
+impl<E: SideEffect, O: Observer, I: Instruction> Machine<E, O, I> {
+    /// Create a new machine for code that uses the native functions in `natives`.
+    pub fn with_natives(code: Code<I>, natives: Natives<I>) -> Result<Self, StackError>
+    where
+        E: Default,
+        O: Default,
+    {
+        Self::with_natives_and_effect(code, natives, E::default(), O::default())
+    }
+
+    /// Create a new machine for code that uses the native functions in
+    /// `natives`, with `effect` for I/O, reporting to `observer`.
+    pub fn with_natives_and_effect(code: Code<I>, natives: Natives<I>, effect: E, observer: O) -> Result<Self, StackError> {
+        let code = Self::preprocess_with(code, &natives)?;
+        let mut machine = Self::from_preprocessed(code, effect, observer);
+        machine.set_natives(natives);
+        Ok(machine)
+    }
+
+    /// Replaces the machine's native functions.
+    ///
+    /// `StackValue::Native`s refer to natives by index, so these need to
+    /// be registered in the same order as when the code was preprocessed.
+    pub fn set_natives(&mut self, natives: Natives<I>) {
+        self.natives = natives;
+    }
+
+    pub fn natives(&self) -> &Natives<I> {
+        &self.natives
+    }
+
+    /// Pops the arguments for the native at `index`, checking their types,
+    /// calls it, and dispatches its results as a `MachineOperation::PushAll`.
+    pub(crate) fn call_native(&mut self, index: usize) -> Result<StepResult, StackError> {
+        let arity = match self.natives.natives.get(index) {
+            Some(native) => native.args.len(),
+            None => return Err(StackError::InvalidOperation { name: format!("<native:{}>", index) }),
+        };
+
+        let mut args = Vec::with_capacity(arity);
+        for arg in (0..arity).rev() {
+            let value = self.pop();
+            let native = &self.natives.natives[index];
+            let expected = native.args[arg];
+            match value {
+                None => return Err(StackError::EmptyStack {
+                    arg_pattern: expected.pattern().to_owned(),
+                    expr: native.name.clone(),
+                }),
+                Some(ref value) if !expected.matches(value) => return Err(StackError::PatternMismatch {
+                    arg_pattern: expected.pattern().to_owned(),
+                    expr: native.name.clone(),
+                }),
+                Some(value) => args.push(value),
+            }
+        }
+        args.reverse();
+
+        let native = &mut self.natives.natives[index];
+        let results = (native.function)(&args).map_err(|message| StackError::NativeFailed {
+            name: native.name.clone(),
+            message,
+        })?;
+        self.dispatch(MachineOperation::PushAll(results))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use trace::{TraceEvent, Tracer};
+    use StackValue::*;
+
+    fn natives() -> Natives {
+        let mut natives = Natives::new();
+        natives.register("add3", &[ValueType::Num, ValueType::Num, ValueType::Num], |args| {
+            match (&args[0], &args[1], &args[2]) {
+                (&Num(a), &Num(b), &Num(c)) => Ok(vec![Num(a * 100 + b * 10 + c)]),
+                _ => unreachable!(),
+            }
+        }).unwrap();
+        natives.register("fail", &[ValueType::Any], |_| Err("no".to_owned())).unwrap();
+        natives.register("nothing", &[], |_| Ok(vec![])).unwrap();
+        natives
+    }
+
+    fn run(code: &str) -> Result<Vec<StackValue>, StackError> {
+        let mut machine = Machine::<DefaultSideEffect>::with_natives(tokenize(code).unwrap(), natives())?;
+        machine.run(vec![])?;
+        Ok(machine.stack())
+    }
+
+    #[test]
+    fn test_register() {
+        let mut natives = natives();
+        assert_eq!(3, natives.len());
+        assert_eq!(Some(1), natives.index_of("fail"));
+        assert_eq!(Some("nothing"), natives.name(2));
+        for name in &["add3", "dup", "12", "true", "\"s\"", "l:", "two words", ""] {
+            match natives.register(name, &[], |_| Ok(vec![])) {
+                Err(StackError::InvalidNative { .. }) => {}
+                _ => panic!("registered {:?}", name),
+            }
+        }
+    }
+
+    #[test]
+    fn test_call() {
+        assert_eq!(vec![Num(123)], run("1 2 3 add3 nothing").unwrap());
+        assert_eq!(
+            vec![Operation(StackOperation::Plus), Native(0)],
+            Machine::<DefaultSideEffect>::preprocess_with(tokenize("+ add3").unwrap(), &natives()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_type_errors() {
+        match run("1 true 3 add3") {
+            Err(StackError::PatternMismatch { arg_pattern, expr }) => {
+                assert_eq!("Num(_)", arg_pattern);
+                assert_eq!("add3", expr);
+            }
+            other => panic!("expected PatternMismatch, got {:?}", other),
+        }
+        match run("2 3 add3") {
+            Err(StackError::EmptyStack { arg_pattern, expr }) => {
+                assert_eq!("Num(_)", arg_pattern);
+                assert_eq!("add3", expr);
+            }
+            other => panic!("expected EmptyStack, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_native_failure() {
+        match run("1 fail") {
+            Err(StackError::NativeFailed { name, message }) => {
+                assert_eq!("fail", name);
+                assert_eq!("no", message);
+            }
+            other => panic!("expected NativeFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_labels_take_precedence() {
+        assert_eq!(vec![Num(1)], run("nothing call stop nothing: 1 return").unwrap());
+        match run("unknown") {
+            Err(StackError::UndefinedLabel { label, .. }) => assert_eq!("unknown", label),
+            other => panic!("expected UndefinedLabel, got {:?}", other),
+        }
+    }
+
+    #[derive(Default)]
+    struct Dispatches(Vec<std::string::String>);
+
+    impl Observer for Dispatches {
+        fn after_dispatch<I: Instruction>(&mut self, _: usize, operation: &MachineOperation<I>, _: &[StackValue<I>]) {
+            self.0.push(format!("{:?}", operation));
+        }
+    }
+
+    struct Operations(Rc<RefCell<Vec<Option<MachineOperation>>>>);
+
+    impl Tracer for Operations {
+        fn trace(&mut self, event: &TraceEvent) {
+            self.0.borrow_mut().push(event.operation.clone());
+        }
+    }
+
+    #[test]
+    fn test_calls_are_observed_and_traced() {
+        let code = tokenize("1 2 3 add3").unwrap();
+        let mut machine = Machine::with_natives_and_effect(code, natives(), DefaultSideEffect::default(), Dispatches::default()).unwrap();
+        let operations = Rc::new(RefCell::new(vec![]));
+        machine.set_tracer(Box::new(Operations(operations.clone())));
+        machine.set_history_limit(Some(10));
+        machine.run(vec![]).unwrap();
+
+        let pushed = MachineOperation::PushAll(vec![Num(123)]);
+        assert_eq!(Some(&format!("{:?}", pushed)), machine.observer().0.last());
+        assert_eq!(Some(pushed), operations.borrow()[3]);
+        assert!(machine.step_back());
+        assert_eq!(vec![Num(1), Num(2), Num(3)], machine.stack());
+    }
+
+    #[test]
+    fn test_without_natives() {
+        let code = Machine::<DefaultSideEffect>::preprocess_with(tokenize("nothing").unwrap(), &natives()).unwrap();
+        let mut machine = Machine::<DefaultSideEffect>::new(code).unwrap();
+        match machine.run(vec![]) {
+            Err(StackError::InvalidOperation { name }) => assert_eq!("<native:2>", name),
+            other => panic!("expected InvalidOperation, got {:?}", other),
+        }
+    }
+}
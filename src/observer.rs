@@ -0,0 +1,258 @@
+//! Hooks into the step loop of a `Machine`.
+//!
+//! A `Machine` is generic over an `Observer` the same way it is generic
+//! over a `SideEffect`. Every callback does nothing by default, so with the
+//! default `NoObserver` they compile away and running costs nothing extra.
+//!
+//! To use several observers at once, combine them in a tuple, and wrap
+//! one in an `Option` to decide at runtime whether it is used:
+//!
+//! ```
+//! use simple_vm::*;
+//!
+//! let code = tokenize("1 2 +").unwrap();
+//! let observers = (Some(Coverage::new(&code)), None::<Profiler>);
+//! let mut machine = Machine::<DefaultSideEffect, _>::with_observer(code, observers).unwrap();
+//! machine.run(vec![]).unwrap();
+//! let coverage = machine.into_observer().0.unwrap();
+//! assert_eq!(&[1, 1, 1], coverage.hits());
+//! ```
+
+use super::*;
+
+/// Receives callbacks as a `Machine` steps through its code.
+///
+/// Addresses are indices into the machine's `code`.
+pub trait Observer {
+    /// Whether the machine needs to call this observer at all. `NoObserver`
+    /// sets it to `false`, which lets the machine run a leaner loop.
+    const ENABLED: bool = true;
+
+    /// Called before the instruction at `address` is executed.
+    ///
+    /// A `read` that pauses with `StepResult::AwaitingInput` isn't executed,
+    /// so this is only called once input is there to read.
+    fn before_step<I: Instruction>(&mut self, _address: usize, _instruction: &StackValue<I>, _stack: &[StackValue<I>]) {}
+
+    /// Called with the `MachineOperation` the instruction at `address`
+    /// dispatched to, before the machine carries it out.
+    fn after_dispatch<I: Instruction>(&mut self, _address: usize, _operation: &MachineOperation<I>, _stack: &[StackValue<I>]) {}
+
+    /// Called after the `call` at `address` jumped to `to`.
+    fn on_call(&mut self, _address: usize, _to: usize, _return_stack: &[usize]) {}
+
+    /// Called after the `return` at `address` jumped back to `to`.
+    fn on_return(&mut self, _address: usize, _to: usize, _return_stack: &[usize]) {}
+
+    /// Called when the step that started at `address` failed.
+    fn on_error(&mut self, _address: usize, _error: &StackError) {}
+
+    /// Called when the machine is `reset`.
+    fn on_reset(&mut self) {}
+}
+
+/// The default `Observer`, which observes nothing.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NoObserver;
+
+impl Observer for NoObserver {
+    const ENABLED: bool = false;
+}
+
+impl<O: Observer> Observer for Option<O> {
+    const ENABLED: bool = O::ENABLED;
+
+    #[inline]
+    fn before_step<I: Instruction>(&mut self, address: usize, instruction: &StackValue<I>, stack: &[StackValue<I>]) {
+        if let Some(observer) = self {
+            observer.before_step(address, instruction, stack);
+        }
+    }
+
+    #[inline]
+    fn after_dispatch<I: Instruction>(&mut self, address: usize, operation: &MachineOperation<I>, stack: &[StackValue<I>]) {
+        if let Some(observer) = self {
+            observer.after_dispatch(address, operation, stack);
+        }
+    }
+
+    #[inline]
+    fn on_call(&mut self, address: usize, to: usize, return_stack: &[usize]) {
+        if let Some(observer) = self {
+            observer.on_call(address, to, return_stack);
+        }
+    }
+
+    #[inline]
+    fn on_return(&mut self, address: usize, to: usize, return_stack: &[usize]) {
+        if let Some(observer) = self {
+            observer.on_return(address, to, return_stack);
+        }
+    }
+
+    #[inline]
+    fn on_error(&mut self, address: usize, error: &StackError) {
+        if let Some(observer) = self {
+            observer.on_error(address, error);
+        }
+    }
+
+    #[inline]
+    fn on_reset(&mut self) {
+        if let Some(observer) = self {
+            observer.on_reset();
+        }
+    }
+}
+
+impl<A: Observer, B: Observer> Observer for (A, B) {
+    const ENABLED: bool = A::ENABLED || B::ENABLED;
+
+    #[inline]
+    fn before_step<I: Instruction>(&mut self, address: usize, instruction: &StackValue<I>, stack: &[StackValue<I>]) {
+        self.0.before_step(address, instruction, stack);
+        self.1.before_step(address, instruction, stack);
+    }
+
+    #[inline]
+    fn after_dispatch<I: Instruction>(&mut self, address: usize, operation: &MachineOperation<I>, stack: &[StackValue<I>]) {
+        self.0.after_dispatch(address, operation, stack);
+        self.1.after_dispatch(address, operation, stack);
+    }
+
+    #[inline]
+    fn on_call(&mut self, address: usize, to: usize, return_stack: &[usize]) {
+        self.0.on_call(address, to, return_stack);
+        self.1.on_call(address, to, return_stack);
+    }
+
+    #[inline]
+    fn on_return(&mut self, address: usize, to: usize, return_stack: &[usize]) {
+        self.0.on_return(address, to, return_stack);
+        self.1.on_return(address, to, return_stack);
+    }
+
+    #[inline]
+    fn on_error(&mut self, address: usize, error: &StackError) {
+        self.0.on_error(address, error);
+        self.1.on_error(address, error);
+    }
+
+    #[inline]
+    fn on_reset(&mut self) {
+        self.0.on_reset();
+        self.1.on_reset();
+    }
+}
+
+impl<E: SideEffect, O: Observer, I: Instruction> Machine<E, O, I> {
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    pub fn observer_mut(&mut self) -> &mut O {
+        &mut self.observer
+    }
+
+    /// Consumes the machine, returning its observer.
+    pub fn into_observer(self) -> O {
+        self.observer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Events(Vec<::std::string::String>);
+
+    impl Observer for Events {
+        fn before_step<I: Instruction>(&mut self, address: usize, _: &StackValue<I>, stack: &[StackValue<I>]) {
+            self.0.push(format!("step {} {}", address, stack.len()));
+        }
+
+        fn after_dispatch<I: Instruction>(&mut self, address: usize, operation: &MachineOperation<I>, _: &[StackValue<I>]) {
+            self.0.push(format!("dispatch {} {:?}", address, operation));
+        }
+
+        fn on_call(&mut self, address: usize, to: usize, return_stack: &[usize]) {
+            self.0.push(format!("call {} {} {:?}", address, to, return_stack));
+        }
+
+        fn on_return(&mut self, address: usize, to: usize, return_stack: &[usize]) {
+            self.0.push(format!("return {} {} {:?}", address, to, return_stack));
+        }
+
+        fn on_error(&mut self, address: usize, error: &StackError) {
+            self.0.push(format!("error {} {}", address, error));
+        }
+
+        fn on_reset(&mut self) {
+            self.0.push("reset".to_owned());
+        }
+    }
+
+    fn observed(code: &str) -> Vec<::std::string::String> {
+        let code = tokenize(code).unwrap();
+        let mut machine = Machine::<DefaultSideEffect, Events>::new(code).unwrap();
+        let _ = machine.run(vec![]);
+        machine.into_observer().0
+    }
+
+    #[test]
+    fn test_callbacks() {
+        assert_eq!(
+            vec![
+                "step 0 0",
+                "step 1 1",
+                "dispatch 1 Call(4)",
+                "call 1 4 [2]",
+                "step 4 0",
+                "dispatch 4 Return",
+                "return 4 2 []",
+                "step 2 0",
+                "dispatch 2 Stop(0)",
+            ],
+            observed("f call stop f: return")
+        );
+    }
+
+    #[test]
+    fn test_on_error() {
+        assert_eq!(
+            vec!["step 0 0", "error 0 Cannot pop an empty stack, looking for _ in NA"],
+            observed("drop")
+        );
+    }
+
+    #[test]
+    fn test_combined_observers() {
+        let code = tokenize("1 2").unwrap();
+        let observers = (Events::default(), Some(Events::default()));
+        let mut machine = Machine::<DefaultSideEffect, _>::with_observer(code, observers).unwrap();
+        machine.run(vec![]).unwrap();
+        machine.reset();
+        let (first, second) = machine.into_observer();
+        assert_eq!(vec!["step 0 0", "step 1 1", "reset"], first.0);
+        assert_eq!(first.0, second.unwrap().0);
+    }
+
+    #[test]
+    fn test_callbacks_around_provide_input() {
+        let code = tokenize("1 read").unwrap();
+        let mut machine = Machine::<DefaultSideEffect, Events>::with_observer(code, Events::default()).unwrap();
+        machine.pause_on_input(true);
+        assert_eq!(RunStatus::Paused(PauseReason::AwaitingInput), machine.resume().unwrap());
+        assert_eq!(RunStatus::Paused(PauseReason::AwaitingInput), machine.resume().unwrap());
+        assert_eq!(1, machine.steps());
+        machine.provide_input("line".to_owned());
+        assert_eq!(RunStatus::Stopped(0), machine.resume().unwrap());
+        assert_eq!(2, machine.steps());
+        assert_eq!(
+            vec!["step 0 0", "step 1 1", "dispatch 1 ReadLn"],
+            machine.into_observer().0
+        );
+    }
+}
@@ -0,0 +1,292 @@
+//! A profiler for programs running on a `Machine`.
+//!
+//! `Profiler` is an `Observer` counting how many times each instruction
+//! and each `StackOperation` is executed, and timing every `call` by the
+//! label it calls, using the return stack to attribute time to callers
+//! (inclusive) and to the label itself (exclusive).
+//!
+//! `Profiler::folded` produces the "folded stacks" format that tools like
+//! [FlameGraph](https://github.com/brendangregg/FlameGraph) consume, where
+//! the weight of each stack is the number of steps taken in it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use super::*;
+
+/// The name of the stack frame for code that is not inside of any `call`.
+const TOP_LEVEL: &str = "main";
+
+/// Call counts and timings for a single label.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LabelStats {
+    pub calls: u64,
+    /// Time spent from calling the label until it returned. Recursive
+    /// calls are only timed once, by their outermost call.
+    pub inclusive: Duration,
+    /// Inclusive time minus the time spent in the labels it called.
+    pub exclusive: Duration,
+}
+
+#[derive(Debug)]
+struct Frame {
+    label: String,
+    started: Instant,
+    in_children: Duration,
+    /// The length of `stack_key` before this frame was entered.
+    key_len: usize,
+}
+
+/// Profiles the machine observed with it, see the `profile` module.
+#[derive(Debug)]
+pub struct Profiler {
+    /// The names of labels, by the address they point to.
+    label_names: HashMap<usize, String>,
+    instructions: Vec<String>,
+    instruction_labels: Vec<String>,
+    instruction_counts: Vec<u64>,
+    operation_counts: BTreeMap<&'static str, u64>,
+    labels: BTreeMap<String, LabelStats>,
+    frames: Vec<Frame>,
+    stack_key: String,
+    folded: HashMap<String, u64>,
+}
+
+impl Profiler {
+    /// Creates a profiler for `code`, which is what the machine will be
+    /// created with. Either tokenized or preprocessed code works.
+    pub fn new<I: Instruction>(code: &Code<I>) -> Profiler {
+        let mut label_names = HashMap::new();
+        let mut label = String::from(TOP_LEVEL);
+        let mut offset = 0;
+        let mut instruction_labels = Vec::with_capacity(code.len());
+        for (address, value) in code.iter().enumerate() {
+            if let StackValue::Label(ref name) = *value {
+                label_names.insert(address + 1, name.clone());
+                label = name.clone();
+                offset = 0;
+                instruction_labels.push(format!("{}:", name));
+            } else {
+                instruction_labels.push(format!("{}+{}", label, offset));
+                offset += 1;
+            }
+        }
+        Profiler {
+            label_names,
+            instructions: code.iter().map(|value| match *value {
+                StackValue::Operation(ref op) => op.name().to_owned(),
+                ref value => value.to_string(),
+            }).collect(),
+            instruction_labels,
+            instruction_counts: vec![0; code.len()],
+            operation_counts: BTreeMap::new(),
+            labels: BTreeMap::new(),
+            frames: vec![],
+            stack_key: String::from(TOP_LEVEL),
+            folded: HashMap::new(),
+        }
+    }
+
+    fn add_call(labels: &mut BTreeMap<String, LabelStats>, frame: &Frame, elapsed: Duration, recursive: bool) {
+        let stats = labels.entry(frame.label.clone()).or_default();
+        stats.calls += 1;
+        if !recursive {
+            stats.inclusive += elapsed;
+        }
+        stats.exclusive += elapsed.checked_sub(frame.in_children).unwrap_or_default();
+    }
+
+    /// How many times the instruction at each address was executed.
+    pub fn instruction_counts(&self) -> &[u64] {
+        &self.instruction_counts
+    }
+
+    /// How many times each operation was executed, keyed by its source name.
+    pub fn operation_counts(&self) -> &BTreeMap<&'static str, u64> {
+        &self.operation_counts
+    }
+
+    /// Timings for every called label.
+    ///
+    /// Calls that haven't returned yet are counted as if they returned now.
+    pub fn label_stats(&self) -> BTreeMap<String, LabelStats> {
+        let mut labels = self.labels.clone();
+        let now = Instant::now();
+        let mut in_children = Duration::default();
+        for (depth, frame) in self.frames.iter().enumerate().rev() {
+            let elapsed = now.duration_since(frame.started);
+            let recursive = self.frames[..depth].iter().any(|f| f.label == frame.label);
+            let frame = Frame {
+                label: frame.label.clone(),
+                started: frame.started,
+                in_children: frame.in_children + in_children,
+                key_len: frame.key_len,
+            };
+            Self::add_call(&mut labels, &frame, elapsed, recursive);
+            in_children = elapsed;
+        }
+        labels
+    }
+
+    /// The number of steps taken in each call stack, one stack per line as
+    /// `main;caller;callee count`, sorted by stack.
+    pub fn folded(&self) -> String {
+        let mut stacks: Vec<(&String, &u64)> = self.folded.iter().collect();
+        stacks.sort();
+        stacks.iter().map(|(stack, count)| format!("{} {}\n", stack, count)).collect()
+    }
+
+    /// A human readable summary of the profile.
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+
+        report.push_str("Labels (by inclusive time)\n");
+        report.push_str(&format!("{:>10} {:>14} {:>14}  label\n", "calls", "inclusive", "exclusive"));
+        let mut labels: Vec<(String, LabelStats)> = self.label_stats().into_iter().collect();
+        labels.sort_by_key(|&(_, stats)| ::std::cmp::Reverse(stats.inclusive));
+        for (label, stats) in labels {
+            report.push_str(&format!(
+                "{:>10} {:>14?} {:>14?}  {}\n",
+                stats.calls, stats.inclusive, stats.exclusive, label
+            ));
+        }
+
+        report.push_str("\nOperations (by count)\n");
+        let mut operations: Vec<(&&str, &u64)> = self.operation_counts.iter().collect();
+        operations.sort_by(|a, b| b.1.cmp(a.1));
+        for (name, count) in operations {
+            report.push_str(&format!("{:>10}  {}\n", count, name));
+        }
+
+        report.push_str("\nInstructions (by count)\n");
+        let mut addresses: Vec<usize> = (0..self.instruction_counts.len())
+            .filter(|&address| self.instruction_counts[address] > 0)
+            .collect();
+        addresses.sort_by(|&a, &b| self.instruction_counts[b].cmp(&self.instruction_counts[a]).then(a.cmp(&b)));
+        for address in addresses {
+            report.push_str(&format!(
+                "{:>10}  {:>6} {:<16} {}\n",
+                self.instruction_counts[address],
+                address,
+                self.instruction_labels[address],
+                self.instructions[address]
+            ));
+        }
+
+        report
+    }
+}
+
+impl Observer for Profiler {
+    fn before_step<I: Instruction>(&mut self, address: usize, instruction: &StackValue<I>, _stack: &[StackValue<I>]) {
+        // Instructions outside of the profiled code are ignored.
+        if let Some(count) = self.instruction_counts.get_mut(address) {
+            *count += 1;
+        }
+        if let StackValue::Operation(ref op) = *instruction {
+            *self.operation_counts.entry(op.name()).or_insert(0) += 1;
+        }
+        // Only allocate a key the first time a call stack is seen.
+        if let Some(count) = self.folded.get_mut(&self.stack_key) {
+            *count += 1;
+        } else {
+            self.folded.insert(self.stack_key.clone(), 1);
+        }
+    }
+
+    fn on_call(&mut self, _address: usize, to: usize, _return_stack: &[usize]) {
+        let label = match self.label_names.get(&to) {
+            Some(name) => name.clone(),
+            None => format!("@{}", to),
+        };
+        self.frames.push(Frame {
+            label: label.clone(),
+            started: Instant::now(),
+            in_children: Duration::default(),
+            key_len: self.stack_key.len(),
+        });
+        self.stack_key.push(';');
+        self.stack_key.push_str(&label);
+    }
+
+    fn on_return(&mut self, _address: usize, _to: usize, _return_stack: &[usize]) {
+        if let Some(frame) = self.frames.pop() {
+            let elapsed = frame.started.elapsed();
+            self.stack_key.truncate(frame.key_len);
+            let recursive = self.frames.iter().any(|f| f.label == frame.label);
+            Self::add_call(&mut self.labels, &frame, elapsed, recursive);
+            if let Some(parent) = self.frames.last_mut() {
+                parent.in_children += elapsed;
+            }
+        }
+    }
+
+    /// Ends every call that hasn't returned yet, as the return stack is
+    /// cleared. Counts are kept so that runs add up.
+    fn on_reset(&mut self) {
+        self.labels = self.label_stats();
+        self.frames.clear();
+        self.stack_key = String::from(TOP_LEVEL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn profiled(code: &str, args: Vec<StackValue>) -> Profiler {
+        let code = tokenize(code).unwrap();
+        let profiler = Profiler::new(&code);
+        let mut machine = Machine::<DefaultSideEffect, _>::with_observer(code, profiler).unwrap();
+        machine.run(args).unwrap();
+        machine.into_observer()
+    }
+
+    #[test]
+    fn test_counts() {
+        let profile = profiled("0 loop: 1 + dup 3 < loop end if jmp end:", vec![]);
+        assert_eq!(&[1, 1, 3, 3, 3, 3, 3, 3, 3, 3, 3, 0], profile.instruction_counts());
+        assert_eq!(Some(&3), profile.operation_counts().get("+"));
+        assert_eq!(Some(&3), profile.operation_counts().get("jmp"));
+        assert_eq!(None, profile.operation_counts().get("call"));
+    }
+
+    #[test]
+    fn test_label_stats_and_folded_stacks() {
+        let profile = profiled(
+            include_str!("../examples/fib_no_print"),
+            vec![StackValue::Num(4)],
+        );
+        let stats = profile.label_stats();
+        assert_eq!(vec!["end", "fib", "inner"], stats.keys().collect::<Vec<_>>());
+        assert_eq!(9, stats["fib"].calls);
+        assert_eq!(5, stats["end"].calls);
+        assert_eq!(4, stats["inner"].calls);
+        assert!(stats["fib"].exclusive <= stats["fib"].inclusive);
+        assert!(stats["inner"].inclusive <= stats["fib"].inclusive);
+
+        let folded = profile.folded();
+        let lines: Vec<&str> = folded.lines().collect();
+        assert_eq!("main 3", lines[0]);
+        assert!(lines.iter().any(|line| line.starts_with("main;fib;inner;fib;inner;fib;end ")));
+    }
+
+    #[test]
+    fn test_nested_calls() {
+        let profile = profiled("a call stop a: b call b call return b: return", vec![]);
+        let stats = profile.label_stats();
+        assert_eq!(1, stats["a"].calls);
+        assert_eq!(2, stats["b"].calls);
+        assert!(stats["a"].inclusive >= stats["b"].inclusive);
+        assert_eq!("main 3\nmain;a 5\nmain;a;b 2\n", profile.folded());
+    }
+
+    #[test]
+    fn test_ignores_addresses_outside_the_code() {
+        let profiler = Profiler::new(&tokenize("1").unwrap());
+        let mut machine = Machine::<DefaultSideEffect, _>::with_observer(tokenize("1 2").unwrap(), profiler).unwrap();
+        machine.run(vec![]).unwrap();
+        assert_eq!(&[1], machine.into_observer().instruction_counts());
+    }
+}
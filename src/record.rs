@@ -0,0 +1,465 @@
+//! Recording the interactions of a program with its `SideEffect`, and
+//! replaying them to reproduce a run exactly.
+//!
+//! A `Recorder` wraps an effect and writes a line for every call made to
+//! it, as it happens. A `Replayer` reads those lines back, returning the
+//! recorded results instead of doing any I/O and failing as soon as the
+//! program does something different from the recorded run. A recording
+//! looks like this:
+//!
+//! ```text
+//! simple_vm recording v1
+//! println "What's your name?" ->
+//! read_line -> "alice"
+//! sleep_ms "100" ->
+//! read_file "names.txt" -> error "No such file or directory (os error 2)"
+//! ```
+//!
+//! Every line is the method called and its arguments, then `->` and what
+//! it returned, or `error` and the message of the error it failed with.
+//! Arguments and results are quoted like strings in snapshots. Errors are
+//! replayed with their message but not their kind, so a `Sandbox` should
+//! wrap the `Recorder` rather than the other way around.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Write};
+
+use super::*;
+use snapshot::{quote, unquote};
+
+const HEADER: &str = "simple_vm recording v1";
+
+/// A single call made to a `SideEffect`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Interaction {
+    /// The name of the method, like `read_line`.
+    pub method: String,
+    /// The arguments, with the value for `println` as it is printed.
+    pub args: Vec<String>,
+    /// The values it returned, or the message of its error.
+    pub result: Result<Vec<String>, String>,
+}
+
+impl Interaction {
+    fn new(method: &str, args: &[&str]) -> Interaction {
+        Interaction {
+            method: method.to_owned(),
+            args: args.iter().map(|&arg| arg.to_owned()).collect(),
+            result: Ok(vec![]),
+        }
+    }
+
+    /// The call, without its result.
+    fn call(&self) -> String {
+        let mut call = self.method.clone();
+        for arg in &self.args {
+            call.push(' ');
+            call.push_str(&quote(arg));
+        }
+        call
+    }
+}
+
+impl fmt::Display for Interaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ->", self.call())?;
+        match self.result {
+            Ok(ref values) => values.iter().try_for_each(|value| write!(f, " {}", quote(value))),
+            Err(ref message) => write!(f, " error {}", quote(message)),
+        }
+    }
+}
+
+fn invalid(reason: &str) -> StackError {
+    StackError::InvalidRecording { reason: reason.to_owned() }
+}
+
+/// Splits a line into its bare words and quoted strings, unquoting the
+/// strings. Bare words are returned as `Err` to tell them apart.
+fn split(line: &str) -> Result<Vec<Result<String, &str>>, StackError> {
+    let mut fields = vec![];
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        let end = if rest.starts_with('"') {
+            let mut escaped = false;
+            let close = rest.char_indices().skip(1).find(|&(_, c)| {
+                let found = !escaped && c == '"';
+                escaped = !escaped && c == '\\';
+                found
+            });
+            match close {
+                Some((idx, _)) => idx + 1,
+                None => return Err(invalid(&format!("unterminated string in \"{}\"", line))),
+            }
+        } else {
+            rest.find(' ').unwrap_or(rest.len())
+        };
+        let (field, tail) = rest.split_at(end);
+        fields.push(if field.starts_with('"') {
+            Ok(unquote(field).map_err(|_| invalid(&format!("bad string {}", field)))?)
+        } else {
+            Err(field)
+        });
+        rest = tail.trim_start();
+    }
+    Ok(fields)
+}
+
+/// Parses a recording made by a `Recorder`.
+pub fn parse_recording(recording: &str) -> Result<Vec<Interaction>, StackError> {
+    let mut lines = recording.lines();
+    if lines.next() != Some(HEADER) {
+        return Err(invalid("missing the recording header"));
+    }
+    lines.filter(|line| !line.is_empty()).map(|line| {
+        let malformed = || invalid(&format!("malformed line \"{}\"", line));
+        let mut fields = split(line)?.into_iter();
+        let method = match fields.next() {
+            Some(Err(method)) => method.to_owned(),
+            _ => return Err(malformed()),
+        };
+        let mut args = vec![];
+        loop {
+            match fields.next() {
+                Some(Ok(arg)) => args.push(arg),
+                Some(Err("->")) => break,
+                _ => return Err(malformed()),
+            }
+        }
+        let fields: Vec<_> = fields.collect();
+        let result = match fields.split_first() {
+            Some((&Err("error"), &[Ok(ref message)])) => Err(message.clone()),
+            _ => Ok(fields.into_iter().collect::<Result<_, _>>().map_err(|_| malformed())?),
+        };
+        Ok(Interaction { method, args, result })
+    }).collect()
+}
+
+/// A `SideEffect` that writes every call made to the effect it wraps to
+/// `writer`, see the `record` module.
+///
+/// Each line is flushed as soon as it is written, so nothing is lost if
+/// the program fails. If writing fails, the call fails with that error.
+/// Input given with `Machine::provide_input` doesn't go through the
+/// effect, so it isn't recorded.
+pub struct Recorder<E, W> {
+    effect: E,
+    writer: W,
+}
+
+impl<E: SideEffect, W: Write> Recorder<E, W> {
+    pub fn new(effect: E, mut writer: W) -> io::Result<Recorder<E, W>> {
+        writeln!(writer, "{}", HEADER)?;
+        writer.flush()?;
+        Ok(Recorder { effect, writer })
+    }
+
+    /// The wrapped `SideEffect`.
+    pub fn inner(&self) -> &E {
+        &self.effect
+    }
+
+    pub fn inner_mut(&mut self) -> &mut E {
+        &mut self.effect
+    }
+
+    pub fn into_writer(self) -> W {
+        self.writer
+    }
+
+    /// Writes the call along with its `result`, converted with `values`,
+    /// and passes the result on.
+    fn record<T, F>(&mut self, mut interaction: Interaction, result: io::Result<T>, values: F) -> io::Result<T>
+    where
+        F: FnOnce(&T) -> Vec<String>,
+    {
+        interaction.result = match result {
+            Ok(ref value) => Ok(values(value)),
+            Err(ref error) => Err(error.to_string()),
+        };
+        writeln!(self.writer, "{}", interaction)?;
+        self.writer.flush()?;
+        result
+    }
+}
+
+impl<E: SideEffect, W: Write> SideEffect for Recorder<E, W> {
+    fn println<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        let interaction = Interaction::new("println", &[&value.to_string()]);
+        let result = self.effect.println(value);
+        self.record(interaction, result, |_| vec![])
+    }
+
+    fn eprintln<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        let interaction = Interaction::new("eprintln", &[&value.to_string()]);
+        let result = self.effect.eprintln(value);
+        self.record(interaction, result, |_| vec![])
+    }
+
+    fn print<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        let interaction = Interaction::new("print", &[&value.to_string()]);
+        let result = self.effect.print(value);
+        self.record(interaction, result, |_| vec![])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.effect.flush();
+        self.record(Interaction::new("flush", &[]), result, |_| vec![])
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let result = self.effect.read_line();
+        self.record(Interaction::new("read_line", &[]), result, |line| vec![line.clone()])
+    }
+
+    fn sleep_ms(&mut self, duration: u64) -> io::Result<()> {
+        let result = self.effect.sleep_ms(duration);
+        self.record(Interaction::new("sleep_ms", &[&duration.to_string()]), result, |_| vec![])
+    }
+
+    fn open(&mut self, path: &str) -> io::Result<()> {
+        let result = self.effect.open(path);
+        self.record(Interaction::new("open", &[path]), result, |_| vec![])
+    }
+
+    fn read_file(&mut self, path: &str) -> io::Result<String> {
+        let result = self.effect.read_file(path);
+        self.record(Interaction::new("read_file", &[path]), result, |contents| vec![contents.clone()])
+    }
+
+    fn write_file(&mut self, path: &str, contents: &str) -> io::Result<()> {
+        let result = self.effect.write_file(path, contents);
+        self.record(Interaction::new("write_file", &[path, contents]), result, |_| vec![])
+    }
+
+    fn append_file(&mut self, path: &str, contents: &str) -> io::Result<()> {
+        let result = self.effect.append_file(path, contents);
+        self.record(Interaction::new("append_file", &[path, contents]), result, |_| vec![])
+    }
+
+    fn exists(&mut self, path: &str) -> io::Result<bool> {
+        let result = self.effect.exists(path);
+        self.record(Interaction::new("exists", &[path]), result, |exists| vec![exists.to_string()])
+    }
+
+    fn list_dir(&mut self, path: &str) -> io::Result<Vec<String>> {
+        let result = self.effect.list_dir(path);
+        self.record(Interaction::new("list_dir", &[path]), result, |names| names.clone())
+    }
+
+    fn env_var(&mut self, name: &str) -> io::Result<Option<String>> {
+        let result = self.effect.env_var(name);
+        self.record(Interaction::new("env_var", &[name]), result, |value| value.iter().cloned().collect())
+    }
+
+    fn now_ms(&mut self) -> io::Result<u64> {
+        let result = self.effect.now_ms();
+        self.record(Interaction::new("now_ms", &[]), result, |now| vec![now.to_string()])
+    }
+}
+
+/// A `SideEffect` that plays back a recording, see the `record` module.
+///
+/// Calls fail if they don't match the next recorded one. A replayer
+/// made with `default` has an empty recording.
+#[derive(Debug, Default)]
+pub struct Replayer {
+    interactions: VecDeque<Interaction>,
+}
+
+impl Replayer {
+    pub fn new(recording: &str) -> Result<Replayer, StackError> {
+        Ok(Replayer::from_interactions(parse_recording(recording)?))
+    }
+
+    pub fn from_interactions(interactions: Vec<Interaction>) -> Replayer {
+        Replayer { interactions: interactions.into() }
+    }
+
+    /// The recorded calls that haven't been replayed yet.
+    pub fn remaining(&self) -> &VecDeque<Interaction> {
+        &self.interactions
+    }
+
+    /// Takes the next recorded call, checking that it is this one, and
+    /// returns its result converted with `value`.
+    fn replay<T, F>(&mut self, method: &str, args: &[&str], value: F) -> io::Result<T>
+    where
+        F: FnOnce(&[String]) -> Option<T>,
+    {
+        let call = Interaction::new(method, args);
+        let recorded = match self.interactions.pop_front() {
+            Some(recorded) => recorded,
+            None => return Err(diverged(format!("the recording ended, but the program called {}", call.call()))),
+        };
+        if recorded.method != call.method || recorded.args != call.args {
+            return Err(diverged(format!("expected {} but the program called {}", recorded.call(), call.call())));
+        }
+        match recorded.result {
+            Ok(ref values) => value(values).ok_or_else(|| diverged(format!("malformed result in {}", recorded))),
+            Err(message) => Err(io::Error::other(message)),
+        }
+    }
+}
+
+fn diverged(message: String) -> io::Error {
+    io::Error::other(format!("replay diverged: {}", message))
+}
+
+fn none(values: &[String]) -> Option<()> {
+    if values.is_empty() {
+        Some(())
+    } else {
+        None
+    }
+}
+
+fn single(values: &[String]) -> Option<&String> {
+    match values {
+        [value] => Some(value),
+        _ => None,
+    }
+}
+
+impl SideEffect for Replayer {
+    fn println<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        self.replay("println", &[&value.to_string()], none)
+    }
+
+    fn eprintln<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        self.replay("eprintln", &[&value.to_string()], none)
+    }
+
+    fn print<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        self.replay("print", &[&value.to_string()], none)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.replay("flush", &[], none)
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        self.replay("read_line", &[], |values| single(values).cloned())
+    }
+
+    /// Doesn't actually sleep, replaying as fast as possible.
+    fn sleep_ms(&mut self, duration: u64) -> io::Result<()> {
+        self.replay("sleep_ms", &[&duration.to_string()], none)
+    }
+
+    fn open(&mut self, path: &str) -> io::Result<()> {
+        self.replay("open", &[path], none)
+    }
+
+    fn read_file(&mut self, path: &str) -> io::Result<String> {
+        self.replay("read_file", &[path], |values| single(values).cloned())
+    }
+
+    fn write_file(&mut self, path: &str, contents: &str) -> io::Result<()> {
+        self.replay("write_file", &[path, contents], none)
+    }
+
+    fn append_file(&mut self, path: &str, contents: &str) -> io::Result<()> {
+        self.replay("append_file", &[path, contents], none)
+    }
+
+    fn exists(&mut self, path: &str) -> io::Result<bool> {
+        self.replay("exists", &[path], |values| single(values).and_then(|value| value.parse().ok()))
+    }
+
+    fn list_dir(&mut self, path: &str) -> io::Result<Vec<String>> {
+        self.replay("list_dir", &[path], |values| Some(values.to_vec()))
+    }
+
+    fn env_var(&mut self, name: &str) -> io::Result<Option<String>> {
+        self.replay("env_var", &[name], |values| match values {
+            [] => Some(None),
+            [value] => Some(Some(value.clone())),
+            _ => None,
+        })
+    }
+
+    fn now_ms(&mut self) -> io::Result<u64> {
+        self.replay("now_ms", &[], |values| single(values).and_then(|value| value.parse().ok()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use testing::ScriptedEffect;
+
+    const PROGRAM: &str = "\"name?\" println read dup println \"zzz\" eprintln 100 sleep_ms \"a \\\"b\\\"\" read_file";
+
+    fn record(code: &str) -> (Result<RunStatus, StackError>, String) {
+        let recorder = Recorder::new(ScriptedEffect::with_input(vec!["alice"]), vec![]).unwrap();
+        let mut machine = Machine::<_>::with_effect(tokenize(code).unwrap(), recorder).unwrap();
+        let status = machine.run(vec![]);
+        let recording = machine.into_effect().into_writer();
+        (status, std::string::String::from_utf8(recording).unwrap())
+    }
+
+    fn replay(code: &str, recording: &str) -> (Result<RunStatus, StackError>, Machine<Replayer>) {
+        let replayer = Replayer::new(recording).unwrap();
+        let mut machine = Machine::<_>::with_effect(tokenize(code).unwrap(), replayer).unwrap();
+        (machine.run(vec![]), machine)
+    }
+
+    #[test]
+    fn test_record() {
+        let (status, recording) = record(PROGRAM);
+        assert!(status.is_err());
+        let expected = "\
+simple_vm recording v1
+println \"name?\" ->
+read_line -> \"alice\"
+println \"alice\" ->
+eprintln \"zzz\" ->
+sleep_ms \"100\" ->
+read_file \"a \\\"b\\\"\" -> error \"not found\"
+";
+        assert_eq!(expected, recording);
+    }
+
+    #[test]
+    fn test_replay() {
+        let (_, recording) = record(PROGRAM);
+        let (status, machine) = replay(PROGRAM, &recording);
+        match status {
+            Err(StackError::Io { operation, message }) => {
+                assert_eq!("read_file", operation);
+                assert_eq!("a \"b\": not found", message);
+            }
+            other => panic!("expected the recorded error, got {:?}", other),
+        }
+        assert!(machine.effect().remaining().is_empty());
+        assert_eq!(vec![StackValue::String("alice".to_owned())], machine.stack());
+    }
+
+    #[test]
+    fn test_replay_diverges() {
+        let (_, recording) = record(PROGRAM);
+        match replay("\"name?\" println \"hi\" println", &recording).0 {
+            Err(StackError::Io { operation, message }) => {
+                assert_eq!("println", operation);
+                assert_eq!("replay diverged: expected read_line but the program called println \"hi\"", message);
+            }
+            other => panic!("expected a divergence, got {:?}", other),
+        }
+        let (status, machine) = replay("\"name?\" println", &recording);
+        assert_eq!(RunStatus::Stopped(0), status.unwrap());
+        assert_eq!(5, machine.effect().remaining().len());
+    }
+
+    #[test]
+    fn test_parse_recording() {
+        let interactions = parse_recording("simple_vm recording v1\nlist_dir \".\" -> \"a b\" \"c\"\nenv_var \"X\" ->\n").unwrap();
+        assert_eq!(Ok(vec!["a b".to_owned(), "c".to_owned()]), interactions[0].result);
+        assert_eq!(Ok(vec![]), interactions[1].result);
+        assert!(parse_recording("println \"a\" ->").is_err());
+        assert!(parse_recording("simple_vm recording v1\nprintln \"a").is_err());
+        assert!(parse_recording("simple_vm recording v1\nprintln \"a\"").is_err());
+    }
+}
@@ -0,0 +1,330 @@
+//! A `SideEffect` that only lets programs do what the host allows.
+//!
+//! ```
+//! use simple_vm::*;
+//! use simple_vm::error::StackError;
+//!
+//! let capabilities = Capabilities { stdout: true, ..Capabilities::default() };
+//! let effect = Sandbox::new(DefaultSideEffect::default(), capabilities);
+//! let code = tokenize("\"hi\" println \"/etc/passwd\" read_file").unwrap();
+//! let mut machine = Machine::<_>::with_effect(code, effect).unwrap();
+//! match machine.run(vec![]) {
+//!     Err(StackError::PermissionDenied { operation, .. }) => assert_eq!("read_file", operation),
+//!     _ => panic!("expected PermissionDenied"),
+//! }
+//! ```
+
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::*;
+
+/// What a `Sandbox` allows. Everything is denied by default.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Capabilities {
+    /// Writing with `println`, `print` and `flush`.
+    pub stdout: bool,
+    /// Writing with `eprintln`.
+    pub stderr: bool,
+    /// Reading lines with `read`, from stdin. Reading lines from a
+    /// file after `open` only needs access to the file, until it runs out.
+    pub stdin: bool,
+    /// Sleeping with `sleep_ms`.
+    pub sleep: bool,
+    /// Reading the time with `now_ms`.
+    pub clock: bool,
+    /// The environment variables `env` can read.
+    pub env_vars: Vec<String>,
+    /// Directories that files can be read from, including subdirectories.
+    pub read_roots: Vec<PathBuf>,
+    /// Directories that files can be written to, and read from, including subdirectories.
+    pub write_roots: Vec<PathBuf>,
+}
+
+/// Wraps a `SideEffect`, failing the operations its `Capabilities`
+/// don't allow with `StackError::PermissionDenied`.
+pub struct Sandbox<E> {
+    effect: E,
+    capabilities: Capabilities,
+    /// The lines left in the file that was allowed to be `open`ed, which
+    /// `read_line` reads before asking the wrapped effect. The sandbox reads
+    /// the file itself, since it can't tell where the effect's lines come from.
+    opened: VecDeque<String>,
+}
+
+/// The error a `Sandbox` fails denied operations with, which the
+/// machine turns into `StackError::PermissionDenied`.
+#[derive(Debug)]
+pub(crate) struct Denied(pub(crate) String);
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for Denied {}
+
+fn denied(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, Denied(reason))
+}
+
+fn check(allowed: bool, capability: &str) -> io::Result<()> {
+    if allowed {
+        Ok(())
+    } else {
+        Err(denied(format!("{} is not allowed", capability)))
+    }
+}
+
+/// Makes `path` absolute with every symlink resolved, so that it can be
+/// compared with the roots. The part of it that doesn't exist yet can
+/// only be plain names, since `..` could lead anywhere once it does.
+fn resolve(path: &str) -> io::Result<PathBuf> {
+    let absolute = env::current_dir()?.join(path);
+    let mut existing: &Path = &absolute;
+    let mut missing = vec![];
+    loop {
+        if let Ok(mut resolved) = existing.canonicalize() {
+            resolved.extend(missing.iter().rev());
+            return Ok(resolved);
+        }
+        match (existing.file_name(), existing.parent()) {
+            (Some(name), Some(parent)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => return Err(denied(format!("{} can't be resolved", path))),
+        }
+    }
+}
+
+fn is_under(path: &Path, roots: &[PathBuf]) -> bool {
+    roots.iter().any(|root| root.canonicalize().is_ok_and(|root| path.starts_with(root)))
+}
+
+impl<E: SideEffect> Sandbox<E> {
+    pub fn new(effect: E, capabilities: Capabilities) -> Sandbox<E> {
+        Sandbox {
+            effect,
+            capabilities,
+            opened: VecDeque::new(),
+        }
+    }
+
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// The wrapped `SideEffect`.
+    pub fn inner(&self) -> &E {
+        &self.effect
+    }
+
+    pub fn inner_mut(&mut self) -> &mut E {
+        &mut self.effect
+    }
+
+    pub fn into_inner(self) -> E {
+        self.effect
+    }
+
+    fn check_read(&self, path: &str) -> io::Result<()> {
+        let resolved = resolve(path)?;
+        if is_under(&resolved, &self.capabilities.read_roots) || is_under(&resolved, &self.capabilities.write_roots) {
+            Ok(())
+        } else {
+            Err(denied(format!("reading {} is not allowed", path)))
+        }
+    }
+
+    fn check_write(&self, path: &str) -> io::Result<()> {
+        if is_under(&resolve(path)?, &self.capabilities.write_roots) {
+            Ok(())
+        } else {
+            Err(denied(format!("writing {} is not allowed", path)))
+        }
+    }
+}
+
+impl<E: SideEffect> SideEffect for Sandbox<E> {
+    fn println<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        check(self.capabilities.stdout, "stdout")?;
+        self.effect.println(value)
+    }
+
+    fn eprintln<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        check(self.capabilities.stderr, "stderr")?;
+        self.effect.eprintln(value)
+    }
+
+    fn print<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        check(self.capabilities.stdout, "stdout")?;
+        self.effect.print(value)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        check(self.capabilities.stdout, "stdout")?;
+        self.effect.flush()
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        if let Some(line) = self.opened.pop_front() {
+            return Ok(line);
+        }
+        check(self.capabilities.stdin, "stdin")?;
+        self.effect.read_line()
+    }
+
+    fn sleep_ms(&mut self, duration: u64) -> io::Result<()> {
+        check(self.capabilities.sleep, "sleeping")?;
+        self.effect.sleep_ms(duration)
+    }
+
+    fn open(&mut self, path: &str) -> io::Result<()> {
+        self.check_read(path)?;
+        let contents = self.effect.read_file(path)?;
+        self.opened = contents.lines().map(|line| line.trim().to_owned()).collect();
+        Ok(())
+    }
+
+    fn read_file(&mut self, path: &str) -> io::Result<String> {
+        self.check_read(path)?;
+        self.effect.read_file(path)
+    }
+
+    fn write_file(&mut self, path: &str, contents: &str) -> io::Result<()> {
+        self.check_write(path)?;
+        self.effect.write_file(path, contents)
+    }
+
+    fn append_file(&mut self, path: &str, contents: &str) -> io::Result<()> {
+        self.check_write(path)?;
+        self.effect.append_file(path, contents)
+    }
+
+    fn exists(&mut self, path: &str) -> io::Result<bool> {
+        self.check_read(path)?;
+        self.effect.exists(path)
+    }
+
+    fn list_dir(&mut self, path: &str) -> io::Result<Vec<String>> {
+        self.check_read(path)?;
+        self.effect.list_dir(path)
+    }
+
+    fn env_var(&mut self, name: &str) -> io::Result<Option<String>> {
+        check(self.capabilities.env_vars.iter().any(|var| var == name), &format!("reading ${}", name))?;
+        self.effect.env_var(name)
+    }
+
+    fn now_ms(&mut self) -> io::Result<u64> {
+        check(self.capabilities.clock, "the clock")?;
+        self.effect.now_ms()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs;
+    use testing::ScriptedEffect;
+
+    fn run(code: &str, capabilities: Capabilities) -> Result<Machine<Sandbox<DefaultSideEffect>>, StackError> {
+        let effect = Sandbox::new(DefaultSideEffect::default(), capabilities);
+        let mut machine = Machine::<_>::with_effect(tokenize(code).unwrap(), effect)?;
+        machine.run(vec![])?;
+        Ok(machine)
+    }
+
+    fn denied_operation(result: Result<Machine<Sandbox<DefaultSideEffect>>, StackError>) -> String {
+        match result {
+            Err(StackError::PermissionDenied { operation, .. }) => operation,
+            Err(error) => panic!("expected PermissionDenied, got {:?}", error),
+            Ok(_) => panic!("expected PermissionDenied"),
+        }
+    }
+
+    #[test]
+    fn test_denies_by_default() {
+        let none = Capabilities::default;
+        assert_eq!("println", denied_operation(run("1 println", none())));
+        assert_eq!("eprintln", denied_operation(run("1 eprintln", none())));
+        assert_eq!("print", denied_operation(run("1 print", none())));
+        assert_eq!("flush", denied_operation(run("flush", none())));
+        assert_eq!("read", denied_operation(run("read", none())));
+        assert_eq!("sleep_ms", denied_operation(run("1 sleep_ms", none())));
+        assert_eq!("now_ms", denied_operation(run("now_ms", none())));
+        assert_eq!("env", denied_operation(run("\"HOME\" env", none())));
+        assert_eq!("exists", denied_operation(run("\".\" exists", none())));
+    }
+
+    #[test]
+    fn test_allows_capabilities() {
+        let capabilities = Capabilities {
+            sleep: true,
+            clock: true,
+            env_vars: vec!["SIMPLE_VM_SANDBOX_TEST".to_owned()],
+            ..Capabilities::default()
+        };
+        env::set_var("SIMPLE_VM_SANDBOX_TEST", "yes");
+        let machine = run("0 sleep_ms now_ms \"SIMPLE_VM_SANDBOX_TEST\" env", capabilities.clone()).unwrap();
+        assert_eq!(StackValue::String("yes".to_owned()), machine.stack()[1]);
+        assert_eq!("env", denied_operation(run("\"PATH\" env", capabilities)));
+    }
+
+    #[test]
+    fn test_file_roots() {
+        let dir = env::temp_dir().join(format!("simple_vm_sandbox_{}", std::process::id()));
+        let data = dir.join("data");
+        let out = dir.join("out");
+        fs::create_dir_all(&data).unwrap();
+        fs::create_dir_all(&out).unwrap();
+        fs::write(data.join("in.txt"), "hello").unwrap();
+        let capabilities = Capabilities {
+            read_roots: vec![data.clone()],
+            write_roots: vec![out.clone()],
+            ..Capabilities::default()
+        };
+        let path = |path: &Path| path.to_str().unwrap().to_owned();
+        let code = format!(
+            "\"{}\" read_file \"{}\" write_file \"{}\" open read",
+            path(&data.join("in.txt")),
+            path(&out.join("copy.txt")),
+            path(&out.join("copy.txt")),
+        );
+        let machine = run(&code, capabilities.clone()).unwrap();
+        assert_eq!(vec![StackValue::String("hello".to_owned())], machine.stack());
+        assert_eq!("hello", fs::read_to_string(out.join("copy.txt")).unwrap());
+
+        let write_to_data = format!("\"x\" \"{}\" write_file", path(&data.join("new.txt")));
+        assert_eq!("write_file", denied_operation(run(&write_to_data, capabilities.clone())));
+        let escape = format!("\"{}\" read_file", path(&data.join("..").join("out").join("..").join("..")));
+        assert_eq!("read_file", denied_operation(run(&escape, capabilities.clone())));
+        let missing_escape = format!("\"{}\" exists", path(&data.join("nope").join("..").join("..")));
+        assert_eq!("exists", denied_operation(run(&missing_escape, capabilities)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stdin_is_checked_once_the_opened_file_runs_out() {
+        let path = env::temp_dir().join("simple_vm_sandbox_scripted.txt");
+        let path = path.to_str().unwrap();
+        let mut scripted = ScriptedEffect::with_input(vec!["from stdin"]);
+        scripted.files.insert(path.to_owned(), "a\nb".to_owned());
+        let capabilities = Capabilities { read_roots: vec![env::temp_dir()], ..Capabilities::default() };
+        let code = format!("\"{}\" open read read read", path);
+        let mut machine = Machine::<_>::with_effect(tokenize(&code).unwrap(), Sandbox::new(scripted, capabilities)).unwrap();
+        match machine.run(vec![]) {
+            Err(StackError::PermissionDenied { operation, .. }) => assert_eq!("read", operation),
+            other => panic!("expected PermissionDenied, got {:?}", other),
+        }
+        assert_eq!(vec![StackValue::String("a".to_owned()), StackValue::String("b".to_owned())], machine.stack());
+        assert_eq!(1, machine.effect().inner().input.len());
+    }
+}
@@ -1,37 +1,186 @@
-use super::StackValue;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-/// A trait that can be constructed using `::default()`
-/// that is used for dependency injection of IO-like operations
-/// in the vm.
-pub trait SideEffect: Default {
+use super::{Instruction, StackValue};
+
+/// A trait that is used for dependency injection of IO-like operations
+/// in the vm. Machines are given an instance with `Machine::with_effect`,
+/// so effects can hold their own state, or are created with `::default()`
+/// by `Machine::new`.
+///
+/// The stderr, print, file, environment and clock operations are unsupported
+/// unless they are implemented, so effects that don't provide them can leave them out.
+pub trait SideEffect {
     /// Writes a line to stdout (or elsewhere)
-    fn println(&mut self, value: StackValue);
+    fn println<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()>;
     /// Reads a line from stdin (or elsewhere)
-    fn read_line(&mut self) -> String;
+    fn read_line(&mut self) -> io::Result<String>;
     /// Sleeps a given number of ms
-    fn sleep_ms(&mut self, duration: u64);
+    fn sleep_ms(&mut self, duration: u64) -> io::Result<()>;
+
+    /// Writes a line to stderr, for diagnostics that shouldn't mix with the output.
+    fn eprintln<I: Instruction>(&mut self, _value: StackValue<I>) -> io::Result<()> {
+        Err(unsupported())
+    }
+    /// Writes to stdout without ending the line.
+    fn print<I: Instruction>(&mut self, _value: StackValue<I>) -> io::Result<()> {
+        Err(unsupported())
+    }
+    /// Flushes what has been written to stdout. Does nothing unless implemented,
+    /// for effects that don't buffer their output.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Makes `read_line` read from the file at `path` instead.
+    fn open(&mut self, _path: &str) -> io::Result<()> {
+        Err(unsupported())
+    }
+    /// Reads the whole file at `path`.
+    fn read_file(&mut self, _path: &str) -> io::Result<String> {
+        Err(unsupported())
+    }
+    /// Replaces the contents of the file at `path`, creating it if needed.
+    fn write_file(&mut self, _path: &str, _contents: &str) -> io::Result<()> {
+        Err(unsupported())
+    }
+    /// Appends to the file at `path`, creating it if needed.
+    fn append_file(&mut self, _path: &str, _contents: &str) -> io::Result<()> {
+        Err(unsupported())
+    }
+    /// Whether there is a file or directory at `path`.
+    fn exists(&mut self, _path: &str) -> io::Result<bool> {
+        Err(unsupported())
+    }
+    /// The names of the entries in the directory at `path`, in any order.
+    fn list_dir(&mut self, _path: &str) -> io::Result<Vec<String>> {
+        Err(unsupported())
+    }
+    /// The value of the environment variable `name`, if it is set.
+    fn env_var(&mut self, _name: &str) -> io::Result<Option<String>> {
+        Err(unsupported())
+    }
+    /// The number of ms since the Unix epoch.
+    fn now_ms(&mut self) -> io::Result<u64> {
+        Err(unsupported())
+    }
+}
+
+fn unsupported() -> io::Error {
+    io::Error::other("not supported by this side effect")
 }
 
 /// This is the default sideffect,
 /// reading from STDIN, using `println!`,
-/// and regular ol' sleep.
+/// and regular ol' sleep, with files on the local filesystem.
 #[derive(Default)]
-pub struct DefaultSideEffect;
+pub struct DefaultSideEffect {
+    /// The file `read_line` reads from after `open`.
+    input: Option<BufReader<File>>,
+}
 
 impl SideEffect for DefaultSideEffect {
-    fn read_line(&mut self) -> String {
+    fn read_line(&mut self) -> io::Result<String> {
         let mut input = String::new();
-        ::std::io::stdin().read_line(&mut input).unwrap();
-        input.trim().to_owned()
+        match self.input {
+            Some(ref mut file) => file.read_line(&mut input)?,
+            None => io::stdin().read_line(&mut input)?,
+        };
+        Ok(input.trim().to_owned())
     }
 
-    fn sleep_ms(&mut self, duration: u64) {
+    fn sleep_ms(&mut self, duration: u64) -> io::Result<()> {
         use std::thread;
         use std::time;
-        thread::sleep(time::Duration::from_millis(duration))
+        thread::sleep(time::Duration::from_millis(duration));
+        Ok(())
+    }
+
+    fn println<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        writeln!(io::stdout(), "{}", value)
+    }
+
+    fn eprintln<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        writeln!(io::stderr(), "{}", value)
     }
 
-    fn println(&mut self, value: StackValue) {
-        println!("{}", value);
+    fn print<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        write!(io::stdout(), "{}", value)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+
+    fn open(&mut self, path: &str) -> io::Result<()> {
+        self.input = Some(BufReader::new(File::open(path)?));
+        Ok(())
+    }
+
+    fn read_file(&mut self, path: &str) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+
+    fn write_file(&mut self, path: &str, contents: &str) -> io::Result<()> {
+        fs::write(path, contents)
+    }
+
+    fn append_file(&mut self, path: &str, contents: &str) -> io::Result<()> {
+        OpenOptions::new().append(true).create(true).open(path)?.write_all(contents.as_bytes())
+    }
+
+    fn exists(&mut self, path: &str) -> io::Result<bool> {
+        match fs::metadata(path) {
+            Ok(_) => Ok(true),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn list_dir(&mut self, path: &str) -> io::Result<Vec<String>> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+            .collect()
+    }
+
+    fn env_var(&mut self, name: &str) -> io::Result<Option<String>> {
+        Ok(env::var(name).ok())
+    }
+
+    fn now_ms(&mut self) -> io::Result<u64> {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).map_err(io::Error::other)?;
+        Ok(since_epoch.as_millis() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_default_file_operations() {
+        let dir = ::std::env::temp_dir().join(format!("simple_vm_test_{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.txt");
+        let path = path.to_str().unwrap();
+        let mut effect = DefaultSideEffect::default();
+
+        assert!(!effect.exists(path).unwrap());
+        effect.write_file(path, "one\n").unwrap();
+        effect.append_file(path, "two\n").unwrap();
+        assert!(effect.exists(path).unwrap());
+        assert_eq!("one\ntwo\n", effect.read_file(path).unwrap());
+        assert_eq!(vec!["file.txt".to_owned()], effect.list_dir(dir.to_str().unwrap()).unwrap());
+
+        effect.open(path).unwrap();
+        assert_eq!("one", effect.read_line().unwrap());
+        assert_eq!("two", effect.read_line().unwrap());
+        assert_eq!("", effect.read_line().unwrap());
+
+        assert!(effect.read_file(dir.join("nope").to_str().unwrap()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
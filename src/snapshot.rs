@@ -0,0 +1,408 @@
+//! Saving and restoring the full state of a `Machine`.
+//!
+//! A snapshot is a line based text format, versioned by its first line.
+//! Version 1 looks like this:
+//!
+//! ```text
+//! simple_vm snapshot v1
+//! instruction_ptr 4
+//! steps 4
+//! input 0
+//! code 5
+//! num 1
+//! num 2
+//! op +
+//! label "end"
+//! str "a \"quoted\" string"
+//! stack 1
+//! num 3
+//! return_stack 0
+//! checksum 9f3c0d3f6a1b2c4d
+//! ```
+//!
+//! Every section starts with its name and, for lists, the number of lines
+//! that follow. Values are written one per line as a tag followed by
+//! their contents: `num`, `bool`, `op` (by its source name), `native`
+//! (by its index), and the quoted `str`, `label`, and `possible_label`.
+//! Quoted strings escape `\\`, `"`, newlines, carriage returns and tabs
+//! with a backslash.
+//! The `input` section holds the quoted lines given via `provide_input`,
+//! or put back by `step_back`, that haven't been read yet.
+//!
+//! The last line is a 64-bit FNV-1a hash, in hex, of every byte that
+//! precedes it, so that truncated or edited snapshots are rejected.
+//!
+//! Only the program's state is saved. Host configuration like limits,
+//! deadlines and the `SideEffect` are not, and start out as defaults on
+//! the restored machine, or as the effect given to `restore_with_effect`.
+//! Native functions aren't either, and must be given back, in the same
+//! order, to `restore_with_natives`.
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+use super::*;
+
+const HEADER: &str = "simple_vm snapshot v1";
+
+impl<E: SideEffect, O: Observer, I: Instruction> Machine<E, O, I> {
+    /// Serializes the state of the machine, see the `snapshot` module.
+    pub fn snapshot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "{}", HEADER).unwrap();
+        writeln!(out, "instruction_ptr {}", self.instruction_ptr).unwrap();
+        writeln!(out, "steps {}", self.steps).unwrap();
+        writeln!(out, "input {}", self.input.len()).unwrap();
+        for line in &self.input {
+            writeln!(out, "{}", quote(line)).unwrap();
+        }
+        writeln!(out, "code {}", self.code.len()).unwrap();
+        for value in &self.code {
+            writeln!(out, "{}", encode_value(value)).unwrap();
+        }
+        writeln!(out, "stack {}", self.stack.len()).unwrap();
+        for value in &self.stack {
+            writeln!(out, "{}", encode_value(value)).unwrap();
+        }
+        writeln!(out, "return_stack {}", self.return_stack.len()).unwrap();
+        for address in &self.return_stack {
+            writeln!(out, "{}", address).unwrap();
+        }
+        let checksum = fnv1a(out.as_bytes());
+        writeln!(out, "checksum {:016x}", checksum).unwrap();
+        out
+    }
+
+    /// Creates a machine from a snapshot made with `Machine::snapshot`.
+    ///
+    /// Returns `StackError::InvalidSnapshot` if the snapshot is for a different
+    /// version, fails its checksum, or describes an impossible machine.
+    pub fn restore(snapshot: &str) -> Result<Self, StackError>
+    where
+        E: Default,
+        O: Default,
+    {
+        Self::restore_with_effect(snapshot, E::default())
+    }
+
+    /// Like `restore`, but the restored machine uses `effect` for I/O.
+    pub fn restore_with_effect(snapshot: &str, effect: E) -> Result<Self, StackError>
+    where
+        O: Default,
+    {
+        Self::restore_with_natives(snapshot, Natives::new(), effect)
+    }
+
+    /// Like `restore_with_effect`, for code that calls the native functions
+    /// in `natives`, which must be registered in the order they were when
+    /// the code was preprocessed.
+    pub fn restore_with_natives(snapshot: &str, natives: Natives<I>, effect: E) -> Result<Self, StackError>
+    where
+        O: Default,
+    {
+        let body_len = match snapshot.trim_end_matches('\n').rfind('\n') {
+            Some(idx) => idx + 1,
+            None => return Err(invalid("snapshot is empty")),
+        };
+        let (body, checksum_line) = snapshot.split_at(body_len);
+        let checksum = field(checksum_line.trim_end(), "checksum")?;
+        if u64::from_str_radix(checksum, 16).ok() != Some(fnv1a(body.as_bytes())) {
+            return Err(invalid("checksum does not match"));
+        }
+
+        let mut lines = body.lines();
+        match lines.next() {
+            Some(HEADER) => {}
+            Some(header) => return Err(invalid(&format!("unsupported header \"{}\"", header))),
+            None => return Err(invalid("snapshot is empty")),
+        }
+
+        let instruction_ptr = parse_number(field(next_line(&mut lines)?, "instruction_ptr")?)?;
+        let steps = parse_number(field(next_line(&mut lines)?, "steps")?)?;
+        let input = {
+            let len = parse_number(field(next_line(&mut lines)?, "input")?)?;
+            // The counts aren't trusted, so don't preallocate from them.
+            let mut input = VecDeque::new();
+            for _ in 0..len {
+                input.push_back(unquote(next_line(&mut lines)?)?);
+            }
+            input
+        };
+        let code = decode_values(&mut lines, "code")?;
+        let stack = decode_values(&mut lines, "stack")?;
+        let return_stack = {
+            let len = parse_number(field(next_line(&mut lines)?, "return_stack")?)?;
+            let mut return_stack = Vec::new();
+            for _ in 0..len {
+                return_stack.push(parse_number(next_line(&mut lines)?)?);
+            }
+            return_stack
+        };
+        if lines.next().is_some() {
+            return Err(invalid("unexpected trailing lines"));
+        }
+
+        if instruction_ptr > code.len() {
+            return Err(invalid("instruction_ptr is out of bounds"));
+        }
+        if return_stack.iter().any(|&address| address > code.len()) {
+            return Err(invalid("return_stack address is out of bounds"));
+        }
+        if let Some(value) = code.iter().find(|v| matches!(**v, StackValue::PossibleLabel(_))) {
+            return Err(invalid(&format!("code contains unresolved label {}", value)));
+        }
+        if code.windows(2).any(|pair| is_jump_target_out_of_bounds(pair, code.len())) {
+            return Err(invalid("jump target is out of bounds"));
+        }
+        if let Some(value) = code.iter().chain(&stack).find(|v| match **v {
+            StackValue::Native(index) => index >= natives.len(),
+            _ => false,
+        }) {
+            return Err(invalid(&format!("{} is not a registered native", value)));
+        }
+
+        let mut machine = Machine::from_preprocessed(code, effect, O::default());
+        machine.set_natives(natives);
+        machine.instruction_ptr = instruction_ptr;
+        machine.steps = steps;
+        machine.input = input;
+        machine.return_stack = return_stack;
+        machine.stack_push(stack)?;
+        Ok(machine)
+    }
+}
+
+/// Whether `pair` is a number followed by `jmp` or `call` that would go
+/// past the end of code that is `len` long.
+fn is_jump_target_out_of_bounds<I: Instruction>(pair: &[StackValue<I>], len: usize) -> bool {
+    match (&pair[0], &pair[1]) {
+        (StackValue::Num(to), StackValue::Operation(op)) => {
+            (op.name() == "jmp" || op.name() == "call") && (*to < 0 || *to as usize > len)
+        }
+        _ => false,
+    }
+}
+
+fn invalid(reason: &str) -> StackError {
+    StackError::InvalidSnapshot { reason: reason.to_owned() }
+}
+
+/// 64-bit FNV-1a, which is plenty to catch accidental corruption.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+fn next_line<'a, I: Iterator<Item = &'a str>>(lines: &mut I) -> Result<&'a str, StackError> {
+    lines.next().ok_or_else(|| invalid("snapshot is truncated"))
+}
+
+/// Returns what follows `name ` on a line.
+fn field<'a>(line: &'a str, name: &str) -> Result<&'a str, StackError> {
+    if line.starts_with(name) && line[name.len()..].starts_with(' ') {
+        Ok(&line[name.len() + 1..])
+    } else {
+        Err(invalid(&format!("expected {} but found \"{}\"", name, line)))
+    }
+}
+
+fn parse_number<T: FromStr>(s: &str) -> Result<T, StackError> {
+    s.parse::<T>().map_err(|_| invalid(&format!("\"{}\" is not a number", s)))
+}
+
+fn decode_values<'a, L, I>(lines: &mut L, section: &str) -> Result<Vec<StackValue<I>>, StackError>
+where
+    L: Iterator<Item = &'a str>,
+    I: Instruction,
+{
+    let len = parse_number(field(next_line(lines)?, section)?)?;
+    let mut values = Vec::new();
+    for _ in 0..len {
+        values.push(decode_value(next_line(lines)?)?);
+    }
+    Ok(values)
+}
+
+fn encode_value<I: Instruction>(value: &StackValue<I>) -> String {
+    use StackValue::*;
+    match *value {
+        Bool(b) => format!("bool {}", b),
+        Num(n) => format!("num {}", n),
+        Label(ref s) => format!("label {}", quote(s)),
+        Operation(ref op) => format!("op {}", op.name()),
+        String(ref s) => format!("str {}", quote(s)),
+        PossibleLabel(ref s) => format!("possible_label {}", quote(s)),
+        Native(index) => format!("native {}", index),
+    }
+}
+
+fn decode_value<I: Instruction>(line: &str) -> Result<StackValue<I>, StackError> {
+    use StackValue::*;
+    let (tag, rest) = match line.find(' ') {
+        Some(idx) => (&line[..idx], &line[idx + 1..]),
+        None => return Err(invalid(&format!("malformed value \"{}\"", line))),
+    };
+    Ok(match tag {
+        "bool" => Bool(parse_number(rest)?),
+        "num" => Num(parse_number(rest)?),
+        "label" => Label(unquote(rest)?),
+        "op" => Operation(I::from_str(rest)?),
+        "str" => String(unquote(rest)?),
+        "possible_label" => PossibleLabel(unquote(rest)?),
+        "native" => Native(parse_number(rest)?),
+        _ => return Err(invalid(&format!("unknown value tag \"{}\"", tag))),
+    })
+}
+
+pub(crate) fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub(crate) fn unquote(s: &str) -> Result<String, StackError> {
+    if s.len() < 2 || !s.starts_with('"') || !s.ends_with('"') {
+        return Err(invalid(&format!("expected a quoted string but found {}", s)));
+    }
+    let mut out = String::with_capacity(s.len() - 2);
+    let mut chars = s[1..s.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => out.push('\\'),
+            Some('"') => out.push('"'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            _ => return Err(invalid(&format!("bad escape in {}", s))),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use StackValue::*;
+
+    fn machine(code: &str) -> Machine<DefaultSideEffect> {
+        Machine::new(tokenize(code).unwrap()).unwrap()
+    }
+
+    /// Replaces the checksum of an edited snapshot with a matching one.
+    fn with_checksum(snapshot: &str) -> std::string::String {
+        let body = &snapshot[..snapshot.rfind("checksum").unwrap()];
+        format!("{}checksum {:016x}\n", body, fnv1a(body.as_bytes()))
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut original = machine("\"a \\\"b\\\"\" 1 2 f call + stop f: dup return");
+        original.run_for(6).unwrap();
+        original.provide_input("line\twith tab".to_owned());
+        original.provide_input("second".to_owned());
+
+        let snapshot = original.snapshot();
+        let mut restored = Machine::<DefaultSideEffect>::restore(&snapshot).unwrap();
+        assert_eq!(snapshot, restored.snapshot());
+        assert_eq!(original.stack(), restored.stack());
+        assert_eq!(original.return_stack(), restored.return_stack());
+        assert_eq!(original.instruction_ptr(), restored.instruction_ptr());
+
+        assert_eq!(original.resume().unwrap(), restored.resume().unwrap());
+        assert_eq!(original.stack(), restored.stack());
+        assert_eq!(vec![String("a \"b\"".to_owned()), Num(1), Num(4)], restored.stack());
+    }
+
+    #[test]
+    fn test_rejects_tampering() {
+        let snapshot = machine("1 2 +").snapshot().replace("num 2", "num 3");
+        match Machine::<DefaultSideEffect>::restore(&snapshot) {
+            Err(StackError::InvalidSnapshot { reason }) => assert_eq!("checksum does not match", reason),
+            _ => panic!("expected InvalidSnapshot"),
+        }
+    }
+
+    #[test]
+    fn test_rejects_other_versions() {
+        let snapshot = with_checksum(&machine("1").snapshot().replace("v1", "v2"));
+        match Machine::<DefaultSideEffect>::restore(&snapshot) {
+            Err(StackError::InvalidSnapshot { reason }) => assert!(reason.contains("v2")),
+            _ => panic!("expected InvalidSnapshot"),
+        }
+    }
+
+    #[test]
+    fn test_rejects_out_of_bounds_instruction_ptr() {
+        let snapshot = with_checksum(&machine("1").snapshot().replace("instruction_ptr 0", "instruction_ptr 9"));
+        assert!(Machine::<DefaultSideEffect>::restore(&snapshot).is_err());
+    }
+
+    #[test]
+    fn test_huge_counts_are_not_preallocated() {
+        let snapshot = machine("1").snapshot();
+        for section in &["input 0", "code 1", "stack 0", "return_stack 0"] {
+            let name = section.split(' ').next().unwrap();
+            let huge = format!("{} {}", name, usize::MAX);
+            let snapshot = with_checksum(&snapshot.replace(section, &huge));
+            match Machine::<DefaultSideEffect>::restore(&snapshot) {
+                Err(StackError::InvalidSnapshot { .. }) => {}
+                _ => panic!("expected InvalidSnapshot for a huge {} count", name),
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejects_out_of_bounds_jump_target() {
+        let snapshot = machine("end jmp 1 end:").snapshot();
+        assert!(Machine::<DefaultSideEffect>::restore(&snapshot).is_ok());
+        for target in &["num 5", "num -1"] {
+            let snapshot = with_checksum(&snapshot.replacen("num 4", target, 1));
+            match Machine::<DefaultSideEffect>::restore(&snapshot) {
+                Err(StackError::InvalidSnapshot { reason }) => assert_eq!("jump target is out of bounds", reason),
+                _ => panic!("expected InvalidSnapshot"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_natives_are_checked_on_restore() {
+        let natives = || {
+            let mut natives = Natives::new();
+            natives.register("one", &[], |_| Ok(vec![Num(1)])).unwrap();
+            natives
+        };
+        let mut original = Machine::<DefaultSideEffect>::with_natives(tokenize("one").unwrap(), natives()).unwrap();
+        let snapshot = original.snapshot();
+
+        let mut restored = Machine::<DefaultSideEffect>::restore_with_natives(&snapshot, natives(), DefaultSideEffect::default()).unwrap();
+        assert_eq!(original.resume().unwrap(), restored.resume().unwrap());
+        assert_eq!(vec![Num(1)], restored.stack());
+
+        match Machine::<DefaultSideEffect>::restore(&snapshot) {
+            Err(StackError::InvalidSnapshot { reason }) => assert_eq!("<native:0> is not a registered native", reason),
+            _ => panic!("expected InvalidSnapshot"),
+        }
+        let snapshot = with_checksum(&snapshot.replace("native 0", "native 1"));
+        assert!(Machine::<DefaultSideEffect>::restore_with_natives(&snapshot, natives(), DefaultSideEffect::default()).is_err());
+    }
+}
@@ -25,19 +25,86 @@
 //! ```norun
 //! machine.dispatch(operation)?;
 //! ```
+//!
+//! ### Custom instruction sets
+//!
+//! A `Machine` is generic over its `Instruction` set, which defaults to the
+//! built in `StackOperation`. Other crates can use the macro to define their
+//! own, either from scratch or extending another set, whose operations are
+//! then wrapped in a `Base` variant. Operations defined in the new set take
+//! precedence over those of the set it extends.
+//!
+//! Each invocation generates an `impl_stack_operation` module next to the
+//! enum, so define every instruction set in a module of its own.
+//!
+//! ```
+//! #[macro_use] extern crate simple_vm;
+//!
+//! use simple_vm::*;
+//!
+//! ops! {
+//!     /// The built in operations, plus squaring.
+//!     pub enum MyOps extends StackOperation {
+//!         Square square (Num(a)) Push(Num(a * a)),
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let code = MyOps::tokenize("3 square 1 +").unwrap();
+//!     let mut machine = Machine::<DefaultSideEffect, NoObserver, MyOps>::new(code).unwrap();
+//!     machine.run(vec![]).unwrap();
+//!     assert_eq!(vec![StackValue::Num(10)], machine.stack());
+//! }
+//! ```
+
+use std::fmt::Debug;
+use std::str::FromStr;
+
+use super::*;
+
+/// A set of operations a `Machine` can run, usually generated by `ops!`.
+pub trait Instruction: Clone + Debug + PartialEq + FromStr<Err = StackError> {
+    /// The name this operation is written as in source, the inverse of `from_str`.
+    fn name(&self) -> &'static str;
+
+    /// Executes this operation on a machine, which may be running a
+    /// different instruction set that extends this one.
+    fn dispatch<E, O, I>(&self, machine: &mut Machine<E, O, I>) -> Result<StepResult, StackError>
+    where
+        E: SideEffect,
+        O: Observer,
+        I: Instruction;
+
+    /// Whether this operation reads a line of input, so that a machine that
+    /// pauses on input can stop before it when there is none queued.
+    fn reads_input(&self) -> bool {
+        self.name() == "read"
+    }
+
+    /// Like `tokenize`, but for code written in this instruction set.
+    fn tokenize(input: &str) -> Result<Code<Self>, StackError> {
+        Self::tokenize_with_lines(input).map(|(code, _)| code)
+    }
+
+    /// Like `tokenize_with_lines`, but for code written in this instruction set.
+    fn tokenize_with_lines(input: &str) -> Result<(Code<Self>, Vec<usize>), StackError> {
+        tokenize_instructions(input)
+    }
+}
+
 #[macro_export]
 macro_rules! ops {
 
     // This means we can't evaluate the expression.
     (ERR $error_type:ident $t:pat, $e:expr) => {
-        Err(StackError::$error_type {
+        Err($crate::error::StackError::$error_type {
             arg_pattern: stringify!($t).to_owned(),
             expr: stringify!($e).to_owned(),
         })
     };
 
-    (POP $machine:ident) => {
-        $machine.stack.pop()
+    (POP $machine:ident, $checked:ident) => {
+        $machine.pop_operand($checked)
     };
 
     // The MATCH variants of this macro are so that we can recursively
@@ -49,8 +116,8 @@ macro_rules! ops {
     //
     // The expression is evaluated and given the result type,
     // we do something with the stack.
-    (MATCH $machine:ident, $e:expr,) => {
-        $machine.dispatch($e)
+    (MATCH $machine:ident, $checked:ident, $e:expr,) => {
+        $machine.dispatch_with($e, $checked)
     };
 
     // The MATCH variants of this macro are so that we can recursively
@@ -59,11 +126,11 @@ macro_rules! ops {
     //
     // This is the penultimate when we are down to the last argument,
     // and need to pop one last value off of the stack.
-    (MATCH $machine:ident, $e:expr, $t:pat) => {
-        match ops!(POP $machine) {
+    (MATCH $machine:ident, $checked:ident, $e:expr, $t:pat) => {
+        match ops!(POP $machine, $checked) {
             None => ops!(ERR EmptyStack $t, $e),
             Some(a) => match a {
-                $t => ops!(MATCH $machine, $e,),
+                $t => ops!(MATCH $machine, $checked, $e,),
                 _ => ops!(ERR PatternMismatch $t, $e),
             },
         }
@@ -76,41 +143,64 @@ macro_rules! ops {
     // This is the main recursion point where we start with a pattern
     // to pop an argument from the stack and match it, and if it succeeds
     // continue to recurse with the $rest.
-    (MATCH $machine:ident, $e:expr, $t:pat, $($rest:pat),*) => {
-        match ops!(POP $machine) {
+    (MATCH $machine:ident, $checked:ident, $e:expr, $t:pat, $($rest:pat),*) => {
+        match ops!(POP $machine, $checked) {
             None => ops!(ERR EmptyStack $t, $e),
             Some(a) => match a {
-                $t => ops!(MATCH $machine, $e, $($rest),*),
+                $t => ops!(MATCH $machine, $checked, $e, $($rest),*),
                 _ => ops!(ERR PatternMismatch $t, $e),
             },
         }
     };
 
+    // This is the entry point for defining an instruction set that
+    // extends `$base`, adding a `Base` variant that wraps its operations.
+    (
+        $(#[$meta:meta])* $vis:vis enum $name:ident extends $base:ty {
+            $($(#[$attr:meta])* $t:ident $s:tt ($($type:pat),*) $e:expr,)+
+        }
+    ) => {
+        ops!(DEFINE [$(#[$meta])*] [$vis] $name [$base] $($(#[$attr])* $t $s ($($type),*) $e,)+);
+    };
+
     // This is the MAIN entry point for the macro.
     (
+        $(#[$meta:meta])* $vis:vis enum $name:ident {
+            $($(#[$attr:meta])* $t:ident $s:tt ($($type:pat),*) $e:expr,)+
+        }
+    ) => {
+        ops!(DEFINE [$(#[$meta])*] [$vis] $name [] $($(#[$attr])* $t $s ($($type),*) $e,)+);
+    };
+
+    (
+        DEFINE [$(#[$meta:meta])*] [$vis:vis] $name:ident [$($base:ty)?]
         $($(#[$attr:meta])* $t:ident $s:tt ($($type:pat),*) $e:expr,)+
     ) => {
 
-        /// Generated enum of all the user-accessible primitive stack operations.
+        $(#[$meta])*
         ///
         /// This is generated by the `ops` macro.
-        ///
-        /// The enum name is the first arg to the macro.
         #[derive(Clone, Copy, Debug, PartialEq)]
-        pub enum StackOperation {
+        $vis enum $name {
             $(
                 $(#[$attr])* $t,
             )+
+            $(
+                /// An operation of the instruction set this one extends.
+                Base($base),
+            )?
         }
 
-        /// Each stack operation is able to be constructed from a string.
-        /// This is the _second_ arg to the `ops` macro.
-        impl FromStr for StackOperation {
-            type Err = StackError;
+        /// Each stack operation is able to be constructed from the
+        /// string that is the _second_ part of its definition.
+        impl ::std::str::FromStr for $name {
+            type Err = $crate::error::StackError;
             fn from_str(s: &str) -> Result<Self, Self::Err> {
                 match s {
-                    $( stringify!($s) => Ok(StackOperation::$t), )+
-                    _ => Err(StackError::InvalidOperation {
+                    $( stringify!($s) => Ok($name::$t), )+
+                    $(_ => <$base as ::std::str::FromStr>::from_str(s).map($name::Base),)?
+                    #[allow(unreachable_patterns)]
+                    _ => Err($crate::error::StackError::InvalidOperation {
                         name: s.into()
                     })
                 }
@@ -118,38 +208,64 @@ macro_rules! ops {
         }
 
         #[allow(non_snake_case)]
-        pub mod impl_stack_operation {
+        $vis mod impl_stack_operation {
             //!
             //! The `impl_stack_operation` module is generated by the `ops!` macro.
             //!
-            //! Each of its submodules has an `execute` method which operates on a `Machine`.
+            //! Each of its submodules has an `execute` method which operates on a `Machine`,
+            //! taking whether the machine's stack is checked so that it can be
+            //! compiled for either case.
             //!
+            #[allow(unused_imports)]
             use super::*;
             $(pub mod $t {
-                //! Corresponds to an enum variant of `StackOperation` of the same name as the module.
+                //! Corresponds to an enum variant of the same name as the module.
+                #[allow(unused_imports)]
                 use super::*;
                 #[allow(unreachable_patterns, unused_imports, unreachable_code)]
-                pub fn execute<E: SideEffect>(machine: &mut Machine<E>)
-                    -> Result<StepResult, StackError>
+                #[inline(always)]
+                pub fn execute<E, O, I>(machine: &mut $crate::Machine<E, O, I>, checked: bool)
+                    -> Result<$crate::StepResult, $crate::error::StackError>
+                where
+                    E: $crate::SideEffect,
+                    O: $crate::Observer,
+                    I: $crate::Instruction,
                 {
-                    use StackValue::*;
-                    use MachineOperation::*;
-                    ops!(MATCH machine, $e, $($type),*)
+                    use $crate::StackValue::*;
+                    use $crate::MachineOperation::*;
+                    ops!(MATCH machine, checked, $e, $($type),*)
                 }
             })+
         }
 
-        impl StackOperation {
-            /// Dispatch a generated `StackOperation` variant to its relevant
-            /// `impl_stack_operation::$OperationVariant::execute()` function.
-            pub fn dispatch<E: SideEffect>(&self, machine: &mut Machine<E>)
-                -> Result<StepResult, StackError>
+        impl $crate::Instruction for $name {
+            fn name(&self) -> &'static str {
+                match *self {
+                    $($name::$t => stringify!($s),)+
+                    $($name::Base(ref op) => <$base as $crate::Instruction>::name(op),)?
+                }
+            }
+
+            fn dispatch<E, O, I>(&self, machine: &mut $crate::Machine<E, O, I>)
+                -> Result<$crate::StepResult, $crate::error::StackError>
+            where
+                E: $crate::SideEffect,
+                O: $crate::Observer,
+                I: $crate::Instruction,
             {
+                // Checking once here gives each operation a copy for the
+                // common case where pushes and pops go straight to the stack.
+                let checked = machine.stack_is_checked();
                 match *self {
-                    $(StackOperation::$t => impl_stack_operation::$t::execute(machine),)+
+                    $($name::$t => if checked {
+                        impl_stack_operation::$t::execute(machine, true)
+                    } else {
+                        impl_stack_operation::$t::execute(machine, false)
+                    },)+
+                    $($name::Base(ref op) => <$base as $crate::Instruction>::dispatch(op, machine),)?
                 }
             }
         }
-    }
+    };
 
 }
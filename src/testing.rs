@@ -0,0 +1,257 @@
+//! A `SideEffect` and helpers for testing programs without any real I/O.
+//!
+//! ```
+//! use simple_vm::StackValue::*;
+//! use simple_vm::testing::{self, ScriptedEffect};
+//!
+//! let effect = ScriptedEffect::with_input(vec!["41"]);
+//! testing::run("read cast_int 1 + dup println 100 sleep_ms", effect)
+//!     .unwrap()
+//!     .assert_exit_code(0)
+//!     .assert_stack(&[Num(42)])
+//!     .assert_output(&["42"])
+//!     .assert_slept(&[100]);
+//! ```
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::mem;
+
+use super::*;
+
+/// A `SideEffect` that reads scripted input and captures everything else.
+///
+/// Files and environment variables live in memory, and the clock only
+/// moves when the program sleeps.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScriptedEffect {
+    /// The lines `read` returns, from the front. Reading fails once they run out.
+    pub input: VecDeque<String>,
+    /// Every line written with `println`, including what was `print`ed before it.
+    pub output: Vec<String>,
+    /// What was written with `print` since the last `println`.
+    pub partial_line: String,
+    /// Every line written with `eprintln`.
+    pub errors: Vec<String>,
+    /// The duration of every sleep, in ms.
+    pub slept: Vec<u64>,
+    /// The virtual time in ms returned by `now_ms`, advanced by sleeping.
+    pub now_ms: u64,
+    /// The files, from paths to contents. Directories are the paths
+    /// that files are under, like `dir` for `dir/file`.
+    pub files: BTreeMap<String, String>,
+    pub env_vars: BTreeMap<String, String>,
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "not found")
+}
+
+impl ScriptedEffect {
+    pub fn with_input<L, S>(lines: L) -> ScriptedEffect
+    where
+        L: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        ScriptedEffect {
+            input: lines.into_iter().map(Into::into).collect(),
+            ..ScriptedEffect::default()
+        }
+    }
+}
+
+impl SideEffect for ScriptedEffect {
+    fn println<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        let mut line = mem::take(&mut self.partial_line);
+        line.push_str(&value.to_string());
+        self.output.push(line);
+        Ok(())
+    }
+
+    fn eprintln<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        self.errors.push(value.to_string());
+        Ok(())
+    }
+
+    fn print<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        self.partial_line.push_str(&value.to_string());
+        Ok(())
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        self.input.pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "the scripted input ran out"))
+    }
+
+    fn sleep_ms(&mut self, duration: u64) -> io::Result<()> {
+        self.slept.push(duration);
+        self.now_ms += duration;
+        Ok(())
+    }
+
+    /// Replaces the remaining input with the lines of the file.
+    fn open(&mut self, path: &str) -> io::Result<()> {
+        let contents = self.read_file(path)?;
+        self.input = contents.lines().map(str::to_owned).collect();
+        Ok(())
+    }
+
+    fn read_file(&mut self, path: &str) -> io::Result<String> {
+        self.files.get(path).cloned().ok_or_else(not_found)
+    }
+
+    fn write_file(&mut self, path: &str, contents: &str) -> io::Result<()> {
+        self.files.insert(path.to_owned(), contents.to_owned());
+        Ok(())
+    }
+
+    fn append_file(&mut self, path: &str, contents: &str) -> io::Result<()> {
+        self.files.entry(path.to_owned()).or_default().push_str(contents);
+        Ok(())
+    }
+
+    fn exists(&mut self, path: &str) -> io::Result<bool> {
+        let dir = format!("{}/", path);
+        Ok(self.files.keys().any(|file| file == path || file.starts_with(&dir)))
+    }
+
+    fn list_dir(&mut self, path: &str) -> io::Result<Vec<String>> {
+        let dir = format!("{}/", path);
+        let mut names: Vec<_> = self.files.keys()
+            .filter_map(|file| file.strip_prefix(&dir))
+            .map(|name| name.split('/').next().unwrap().to_owned())
+            .collect();
+        names.dedup();
+        if names.is_empty() {
+            return Err(not_found());
+        }
+        Ok(names)
+    }
+
+    fn env_var(&mut self, name: &str) -> io::Result<Option<String>> {
+        Ok(self.env_vars.get(name).cloned())
+    }
+
+    fn now_ms(&mut self) -> io::Result<u64> {
+        Ok(self.now_ms)
+    }
+}
+
+/// The state a program stopped in, from `run` or `run_code`, with
+/// assertions that panic with a description of what was different.
+#[derive(Debug)]
+pub struct Outcome<I = StackOperation> {
+    pub exit_code: i32,
+    /// The data stack, from the bottom up.
+    pub stack: Vec<StackValue<I>>,
+    pub effect: ScriptedEffect,
+}
+
+impl<I: Instruction> Outcome<I> {
+    pub fn assert_exit_code(&self, exit_code: i32) -> &Self {
+        assert_eq!(exit_code, self.exit_code, "unexpected exit code, the stack is {:?}", self.stack);
+        self
+    }
+
+    /// Checks the whole stack, from the bottom up.
+    pub fn assert_stack(&self, stack: &[StackValue<I>]) -> &Self {
+        assert_eq!(stack, &self.stack[..], "unexpected stack");
+        self
+    }
+
+    /// Checks every line written with `println`.
+    pub fn assert_output<S: AsRef<str>>(&self, output: &[S]) -> &Self {
+        let output: Vec<&str> = output.iter().map(AsRef::as_ref).collect();
+        assert_eq!(output, self.effect.output, "unexpected output");
+        self
+    }
+
+    /// Checks every line written with `eprintln`.
+    pub fn assert_errors<S: AsRef<str>>(&self, errors: &[S]) -> &Self {
+        let errors: Vec<&str> = errors.iter().map(AsRef::as_ref).collect();
+        assert_eq!(errors, self.effect.errors, "unexpected errors");
+        self
+    }
+
+    /// Checks the duration of every sleep.
+    pub fn assert_slept(&self, slept: &[u64]) -> &Self {
+        assert_eq!(slept, &self.effect.slept[..], "unexpected sleeps");
+        self
+    }
+}
+
+/// Runs `source` until it stops, with `effect` for its I/O.
+pub fn run(source: &str, effect: ScriptedEffect) -> Result<Outcome, StackError> {
+    run_code(tokenize(source)?, effect)
+}
+
+/// Like `run`, but for code that is already tokenized, in any instruction set.
+pub fn run_code<I: Instruction>(code: Code<I>, effect: ScriptedEffect) -> Result<Outcome<I>, StackError> {
+    let mut machine = Machine::<_, NoObserver, I>::with_effect(code, effect)?;
+    let exit_code = match machine.run(vec![])? {
+        RunStatus::Stopped(exit_code) => exit_code,
+        // Only breakpoints and input can pause a run, and neither is set up here.
+        RunStatus::Paused(reason) => unreachable!("paused with {:?}", reason),
+    };
+    Ok(Outcome {
+        exit_code,
+        stack: machine.stack(),
+        effect: machine.into_effect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use StackValue::*;
+
+    #[test]
+    fn test_scripted_input() {
+        let outcome = run("read read", ScriptedEffect::with_input(vec!["a", "b"])).unwrap();
+        outcome.assert_stack(&[String("a".to_owned()), String("b".to_owned())]);
+        assert!(outcome.effect.input.is_empty());
+
+        match run("read", ScriptedEffect::default()) {
+            Err(StackError::Io { operation, .. }) => assert_eq!("read", operation),
+            other => panic!("expected an Io error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_output_streams() {
+        let outcome = run("\"a\" print 1 print flush \"b\" println \"oops\" eprintln \"c\" print", ScriptedEffect::default()).unwrap();
+        outcome.assert_output(&["a1b"]).assert_errors(&["oops"]);
+        assert_eq!("c", outcome.effect.partial_line);
+    }
+
+    #[test]
+    fn test_virtual_clock() {
+        let effect = ScriptedEffect { now_ms: 1000, ..ScriptedEffect::default() };
+        run("now_ms 5 sleep_ms 10 sleep_ms now_ms", effect)
+            .unwrap()
+            .assert_stack(&[Num(1000), Num(1015)])
+            .assert_slept(&[5, 10]);
+    }
+
+    #[test]
+    fn test_env_vars() {
+        let mut effect = ScriptedEffect::default();
+        effect.env_vars.insert("USER".to_owned(), "alice".to_owned());
+        run("\"USER\" env \"HOME\" env", effect)
+            .unwrap()
+            .assert_stack(&[String("alice".to_owned()), String("".to_owned())]);
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected output")]
+    fn test_assert_output() {
+        run("1 println", ScriptedEffect::default()).unwrap().assert_output(&["2"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected exit code")]
+    fn test_assert_exit_code() {
+        run("3 exit", ScriptedEffect::default()).unwrap().assert_exit_code(0);
+    }
+}
@@ -0,0 +1,293 @@
+//! Non-interactive tracing of every step a `Machine` takes.
+//!
+//! A `Tracer` installed with `Machine::set_tracer` receives a `TraceEvent`
+//! after each executed instruction. `JsonLinesTracer` writes these out
+//! as one JSON object per line, for example:
+//!
+//! ```text
+//! {"step":3,"address":2,"instruction":{"op":"+"},"operation":{"name":"push","values":[{"num":3}]},"popped":[{"num":2},{"num":1}],"pushed":[{"num":3}],"stack_depth":1,"return_depth":0}
+//! ```
+//!
+//! Values are written as single key objects tagged with their type:
+//! `num`, `bool`, `str`, `label`, `op`, `possible_label`, or `native`.
+//! Operations are objects with the `name` of the `MachineOperation` in
+//! snake case, and its operands as `address`, `values`, `value`, `ms`,
+//! `path`, `contents`, `name` or `exit_code`. If the instruction failed,
+//! the event has an `error` with the message.
+
+use std::fmt;
+use std::io::{self, Write};
+
+use super::*;
+
+/// Everything that happened during a single step of the machine.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEvent<I = StackOperation> {
+    /// The number of this step, counting from 1.
+    pub step: usize,
+    /// The address in `code` of the instruction that was executed.
+    pub address: usize,
+    pub instruction: StackValue<I>,
+    /// What the instruction asked the machine to do, if it was an operation.
+    pub operation: Option<MachineOperation<I>>,
+    /// The values popped off of the stack, in the order they were popped.
+    pub popped: Vec<StackValue<I>>,
+    /// The values pushed onto the stack, in the order they were pushed.
+    pub pushed: Vec<StackValue<I>>,
+    pub stack_depth: usize,
+    pub return_depth: usize,
+    /// The message of the error the step failed with, if it did.
+    pub error: Option<String>,
+}
+
+/// Receives an event for every step of a machine, see `Machine::set_tracer`.
+pub trait Tracer<I = StackOperation> {
+    fn trace(&mut self, event: &TraceEvent<I>);
+
+    /// Flushes any buffered events, reporting errors that happened while tracing.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The installed tracer, events are built from the machine's `Journal`.
+pub(crate) struct TraceState<I> {
+    pub(crate) tracer: Box<dyn Tracer<I>>,
+}
+
+impl<I> fmt::Debug for TraceState<I> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("TraceState")
+    }
+}
+
+impl<E: SideEffect, O: Observer, I: Instruction> Machine<E, O, I> {
+    /// Installs a tracer that receives a `TraceEvent` after every step.
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer<I>>) {
+        self.trace = Some(TraceState { tracer });
+        self.update_journal();
+    }
+
+    /// Removes the installed tracer, returning it.
+    pub fn take_tracer(&mut self) -> Option<Box<dyn Tracer<I>>> {
+        let tracer = self.trace.take().map(|state| state.tracer);
+        self.update_journal();
+        tracer
+    }
+}
+
+/// A `Tracer` writing JSON lines, as described in the `trace` module.
+pub struct JsonLinesTracer<W: Write> {
+    writer: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> JsonLinesTracer<W> {
+    pub fn new(writer: W) -> JsonLinesTracer<W> {
+        JsonLinesTracer { writer, error: None }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write, I: Instruction> Tracer<I> for JsonLinesTracer<W> {
+    fn trace(&mut self, event: &TraceEvent<I>) {
+        if self.error.is_none() {
+            if let Err(e) = writeln!(self.writer, "{}", event_json(event)) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+fn event_json<I: Instruction>(event: &TraceEvent<I>) -> String {
+    let mut json = format!(
+        "{{\"step\":{},\"address\":{},\"instruction\":{}",
+        event.step, event.address, value_json(&event.instruction)
+    );
+    if let Some(ref operation) = event.operation {
+        json.push_str(&format!(",\"operation\":{}", operation_json(operation)));
+    }
+    json.push_str(&format!(
+        ",\"popped\":{},\"pushed\":{},\"stack_depth\":{},\"return_depth\":{}",
+        values_json(&event.popped), values_json(&event.pushed), event.stack_depth, event.return_depth
+    ));
+    if let Some(ref error) = event.error {
+        json.push_str(&format!(",\"error\":{}", string_json(error)));
+    }
+    json.push('}');
+    json
+}
+
+fn operation_json<I: Instruction>(operation: &MachineOperation<I>) -> String {
+    use MachineOperation::*;
+    let (name, operands) = match *operation {
+        Call(address) => ("call", format!(",\"address\":{}", address)),
+        Jump(address) => ("jump", format!(",\"address\":{}", address)),
+        NA => ("na", String::new()),
+        Push(ref v) => ("push", format!(",\"values\":[{}]", value_json(v))),
+        PushTwo(ref v1, ref v2) => ("push_two", format!(",\"values\":[{},{}]", value_json(v1), value_json(v2))),
+        PushThree(ref v1, ref v2, ref v3) => (
+            "push_three",
+            format!(",\"values\":[{},{},{}]", value_json(v1), value_json(v2), value_json(v3)),
+        ),
+        PushAll(ref values) => ("push_all", format!(",\"values\":{}", values_json(values))),
+        Return => ("return", String::new()),
+        Println(ref v) => ("println", format!(",\"value\":{}", value_json(v))),
+        Eprintln(ref v) => ("eprintln", format!(",\"value\":{}", value_json(v))),
+        Print(ref v) => ("print", format!(",\"value\":{}", value_json(v))),
+        Flush => ("flush", String::new()),
+        ReadLn => ("read_ln", String::new()),
+        Sleep(ms) => ("sleep", format!(",\"ms\":{}", ms)),
+        Open(ref path) => ("open", path_json(path)),
+        ReadFile(ref path) => ("read_file", path_json(path)),
+        WriteFile(ref path, ref contents) => ("write_file", format!("{},\"contents\":{}", path_json(path), string_json(contents))),
+        AppendFile(ref path, ref contents) => ("append_file", format!("{},\"contents\":{}", path_json(path), string_json(contents))),
+        Exists(ref path) => ("exists", path_json(path)),
+        ListDir(ref path) => ("list_dir", path_json(path)),
+        EnvVar(ref name) => ("env_var", format!(",\"name\":{}", string_json(name))),
+        Now => ("now", String::new()),
+        Stop(exit_code) => ("stop", format!(",\"exit_code\":{}", exit_code)),
+    };
+    format!("{{\"name\":\"{}\"{}}}", name, operands)
+}
+
+fn path_json(path: &str) -> String {
+    format!(",\"path\":{}", string_json(path))
+}
+
+fn values_json<I: Instruction>(values: &[StackValue<I>]) -> String {
+    let values: Vec<String> = values.iter().map(value_json).collect();
+    format!("[{}]", values.join(","))
+}
+
+fn value_json<I: Instruction>(value: &StackValue<I>) -> String {
+    use StackValue::*;
+    match *value {
+        Bool(b) => format!("{{\"bool\":{}}}", b),
+        Num(n) => format!("{{\"num\":{}}}", n),
+        Label(ref s) => format!("{{\"label\":{}}}", string_json(s)),
+        Operation(ref op) => format!("{{\"op\":{}}}", string_json(op.name())),
+        String(ref s) => format!("{{\"str\":{}}}", string_json(s)),
+        PossibleLabel(ref s) => format!("{{\"possible_label\":{}}}", string_json(s)),
+        Native(index) => format!("{{\"native\":{}}}", index),
+    }
+}
+
+fn string_json(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use StackValue::*;
+
+    struct Collect(Rc<RefCell<Vec<TraceEvent>>>);
+
+    impl Tracer for Collect {
+        fn trace(&mut self, event: &TraceEvent) {
+            self.0.borrow_mut().push(event.clone());
+        }
+    }
+
+    fn traced(code: &str) -> Vec<TraceEvent> {
+        let events = Rc::new(RefCell::new(vec![]));
+        let mut machine = Machine::<DefaultSideEffect>::new(tokenize(code).unwrap()).unwrap();
+        machine.set_tracer(Box::new(Collect(events.clone())));
+        let _ = machine.run(vec![]);
+        let events = events.borrow().clone();
+        events
+    }
+
+    #[test]
+    fn test_records_stack_deltas() {
+        let events = traced("1 2 + end: drop");
+        assert_eq!(5, events.len());
+
+        let plus = &events[2];
+        assert_eq!(3, plus.step);
+        assert_eq!(2, plus.address);
+        assert_eq!(Operation(StackOperation::Plus), plus.instruction);
+        assert_eq!(Some(MachineOperation::Push(Num(3))), plus.operation);
+        assert_eq!(vec![Num(2), Num(1)], plus.popped);
+        assert_eq!(vec![Num(3)], plus.pushed);
+        assert_eq!(1, plus.stack_depth);
+
+        let label = &events[3];
+        assert_eq!(None, label.operation);
+        assert!(label.popped.is_empty() && label.pushed.is_empty());
+    }
+
+    #[test]
+    fn test_ignores_values_pushed_between_steps() {
+        let events = Rc::new(RefCell::new(vec![]));
+        let mut machine = Machine::<DefaultSideEffect>::new(tokenize("1").unwrap()).unwrap();
+        machine.set_tracer(Box::new(Collect(events.clone())));
+        machine.run(vec![Num(0)]).unwrap();
+        assert_eq!(vec![Num(1)], events.borrow()[0].pushed);
+    }
+
+    #[test]
+    fn test_records_errors() {
+        let events = traced("1 +");
+        assert_eq!(2, events.len());
+        assert!(events[1].error.as_ref().unwrap().contains("Cannot pop an empty stack"));
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let mut tracer = JsonLinesTracer::new(vec![]);
+        for event in traced("\"a\\\"b\" 1 swap") {
+            tracer.trace(&event);
+        }
+        assert!(Tracer::<StackOperation>::flush(&mut tracer).is_ok());
+        let json = ::std::string::String::from_utf8(tracer.into_inner()).unwrap();
+        let lines: Vec<&str> = json.lines().collect();
+        assert_eq!(3, lines.len());
+        assert_eq!(
+            r#"{"step":3,"address":2,"instruction":{"op":"swap"},"operation":{"name":"push_two","values":[{"num":1},{"str":"a\"b"}]},"popped":[{"num":1},{"str":"a\"b"}],"pushed":[{"num":1},{"str":"a\"b"}],"stack_depth":2,"return_depth":0}"#,
+            lines[2]
+        );
+    }
+
+    #[test]
+    fn test_operation_json() {
+        let json = |operation: MachineOperation| operation_json(&operation);
+        assert_eq!(r#"{"name":"call","address":4}"#, json(MachineOperation::Call(4)));
+        assert_eq!(r#"{"name":"return"}"#, json(MachineOperation::Return));
+        assert_eq!(r#"{"name":"push_all","values":[]}"#, json(MachineOperation::PushAll(vec![])));
+        assert_eq!(r#"{"name":"println","value":{"bool":true}}"#, json(MachineOperation::Println(Bool(true))));
+        assert_eq!(
+            r#"{"name":"write_file","path":"out","contents":"hi\n"}"#,
+            json(MachineOperation::WriteFile("out".to_owned(), "hi\n".to_owned()))
+        );
+        assert_eq!(r#"{"name":"stop","exit_code":2}"#, json(MachineOperation::Stop(2)));
+    }
+}
@@ -0,0 +1,6 @@
+#!/bin/sh
+# Builds the crate and generates the JS bindings that the playground loads.
+set -e
+cd "$(dirname "$0")"
+cargo build --release
+wasm-bindgen target/wasm32-unknown-unknown/release/simple_vm_wasm.wasm --target web --out-dir .
@@ -1,5 +1,233 @@
-const simple_vm = import('./simple_vm_wasm');
-simple_vm.then(vm => {
-    console.log('attaching to window as simple_vm');
-    window.simple_vm = vm;
+import init, { Machine, isOperation } from './simple_vm_wasm.js';
+
+// How many steps to run before giving the page a chance to update.
+const BUDGET = 10000;
+
+const $ = id => document.getElementById(id);
+let machine = null;
+// The (1-based) lines with breakpoints, kept across runs and edits.
+const breakpointLines = new Set();
+
+// Splits source into tokens the same way the VM's tokenizer does,
+// as [class, text] pairs, keeping the whitespace.
+function lex(code) {
+    const tokens = [];
+    const pattern = /("(?:\\[^]|[^"\\])*"?)|(#[^\n]*)|(\s+)|([^\s"#]+)/g;
+    let match;
+    while ((match = pattern.exec(code))) {
+        const [text, string, comment, space] = match;
+        let kind = null;
+        if (string) kind = 'str';
+        else if (comment) kind = 'comment';
+        else if (space) kind = null;
+        else if (/^-?\d+$/.test(text)) kind = 'num';
+        else if (text === 'true' || text === 'false') kind = 'bool';
+        else if (text.endsWith(':')) kind = 'label';
+        else if (isOperation(text)) kind = 'op';
+        else kind = 'ref';
+        tokens.push([kind, text]);
+    }
+    return tokens;
+}
+
+function escape(text) {
+    return text.replace(/&/g, '&amp;').replace(/</g, '&lt;').replace(/>/g, '&gt;');
+}
+
+// The line of the next instruction, if the machine is part way through.
+function currentLine() {
+    return machine ? machine.line(machine.instructionPtr) : undefined;
+}
+
+function render() {
+    const code = $('code').value;
+    const lines = [[]];
+    for (const [kind, text] of lex(code)) {
+        // Tokens like strings can span lines, so each line gets its own span.
+        text.split('\n').forEach((part, i) => {
+            if (i > 0) lines.push([]);
+            if (part) lines[lines.length - 1].push(kind ? `<span class="tok-${kind}">${escape(part)}</span>` : escape(part));
+        });
+    }
+    const current = currentLine();
+    $('highlight').innerHTML = lines.map((html, i) =>
+        `<span class="line${i + 1 === current ? ' current' : ''}">${html.join('') || ' '}</span>`
+    ).join('');
+    $('gutter').innerHTML = lines.map((_, i) =>
+        `<div data-line="${i + 1}"${breakpointLines.has(i + 1) ? ' class="breakpoint"' : ''}>${i + 1}</div>`
+    ).join('');
+    syncScroll();
+}
+
+function syncScroll() {
+    $('highlight').scrollTop = $('gutter').scrollTop = $('code').scrollTop;
+    $('highlight').scrollLeft = $('code').scrollLeft;
+}
+
+// Shows a stack value the way it would be written in source.
+function display(value) {
+    switch (typeof value) {
+        case 'string': return JSON.stringify(value);
+        case 'object':
+            if ('label' in value) return `${value.label}:`;
+            if ('possibleLabel' in value) return value.possibleLabel;
+            if ('operation' in value) return value.operation;
+            return `<native:${value.native}>`;
+        default: return String(value);
+    }
+}
+
+function load() {
+    $('output').textContent = '';
+    machine = new Machine($('code').value, {
+        println: line => { $('output').append(line + '\n'); },
+        print: text => { $('output').append(text); },
+        eprintln: line => {
+            const span = document.createElement('span');
+            span.className = 'error';
+            span.textContent = line + '\n';
+            $('output').append(span);
+        },
+    });
+    for (const line of breakpointLines) {
+        const address = machine.addressOfLine(line);
+        if (address !== undefined) {
+            machine.setBreakpoint(address);
+        }
+    }
+}
+
+function show(status) {
+    $('stack').textContent = machine.stack().map(display).reverse().join('\n');
+    $('return-stack').textContent = machine.returnStack().reverse().map(address => {
+        const line = machine.line(address);
+        return line === undefined ? `${address}` : `${address} (line ${line})`;
+    }).join('\n');
+    $('status').className = '';
+    $('status').textContent = `${status.state} after ${machine.steps} steps`
+        + (status.state === 'stopped' ? `, exit code ${status.exitCode}` : '')
+        + (status.reason === 'breakpoint' ? ` at a breakpoint on line ${machine.line(status.address)}` : '');
+    $('input-form').hidden = status.reason !== 'awaitingInput';
+    if (status.state === 'stopped') {
+        machine = null;
+    }
+    render();
+}
+
+function showError(error) {
+    machine = null;
+    $('status').className = 'error';
+    $('status').textContent = error.kind ? `${error.kind}: ${error.message}` : String(error);
+    render();
+}
+
+async function run() {
+    try {
+        if (!machine) {
+            load();
+        }
+        for (;;) {
+            const status = machine.run(BUDGET);
+            show(status);
+            if (status.reason !== 'budget') {
+                return;
+            }
+            await new Promise(resolve => setTimeout(resolve));
+        }
+    } catch (error) {
+        showError(error);
+    }
+}
+
+function step() {
+    try {
+        if (!machine) {
+            load();
+        }
+        show(machine.step());
+    } catch (error) {
+        showError(error);
+    }
+}
+
+function reset() {
+    machine = null;
+    $('status').textContent = '';
+    $('stack').textContent = '';
+    $('return-stack').textContent = '';
+    $('output').textContent = '';
+    $('input-form').hidden = true;
+    render();
+}
+
+function toggleBreakpoint(line) {
+    const address = machine ? machine.addressOfLine(line) : undefined;
+    if (breakpointLines.delete(line)) {
+        if (address !== undefined) machine.clearBreakpoint(address);
+    } else {
+        breakpointLines.add(line);
+        if (address !== undefined) machine.setBreakpoint(address);
+    }
+    render();
+}
+
+// Programs are shared as `#code=...&breakpoints=1,2` in the URL.
+function share() {
+    const params = new URLSearchParams({ code: $('code').value });
+    if (breakpointLines.size) {
+        params.set('breakpoints', [...breakpointLines].sort((a, b) => a - b).join(','));
+    }
+    history.replaceState(null, '', '#' + params);
+    $('status').className = '';
+    $('status').textContent = 'The link to this program is in the address bar';
+    if (navigator.clipboard) {
+        navigator.clipboard.writeText(location.href)
+            .then(() => { $('status').textContent = 'Copied the link to this program'; }, () => {});
+    }
+}
+
+function loadShared() {
+    const params = new URLSearchParams(location.hash.slice(1));
+    if (params.has('code')) {
+        $('code').value = params.get('code');
+    }
+    breakpointLines.clear();
+    for (const line of (params.get('breakpoints') || '').split(',')) {
+        if (/^\d+$/.test(line)) breakpointLines.add(Number(line));
+    }
+}
+
+init().then(() => {
+    loadShared();
+    render();
+    $('run').onclick = run;
+    $('step').onclick = step;
+    $('reset').onclick = reset;
+    $('share').onclick = share;
+    $('code').oninput = () => {
+        // The running program no longer matches the source.
+        if (machine) reset();
+        else render();
+    };
+    $('code').onscroll = syncScroll;
+    $('code').onkeydown = event => {
+        if (event.key === 'Tab') {
+            event.preventDefault();
+            document.execCommand('insertText', false, '    ');
+        }
+    };
+    $('gutter').onclick = event => {
+        const line = Number(event.target.dataset.line);
+        if (line) toggleBreakpoint(line);
+    };
+    $('input-form').onsubmit = event => {
+        event.preventDefault();
+        machine.provideInput($('input').value);
+        $('input').value = '';
+        run();
+    };
+    window.onhashchange = () => {
+        loadShared();
+        reset();
+    };
 });
@@ -1,54 +1,673 @@
-#![feature(proc_macro)]
-#![allow(non_camel_case_types)]
+//! WebAssembly bindings for `simple_vm`, exposing a `Machine` class to JS.
+//!
+//! ```js
+//! const machine = new Machine("\"hi\" println read", {
+//!     println: line => console.log(line),
+//! });
+//! machine.run(1000); // { state: "paused", reason: "awaitingInput" }
+//! machine.provideInput("there");
+//! machine.run(1000); // { state: "stopped", exitCode: 0 }
+//! ```
+//!
+//! Stack values are converted losslessly in both directions: booleans,
+//! strings and numbers map to themselves, with numbers beyond
+//! `Number.MAX_SAFE_INTEGER` as `BigInt`s, and the other values to
+//! `{ label }`, `{ possibleLabel }`, `{ operation }` and `{ native }` objects.
+//!
+//! Errors are thrown as JS `Error`s named `StackError`, with a `kind` naming
+//! the `StackError` variant and its fields in camel case.
 
+extern crate js_sys;
 extern crate simple_vm;
 extern crate wasm_bindgen;
 
-use simple_vm::*;
+#[cfg(test)]
+extern crate wasm_bindgen_test;
+
+use std::convert::TryFrom;
+use std::io;
+
+use js_sys::{Array, BigInt, Function, Object, RangeError, Reflect, TypeError};
+use simple_vm::error::StackError;
+use simple_vm::{Instruction, PauseReason, RunStatus, SideEffect, StackOperation, StackValue, StepResult};
 use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
 
 #[wasm_bindgen]
-extern {
-    type console;
-    #[wasm_bindgen(static = console)]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
     fn log(s: &str);
-    #[wasm_bindgen(static = console)]
-    fn warn(s: &str);
-}
-
-macro_rules! try_js {
-    ($e: expr) => {
-        match $e {
-            Ok(ok) => {
-                console::log(&format!("Success: {} {:?}", stringify!($e), &ok));
-                ok
-            },
-            Err(e) => {
-                console::warn(&format!("Error: {} {:?}", stringify!($e), e));
-                return Vec::new();
-            }
+    #[wasm_bindgen(js_namespace = console, js_name = error)]
+    fn log_error(s: &str);
+}
+
+/// How many steps `run` takes between checking whether a callback threw.
+const STEPS_BETWEEN_CHECKS: usize = 1024;
+
+fn set(target: &JsValue, key: &str, value: JsValue) {
+    // Only fails for frozen objects and proxies, which these never are.
+    Reflect::set(target, &JsValue::from_str(key), &value).unwrap();
+}
+
+fn js_error(error: StackError) -> JsValue {
+    let js_error = js_sys::Error::new(&error.to_string());
+    js_error.set_name("StackError");
+    let js_error = JsValue::from(js_error);
+    let kind = match error {
+        StackError::BudgetExhausted { steps } => {
+            set(&js_error, "steps", steps.into());
+            "BudgetExhausted"
+        }
+        StackError::Cancelled => "Cancelled",
+        StackError::EmptyStack { arg_pattern, expr } => {
+            set(&js_error, "argPattern", arg_pattern.into());
+            set(&js_error, "expr", expr.into());
+            "EmptyStack"
+        }
+        StackError::InvalidNative { name, reason } => {
+            set(&js_error, "nativeName", name.into());
+            set(&js_error, "reason", reason.into());
+            "InvalidNative"
+        }
+        StackError::InvalidOperation { name } => {
+            set(&js_error, "operation", name.into());
+            "InvalidOperation"
+        }
+        StackError::InvalidString { string } => {
+            set(&js_error, "string", string.into());
+            "InvalidString"
+        }
+        StackError::InvalidRecording { reason } => {
+            set(&js_error, "reason", reason.into());
+            "InvalidRecording"
+        }
+        StackError::InvalidSnapshot { reason } => {
+            set(&js_error, "reason", reason.into());
+            "InvalidSnapshot"
+        }
+        StackError::Io { operation, message } => {
+            set(&js_error, "operation", operation.into());
+            set(&js_error, "ioMessage", message.into());
+            "Io"
+        }
+        StackError::MultipleLabelDefinitions { label, locations } => {
+            set(&js_error, "label", label.into());
+            set(&js_error, "locations", locations.into_iter().map(JsValue::from).collect::<Array>().into());
+            "MultipleLabelDefinitions"
+        }
+        StackError::NativeFailed { name, message } => {
+            set(&js_error, "nativeName", name.into());
+            set(&js_error, "nativeMessage", message.into());
+            "NativeFailed"
+        }
+        StackError::OutOfBounds => "OutOfBounds",
+        StackError::PatternMismatch { arg_pattern, expr } => {
+            set(&js_error, "argPattern", arg_pattern.into());
+            set(&js_error, "expr", expr.into());
+            "PatternMismatch"
+        }
+        StackError::PermissionDenied { operation, reason } => {
+            set(&js_error, "operation", operation.into());
+            set(&js_error, "reason", reason.into());
+            "PermissionDenied"
+        }
+        StackError::ReturnStackOverflow { limit } => {
+            set(&js_error, "limit", limit.into());
+            "ReturnStackOverflow"
+        }
+        StackError::StackIndexOutOfBounds { index, len } => {
+            set(&js_error, "index", index.into());
+            set(&js_error, "len", len.into());
+            "StackIndexOutOfBounds"
+        }
+        StackError::StackOverflow { limit } => {
+            set(&js_error, "limit", limit.into());
+            "StackOverflow"
+        }
+        StackError::StringMemoryExceeded { limit } => {
+            set(&js_error, "limit", limit.into());
+            "StringMemoryExceeded"
+        }
+        StackError::TimedOut => "TimedOut",
+        StackError::UndefinedLabel { label, times } => {
+            set(&js_error, "label", label.into());
+            set(&js_error, "times", times.into());
+            "UndefinedLabel"
+        }
+    };
+    set(&js_error, "kind", kind.into());
+    js_error
+}
+
+/// The state of a machine after `run` or `step`, as
+/// `{ state: "running" | "stopped" | "paused", exitCode?, reason? }`.
+fn js_status(status: RunStatus) -> JsValue {
+    let js_status = JsValue::from(Object::new());
+    match status {
+        RunStatus::Stopped(exit_code) => {
+            set(&js_status, "state", "stopped".into());
+            set(&js_status, "exitCode", exit_code.into());
+        }
+        RunStatus::Paused(reason) => {
+            set(&js_status, "state", "paused".into());
+            let reason = match reason {
+                PauseReason::Condition => "condition",
+                PauseReason::AwaitingInput => "awaitingInput",
+                PauseReason::StepBudget => "budget",
+                PauseReason::Breakpoint(address) => {
+                    set(&js_status, "address", address.into());
+                    "breakpoint"
+                }
+                PauseReason::Watchpoint(id) => {
+                    set(&js_status, "watchpoint", id.into());
+                    "watchpoint"
+                }
+            };
+            set(&js_status, "reason", reason.into());
         }
     }
+    js_status
 }
 
-fn to_js_value(stack_value: &StackValue) -> JsValue {
+/// The largest integer a JS number holds exactly, `Number.MAX_SAFE_INTEGER`.
+const MAX_SAFE_INTEGER: i64 = (1 << 53) - 1;
+
+/// Converts a stack value to JS, losslessly:
+///
+/// * `Bool` and `String` become booleans and strings,
+/// * `Num` becomes a number, or a `BigInt` if it doesn't fit a number exactly,
+/// * `Label`, `PossibleLabel`, `Operation` and `Native` become
+///   `{ label }`, `{ possibleLabel }`, `{ operation }` and `{ native }`.
+///
+/// There are no compound values on the stack, so nothing else to map.
+fn to_js(stack_value: &StackValue) -> JsValue {
     use StackValue::*;
+    let tagged = |key: &str, value: JsValue| {
+        let object = JsValue::from(Object::new());
+        set(&object, key, value);
+        object
+    };
     match *stack_value {
         Bool(b) => b.into(),
-        Num(n) => (n as u32).into(),
-        _ => (&format!("{}", stack_value)).into()
+        Num(n) => {
+            let n = n as i64;
+            if (-MAX_SAFE_INTEGER..=MAX_SAFE_INTEGER).contains(&n) {
+                (n as f64).into()
+            } else {
+                BigInt::from(n).into()
+            }
+        }
+        String(ref s) => s.into(),
+        Label(ref name) => tagged("label", name.into()),
+        PossibleLabel(ref name) => tagged("possibleLabel", name.into()),
+        Operation(ref op) => tagged("operation", op.name().into()),
+        Native(index) => tagged("native", index.into()),
     }
 }
 
+/// The inverse of `to_js`, throwing a `TypeError` for values that aren't one
+/// of its results and a `RangeError` for numbers that aren't an `isize`.
+fn from_js(value: &JsValue) -> Result<StackValue, JsValue> {
+    let out_of_range = || JsValue::from(RangeError::new(&format!("{:?} is not a valid number", value)));
+    let invalid = || JsValue::from(TypeError::new(&format!("{:?} is not a stack value", value)));
+    if let Some(b) = value.as_bool() {
+        Ok(StackValue::Bool(b))
+    } else if let Some(n) = value.as_f64() {
+        // `isize::MIN` is a power of two, so exact as an `f64`, unlike the max.
+        let min = isize::MIN as f64;
+        if n.fract() != 0.0 || n < min || n >= -min {
+            return Err(out_of_range());
+        }
+        Ok(StackValue::Num(n as isize))
+    } else if value.is_bigint() {
+        let n = i64::try_from(BigInt::unchecked_from_js(value.clone())).map_err(|_| out_of_range())?;
+        isize::try_from(n).map(StackValue::Num).map_err(|_| out_of_range())
+    } else if let Some(s) = value.as_string() {
+        Ok(StackValue::String(s))
+    } else if value.is_object() {
+        let field = |key: &str| {
+            Reflect::get(value, &JsValue::from_str(key)).ok().filter(|field| !field.is_undefined())
+        };
+        if let Some(name) = field("label") {
+            name.as_string().map(StackValue::Label).ok_or_else(invalid)
+        } else if let Some(name) = field("possibleLabel") {
+            name.as_string().map(StackValue::PossibleLabel).ok_or_else(invalid)
+        } else if let Some(name) = field("operation") {
+            let name = name.as_string().ok_or_else(invalid)?;
+            name.parse().map(StackValue::Operation).map_err(js_error)
+        } else if let Some(index) = field("native") {
+            match index.as_f64() {
+                Some(index) if index >= 0.0 && index.fract() == 0.0 => Ok(StackValue::Native(index as usize)),
+                _ => Err(invalid()),
+            }
+        } else {
+            Err(invalid())
+        }
+    } else {
+        Err(invalid())
+    }
+}
+
+/// A `SideEffect` calling the `println`, `eprintln`, `print`, `flush`,
+/// `readLine` and `sleepMs` methods of a JS object. There is no
+/// filesystem or environment, so those operations fail.
+#[derive(Default)]
+struct JsEffect {
+    this: JsValue,
+    println: Option<Function>,
+    eprintln: Option<Function>,
+    print: Option<Function>,
+    flush: Option<Function>,
+    read_line: Option<Function>,
+    sleep_ms: Option<Function>,
+    /// The exception thrown by a callback, thrown again once it has
+    /// stopped the machine.
+    error: Option<JsValue>,
+}
+
+impl JsEffect {
+    fn new(effect: &JsValue) -> JsEffect {
+        let method = |name: &str| {
+            Reflect::get(effect, &JsValue::from_str(name)).ok()
+                .and_then(|method| method.dyn_into::<Function>().ok())
+        };
+        if !effect.is_object() {
+            return JsEffect::default();
+        }
+        JsEffect {
+            this: effect.clone(),
+            println: method("println"),
+            eprintln: method("eprintln"),
+            print: method("print"),
+            flush: method("flush"),
+            read_line: method("readLine"),
+            sleep_ms: method("sleepMs"),
+            error: None,
+        }
+    }
+
+    /// Keeps an exception for `check_effect` to throw, failing the
+    /// operation with its message.
+    fn record(&mut self, result: Result<JsValue, JsValue>) -> io::Result<JsValue> {
+        result.map_err(|error| {
+            let message = error.as_string().unwrap_or_else(|| format!("{:?}", error));
+            self.error = Some(error);
+            io::Error::other(message)
+        })
+    }
+}
+
+impl SideEffect for JsEffect {
+    /// Without a `println` method, lines are logged to the console.
+    fn println<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        let line = value.to_string();
+        let result = match self.println {
+            Some(ref println) => println.call1(&self.this, &line.into()),
+            None => {
+                log(&line);
+                return Ok(());
+            }
+        };
+        self.record(result).map(|_| ())
+    }
+
+    /// Without an `eprintln` method, lines are logged to the console as errors.
+    fn eprintln<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        let line = value.to_string();
+        let result = match self.eprintln {
+            Some(ref eprintln) => eprintln.call1(&self.this, &line.into()),
+            None => {
+                log_error(&line);
+                return Ok(());
+            }
+        };
+        self.record(result).map(|_| ())
+    }
+
+    /// Without a `print` method, the text is logged to the console, which
+    /// can't leave the line open.
+    fn print<I: Instruction>(&mut self, value: StackValue<I>) -> io::Result<()> {
+        let text = value.to_string();
+        let result = match self.print {
+            Some(ref print) => print.call1(&self.this, &text.into()),
+            None => {
+                log(&text);
+                return Ok(());
+            }
+        };
+        self.record(result).map(|_| ())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = match self.flush {
+            Some(ref flush) => flush.call0(&self.this),
+            None => return Ok(()),
+        };
+        self.record(result).map(|_| ())
+    }
+
+    /// Only called with a `readLine` method, the machine pauses for input otherwise.
+    fn read_line(&mut self) -> io::Result<String> {
+        let result = match self.read_line {
+            Some(ref read_line) => read_line.call0(&self.this),
+            None => return Ok(String::new()),
+        };
+        self.record(result).map(|line| line.as_string().unwrap_or_default())
+    }
+
+    /// Without a `sleepMs` method, sleeps are skipped, as blocking would freeze the page.
+    fn sleep_ms(&mut self, duration: u64) -> io::Result<()> {
+        let result = match self.sleep_ms {
+            Some(ref sleep_ms) => sleep_ms.call1(&self.this, &(duration as f64).into()),
+            None => return Ok(()),
+        };
+        self.record(result).map(|_| ())
+    }
+
+    /// The time from `Date.now()`.
+    fn now_ms(&mut self) -> io::Result<u64> {
+        Ok(js_sys::Date::now() as u64)
+    }
+}
+
+/// Whether `name` is an operation, for highlighting source code.
+#[wasm_bindgen(js_name = isOperation)]
+pub fn is_operation(name: &str) -> bool {
+    name.parse::<StackOperation>().is_ok()
+}
+
+/// A machine running a program, see the module docs.
+///
+/// `effect` is an optional object with `println(line)`, `eprintln(line)`,
+/// `print(text)`, `flush()`, `readLine()` and `sleepMs(duration)` methods.
+/// Without `readLine`, a `read` pauses the machine with the reason
+/// `"awaitingInput"` until `provideInput` is called.
+#[wasm_bindgen]
+pub struct Machine {
+    machine: simple_vm::Machine<JsEffect>,
+    /// The source line of each instruction.
+    lines: Vec<usize>,
+}
+
 #[wasm_bindgen]
-pub fn run(code: &str, args: &str) -> Vec<JsValue> {
-    let code = try_js!(tokenize(code));
-    let args = try_js!(tokenize(args));
-    let mut machine = try_js!(Machine::new(code));
-    let _stats = try_js!(machine.run(args));
-    let ret: Vec<JsValue> = machine.stack()
-        .iter()
-        .map(|v| to_js_value(v))
-        .collect();
-    ret
+impl Machine {
+    #[wasm_bindgen(constructor)]
+    pub fn new(code: &str, effect: &JsValue) -> Result<Machine, JsValue> {
+        let (code, lines) = simple_vm::tokenize_with_lines(code).map_err(js_error)?;
+        let effect = JsEffect::new(effect);
+        let pause_on_input = effect.read_line.is_none();
+        let mut machine = simple_vm::Machine::<_>::with_effect(code, effect).map_err(js_error)?;
+        machine.pause_on_input(pause_on_input);
+        Ok(Machine { machine, lines })
+    }
+
+    /// Pushes values onto the stack, in the same form `stack` returns them.
+    pub fn push(&mut self, values: Array) -> Result<(), JsValue> {
+        let values = values.iter().map(|value| from_js(&value)).collect::<Result<_, _>>()?;
+        self.machine.stack_push(values).map_err(js_error)
+    }
+
+    /// Runs until the program stops or pauses, or after `budget` steps if
+    /// one is given, returning the machine's state.
+    pub fn run(&mut self, budget: Option<u32>) -> Result<JsValue, JsValue> {
+        let mut remaining = budget.map(|budget| budget as usize);
+        loop {
+            let steps = remaining.map_or(STEPS_BETWEEN_CHECKS, |remaining| remaining.min(STEPS_BETWEEN_CHECKS));
+            let status = self.machine.run_for(steps);
+            match self.check_effect(status)? {
+                RunStatus::Paused(PauseReason::StepBudget) => {
+                    if let Some(ref mut remaining) = remaining {
+                        *remaining -= steps;
+                        if *remaining == 0 {
+                            return Ok(js_status(RunStatus::Paused(PauseReason::StepBudget)));
+                        }
+                    }
+                }
+                status => return Ok(js_status(status)),
+            }
+        }
+    }
+
+    /// Executes a single instruction, returning the machine's state.
+    pub fn step(&mut self) -> Result<JsValue, JsValue> {
+        let result = self.machine.step();
+        match self.check_effect(result)? {
+            StepResult::Continue => {
+                let js_status = JsValue::from(Object::new());
+                set(&js_status, "state", "running".into());
+                Ok(js_status)
+            }
+            StepResult::Stop(exit_code) => Ok(js_status(RunStatus::Stopped(exit_code))),
+            StepResult::AwaitingInput => Ok(js_status(RunStatus::Paused(PauseReason::AwaitingInput))),
+        }
+    }
+
+    /// Provides a line for a `read` to push onto the stack, after any
+    /// lines that were provided before it.
+    #[wasm_bindgen(js_name = provideInput)]
+    pub fn provide_input(&mut self, line: String) {
+        self.machine.provide_input(line);
+    }
+
+    /// The values on the stack, from the bottom up, converted as described in the module docs.
+    pub fn stack(&self) -> Array {
+        self.machine.stack().iter().map(to_js).collect()
+    }
+
+    /// The return addresses on the return stack, from the bottom up.
+    #[wasm_bindgen(js_name = returnStack)]
+    pub fn return_stack(&self) -> Array {
+        self.machine.return_stack().iter().map(|&address| JsValue::from(address)).collect()
+    }
+
+    /// The (1-based) source line of the instruction at `address`.
+    pub fn line(&self, address: usize) -> Option<usize> {
+        self.lines.get(address).cloned()
+    }
+
+    /// The address of the first instruction on the (1-based) source `line`.
+    #[wasm_bindgen(js_name = addressOfLine)]
+    pub fn address_of_line(&self, line: usize) -> Option<usize> {
+        self.lines.iter().position(|&l| l == line)
+    }
+
+    /// Pauses the machine with the reason `"breakpoint"` before it
+    /// executes the instruction at `address`.
+    #[wasm_bindgen(js_name = setBreakpoint)]
+    pub fn set_breakpoint(&mut self, address: usize) {
+        self.machine.set_breakpoint(address);
+    }
+
+    /// Removes the breakpoint at `address`, returning whether there was one.
+    #[wasm_bindgen(js_name = clearBreakpoint)]
+    pub fn clear_breakpoint(&mut self, address: usize) -> bool {
+        self.machine.clear_breakpoint(address)
+    }
+
+    /// The addresses with breakpoints, in ascending order.
+    pub fn breakpoints(&self) -> Array {
+        self.machine.breakpoints().into_iter().map(JsValue::from).collect()
+    }
+
+    /// Goes back to the start of the program, with an empty stack.
+    pub fn reset(&mut self) {
+        self.machine.reset();
+    }
+
+    /// The number of steps taken since the machine was created or reset.
+    #[wasm_bindgen(getter)]
+    pub fn steps(&self) -> usize {
+        self.machine.steps()
+    }
+
+    /// The address of the next instruction to execute.
+    #[wasm_bindgen(getter = instructionPtr)]
+    pub fn instruction_ptr(&self) -> usize {
+        self.machine.instruction_ptr()
+    }
+
+    /// Converts the error from running the machine, throwing the exception
+    /// thrown by a callback again if that is what stopped it.
+    fn check_effect<T>(&mut self, result: Result<T, StackError>) -> Result<T, JsValue> {
+        match (result, self.machine.effect_mut().error.take()) {
+            (Err(StackError::Io { .. }), Some(error)) => Err(error),
+            (result, _) => result.map_err(js_error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    fn get(value: &JsValue, key: &str) -> JsValue {
+        Reflect::get(value, &JsValue::from_str(key)).unwrap()
+    }
+
+    fn new_machine(code: &str, effect: &str) -> Machine {
+        Machine::new(code, &js_sys::eval(effect).unwrap()).unwrap()
+    }
+
+    #[wasm_bindgen_test]
+    fn test_run_with_budget() {
+        let mut machine = new_machine("0 loop: 1 + dup 2500 < loop end if jmp end: 7 exit", "undefined");
+        let status = machine.run(Some(3000)).unwrap();
+        assert_eq!(get(&status, "state"), "paused");
+        assert_eq!(get(&status, "reason"), "budget");
+        assert_eq!(3000, machine.steps());
+
+        let status = machine.run(None).unwrap();
+        assert_eq!(get(&status, "state"), "stopped");
+        assert_eq!(get(&status, "exitCode"), 7);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_step() {
+        let mut machine = new_machine("1 2 +", "undefined");
+        assert_eq!(get(&machine.step().unwrap(), "state"), "running");
+        assert_eq!(1, machine.instruction_ptr());
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert_eq!(get(&machine.step().unwrap(), "state"), "stopped");
+        assert_eq!(machine.stack().get(0), 3);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_callbacks() {
+        let effect = js_sys::eval(
+            "({ lines: [], slept: 0, println(line) { this.lines.push(line) },
+                eprintln(line) { this.lines.push('error: ' + line) }, print(text) { this.lines.push('print: ' + text) },
+                readLine() { return '41' }, sleepMs(ms) { this.slept += ms } })",
+        ).unwrap();
+        let mut machine = Machine::new("read cast_int 1 + println 5 sleep_ms \"a\" print flush \"b\" eprintln", &effect).unwrap();
+        machine.run(None).unwrap();
+        assert_eq!(Array::from(&get(&effect, "lines")).join(","), "42,print: a,error: b");
+        assert_eq!(get(&effect, "slept"), 5);
+
+        let mut machine = new_machine("1 println loop: loop jmp", "({ println(line) { throw line } })");
+        assert_eq!(machine.run(None).unwrap_err(), "1");
+
+        let effect = js_sys::eval(
+            "({ lines: [], println(line) { this.lines.push(line) }, readLine() { throw 'no input' } })",
+        ).unwrap();
+        let mut machine = Machine::new("read println \"after\" println", &effect).unwrap();
+        assert_eq!(machine.run(None).unwrap_err(), "no input");
+        assert_eq!(Array::from(&get(&effect, "lines")).length(), 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_awaiting_input() {
+        let mut machine = new_machine("read cast_int 1 +", "undefined");
+        let status = machine.run(None).unwrap();
+        assert_eq!(get(&status, "reason"), "awaitingInput");
+        machine.provide_input("41".to_owned());
+        assert_eq!(get(&machine.run(None).unwrap(), "state"), "stopped");
+        assert_eq!(machine.stack().get(0), 42);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_errors() {
+        let error = Machine::new("nowhere jmp", &JsValue::UNDEFINED).err().unwrap();
+        assert!(error.is_instance_of::<js_sys::Error>());
+        assert_eq!(get(&error, "name"), "StackError");
+        assert_eq!(get(&error, "kind"), "UndefinedLabel");
+        assert_eq!(get(&error, "label"), "nowhere");
+
+        let error = new_machine("drop", "undefined").run(None).unwrap_err();
+        assert_eq!(get(&error, "kind"), "EmptyStack");
+        assert_eq!(get(&error, "argPattern"), "_");
+    }
+
+    #[wasm_bindgen_test]
+    fn test_numbers() {
+        let mut machine = new_machine("0 swap -", "undefined");
+        machine.push(Array::of1(&5.into())).unwrap();
+        machine.run(None).unwrap();
+        assert_eq!(machine.stack().get(0), -5);
+
+        let mut machine = new_machine("", "undefined");
+        let max = isize::MAX;
+        machine.push(Array::of2(&(max as f64).into(), &BigInt::from(-8).into())).unwrap();
+        assert_eq!(machine.stack().get(0), max as f64);
+        assert_eq!(machine.stack().get(1), -8);
+
+        assert!(machine.push(Array::of1(&1.5.into())).unwrap_err().is_instance_of::<RangeError>());
+        assert!(machine.push(Array::of1(&1e20.into())).unwrap_err().is_instance_of::<RangeError>());
+        let too_big = BigInt::from(i64::MAX);
+        assert!(machine.push(Array::of1(&too_big.into())).unwrap_err().is_instance_of::<RangeError>());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_round_trip() {
+        let values = js_sys::eval(
+            "[true, 12, -3, 'hi', { label: 'start' }, { possibleLabel: 'start' },
+              { operation: 'dup' }, { native: 0 }]",
+        ).unwrap();
+        let mut machine = new_machine("", "undefined");
+        machine.push(values.clone().into()).unwrap();
+        let stack = machine.stack();
+        let json = |value: &JsValue| js_sys::JSON::stringify(value).unwrap();
+        assert_eq!(json(&stack), json(&values));
+        assert_eq!(stack.length(), Array::from(&values).length());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_invalid_values() {
+        let mut machine = new_machine("", "undefined");
+        for value in &["({})", "null", "undefined", "({ label: 1 })", "({ native: -1 })"] {
+            let error = machine.push(Array::of1(&js_sys::eval(value).unwrap())).unwrap_err();
+            assert!(error.is_instance_of::<TypeError>(), "{}", value);
+        }
+        let error = machine.push(Array::of1(&js_sys::eval("({ operation: 'nope' })").unwrap())).unwrap_err();
+        assert_eq!(get(&error, "kind"), "InvalidOperation");
+        assert_eq!(0, machine.stack().length());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_breakpoints() {
+        let mut machine = new_machine("1\nsub call\nstop\n\nsub: 2\nreturn", "undefined");
+        assert_eq!(Some(3), machine.line(3));
+        assert_eq!(Some(4), machine.address_of_line(5));
+        assert_eq!(None, machine.address_of_line(4));
+
+        machine.set_breakpoint(5);
+        assert_eq!(machine.breakpoints().get(0), 5);
+        let status = machine.run(None).unwrap();
+        assert_eq!(get(&status, "reason"), "breakpoint");
+        assert_eq!(get(&status, "address"), 5);
+        assert_eq!(machine.return_stack().get(0), 3);
+
+        assert!(machine.clear_breakpoint(5));
+        assert_eq!(get(&machine.run(None).unwrap(), "state"), "stopped");
+        assert_eq!(0, machine.return_stack().length());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_is_operation() {
+        assert!(is_operation("dup"));
+        assert!(is_operation("+"));
+        assert!(!is_operation("fib"));
+    }
 }
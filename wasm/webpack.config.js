@@ -1,10 +0,0 @@
-const path = require('path');
-
-module.exports = {
-    entry: "./index.js",
-    output: {
-        path: path.resolve(__dirname, "dist"),
-        filename: "index.js",
-    },
-    mode: "development"
-};